@@ -0,0 +1,7 @@
+MEMORY
+{
+  /* The last 2K page is left out, it holds the contract of persist::ContractStore */
+  FLASH : ORIGIN = 0x08000000, LENGTH = 126K
+  /* SRAM1, SRAM2 and the CCM SRAM alias, contiguous on the G431 */
+  RAM   : ORIGIN = 0x20000000, LENGTH = 32K
+}
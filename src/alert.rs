@@ -0,0 +1,54 @@
+//! Alert messages from the source, e.g. over-temperature or over-current.
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
+
+/// Alert Data Object (USB PD 3.2 Table 6.42).
+///
+/// Several alert types can be reported at once.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Alert(pub u32);
+
+impl Alert {
+    fn type_bit(self, bit: u32) -> bool {
+        self.0 & (1 << (24 + bit)) != 0
+    }
+
+    pub fn battery_status_change(self) -> bool {
+        self.type_bit(1)
+    }
+
+    /// Over-current protection event, the source has reduced or removed VBUS
+    pub fn ocp(self) -> bool {
+        self.type_bit(2)
+    }
+
+    /// Over-temperature protection event
+    pub fn otp(self) -> bool {
+        self.type_bit(3)
+    }
+
+    /// The source's operating condition changed, its capabilities may follow
+    pub fn operating_condition_change(self) -> bool {
+        self.type_bit(4)
+    }
+
+    pub fn source_input_change(self) -> bool {
+        self.type_bit(5)
+    }
+
+    /// Over-voltage protection event
+    pub fn ovp(self) -> bool {
+        self.type_bit(6)
+    }
+
+    /// Details follow in the extended alert event type field
+    pub fn extended(self) -> bool {
+        self.type_bit(7)
+    }
+}
+
+/// Alerts received from the source, for the application to react to.
+///
+/// Only the latest alert is kept if they are not consumed in time.
+pub static ALERTS: Signal<CriticalSectionRawMutex, Alert> = Signal::new();
@@ -0,0 +1,135 @@
+//! Battery information of battery-backed sources such as power banks.
+//!
+//! Queried with Get_Battery_Cap and Get_Battery_Status, which the policy engine has no
+//! support for; the UCPD driver sends them on request and reports the parsed responses
+//! as `PdEvent`s.
+use crate::power::{DRIVER_REQUEST, DriverRequest};
+
+/// Battery references: 0-3 fixed batteries, 4-7 hot swappable slots
+pub const MAX_BATTERIES: u8 = 8;
+
+/// Battery_Capabilities data block (USB PD 3.2 Table 6.60).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct BatteryCapabilities {
+    /// Battery reference the capabilities are for
+    pub battery: u8,
+    /// USB vendor ID of the battery
+    pub vid: u16,
+    /// USB product ID of the battery
+    pub pid: u16,
+    /// Design capacity in mWh, `None` if not present or unknown
+    pub design_capacity_mwh: Option<u32>,
+    /// Capacity at the last full charge in mWh, `None` if not present or unknown
+    pub last_full_charge_mwh: Option<u32>,
+}
+
+impl BatteryCapabilities {
+    /// Length of the data block
+    const LEN: usize = 9;
+
+    /// Parse the data block of a Battery_Capabilities message.
+    ///
+    /// Returns `None` if it is too short or the source flags the battery reference as
+    /// invalid.
+    pub fn parse(battery: u8, data: &[u8]) -> Option<Self> {
+        if data.len() < Self::LEN || data[8] & 0x1 != 0 {
+            return None;
+        }
+
+        Some(Self {
+            battery,
+            vid: u16::from_le_bytes([data[0], data[1]]),
+            pid: u16::from_le_bytes([data[2], data[3]]),
+            design_capacity_mwh: capacity_mwh(u16::from_le_bytes([data[4], data[5]])),
+            last_full_charge_mwh: capacity_mwh(u16::from_le_bytes([data[6], data[7]])),
+        })
+    }
+}
+
+/// Charging state reported in a Battery Status Data Object.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ChargingState {
+    Charging,
+    Discharging,
+    Idle,
+}
+
+/// Battery Status Data Object (USB PD 3.2 Table 6.46).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct BatteryStatus {
+    /// Battery reference the status is for
+    pub battery: u8,
+    /// Remaining capacity in mWh, `None` if unknown
+    pub present_capacity_mwh: Option<u32>,
+    /// State of charge in percent of the last full charge, `None` if either is unknown
+    pub soc_percent: Option<u8>,
+    /// Charging state, `None` while no battery is present
+    pub charging: Option<ChargingState>,
+}
+
+impl BatteryStatus {
+    /// Parse a Battery_Status data object.
+    ///
+    /// The state of charge is only known with the last full charge capacity, as reported in
+    /// the battery's capabilities. Returns `None` if the battery reference is invalid.
+    pub fn parse(battery: u8, bsdo: u32, last_full_charge_mwh: Option<u32>) -> Option<Self> {
+        let info = (bsdo >> 8) as u8;
+        if info & 0x1 != 0 {
+            return None;
+        }
+
+        let present = info & 0x2 != 0;
+        let present_capacity_mwh = capacity_mwh((bsdo >> 16) as u16).filter(|_| present);
+        let soc_percent = match (present_capacity_mwh, last_full_charge_mwh) {
+            (Some(present_mwh), Some(full_mwh)) if full_mwh > 0 => {
+                Some((present_mwh * 100 / full_mwh).min(100) as u8)
+            }
+            _ => None,
+        };
+        let charging = match (info >> 2) & 0x3 {
+            _ if !present => None,
+            0b00 => Some(ChargingState::Charging),
+            0b01 => Some(ChargingState::Discharging),
+            0b10 => Some(ChargingState::Idle),
+            _ => None,
+        };
+
+        Some(Self {
+            battery,
+            present_capacity_mwh,
+            soc_percent,
+            charging,
+        })
+    }
+}
+
+/// Capacity field in 0.1Wh units, 0 for no battery and 0xFFFF for unknown.
+fn capacity_mwh(raw: u16) -> Option<u32> {
+    match raw {
+        0 | 0xFFFF => None,
+        raw => Some(raw as u32 * 100),
+    }
+}
+
+/// Query the capabilities of a battery of the source, reported as a `PdEvent`.
+///
+/// Sent by the driver once the policy engine is idle in PE_SNK_Ready, not in the middle of
+/// an AMS, queries made while detached are dropped on attach. Sources without batteries or without support for the query are
+/// logged as unsupported.
+pub fn request_capabilities(battery: u8) {
+    if battery < MAX_BATTERIES {
+        let _ = DRIVER_REQUEST.try_send(DriverRequest::BatteryCapabilities { battery });
+    }
+}
+
+/// Query the status of a battery of the source, reported as a `PdEvent`.
+///
+/// The state of charge is only included once the capabilities of the battery are known.
+pub fn request_status(battery: u8) {
+    if battery < MAX_BATTERIES {
+        let _ = DRIVER_REQUEST.try_send(DriverRequest::BatteryStatus { battery });
+    }
+}
@@ -0,0 +1,72 @@
+//! Current rating of the cable, from its e-marker.
+//!
+//! The driver asks the cable plug for its identity with Discover Identity over SOP' once a
+//! contract is in place. Until then the source's PDOs are trusted, as a source only offers
+//! more than 3A after checking the cable itself. Cables without an e-marker limit requests
+//! to 3A from then on.
+use core::sync::atomic::{AtomicU32, Ordering};
+
+/// Current every Type-C cable carries, also assumed for cables without an e-marker
+pub const DEFAULT_CABLE_CURRENT_MA: u32 = 3_000;
+
+/// Structured VDM header of a Discover Identity request: PD SID, SVDM version 2.1
+pub const DISCOVER_IDENTITY_REQUEST: u32 = 0xFF00_A001;
+
+/// Current limit from the cable in mA, 0 until the cable was queried on this attach
+static CABLE_MAX_CURRENT_MA: AtomicU32 = AtomicU32::new(0);
+
+/// Highest current the cable carries in mA, `None` until the cable was queried.
+pub fn max_current_ma() -> Option<u32> {
+    match CABLE_MAX_CURRENT_MA.load(Ordering::Relaxed) {
+        0 => None,
+        current_ma => Some(current_ma),
+    }
+}
+
+/// Whether the cable is rated for `current_ma`.
+///
+/// Checked against the e-marker once it was read. Until then the source's offer is trusted,
+/// as it only offers more than 3A after checking the cable itself.
+pub fn current_ok(current_ma: u32) -> bool {
+    max_current_ma().is_none_or(|max_current_ma| current_ma <= max_current_ma)
+}
+
+/// Record the e-marker's rating, `None` if no e-marker answered. Returns the limit.
+#[cfg(target_os = "none")]
+pub(crate) fn set_max_current_ma(current_ma: Option<u32>) -> u32 {
+    let current_ma = current_ma.unwrap_or(DEFAULT_CABLE_CURRENT_MA);
+    CABLE_MAX_CURRENT_MA.store(current_ma, Ordering::Relaxed);
+    current_ma
+}
+
+/// Forget the cable, on attach.
+#[cfg(target_os = "none")]
+pub(crate) fn reset() {
+    CABLE_MAX_CURRENT_MA.store(0, Ordering::Relaxed);
+}
+
+/// VBUS current rating from the data objects of a Discover Identity ACK from SOP'.
+///
+/// Returns `None` if the response isn't an ACK from a passive or active cable.
+pub fn parse_discover_identity(objects: &[u32]) -> Option<u32> {
+    let vdm_header = *objects.first()?;
+    let is_ack = vdm_header >> 16 == 0xFF00 // PD SID
+        && vdm_header & (1 << 15) != 0 // structured
+        && (vdm_header >> 6) & 0x3 == 0b01 // ACK
+        && vdm_header & 0x1F == 1; // Discover Identity
+    if !is_ack {
+        return None;
+    }
+
+    // ID Header, Cert Stat and Product VDOs precede the Cable VDO (USB PD 3.2 6.4.4.3.1)
+    let id_header = *objects.get(1)?;
+    let product_type = (id_header >> 27) & 0x7;
+    if !matches!(product_type, 0b011 | 0b100) {
+        return None;
+    }
+    let cable_vdo = *objects.get(4)?;
+    Some(match (cable_vdo >> 5) & 0x3 {
+        0b10 => 5_000,
+        _ => DEFAULT_CABLE_CURRENT_MA,
+    })
+}
@@ -0,0 +1,348 @@
+//! Owned copy of the source capabilities for consumers outside the policy engine.
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::pubsub::PubSubChannel;
+use embassy_sync::signal::Signal;
+use heapless::Vec;
+use usbpd::protocol_layer::message::data::source_capabilities::{
+    Augmented, PowerDataObject, SourceCapabilities,
+};
+
+/// Maximum number of PDOs in EPR Source_Capabilities, 7 SPR and 4 EPR positions
+pub const MAX_SOURCE_PDOS: usize = 11;
+
+/// A parsed source power data object.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SourcePdo {
+    Fixed {
+        voltage_mv: u32,
+        current_ma: u32,
+        epr_mode_capable: bool,
+    },
+    Battery {
+        min_voltage_mv: u32,
+        max_voltage_mv: u32,
+        power_mw: u32,
+    },
+    Variable {
+        min_voltage_mv: u32,
+        max_voltage_mv: u32,
+        current_ma: u32,
+    },
+    /// SPR PPS
+    Pps {
+        min_voltage_mv: u32,
+        max_voltage_mv: u32,
+        current_ma: u32,
+    },
+    /// EPR AVS
+    Avs {
+        min_voltage_mv: u32,
+        max_voltage_mv: u32,
+        power_mw: u32,
+    },
+    /// Zero PDO padding the SPR positions of EPR capabilities
+    Separator,
+    /// PDO of a type this firmware does not know, as received
+    Unknown(u32),
+}
+
+impl From<&PowerDataObject> for SourcePdo {
+    fn from(pdo: &PowerDataObject) -> Self {
+        match pdo {
+            PowerDataObject::FixedSupply(f) if f.0 == 0 => SourcePdo::Separator,
+            PowerDataObject::FixedSupply(f) => SourcePdo::Fixed {
+                voltage_mv: f.raw_voltage() as u32 * 50,     // 50mV units
+                current_ma: f.raw_max_current() as u32 * 10, // 10mA units
+                epr_mode_capable: f.epr_mode_capable(),
+            },
+            PowerDataObject::Battery(b) => SourcePdo::Battery {
+                min_voltage_mv: b.raw_min_voltage() as u32 * 50,
+                max_voltage_mv: b.raw_max_voltage() as u32 * 50,
+                power_mw: b.raw_max_power() as u32 * 250, // 250mW units
+            },
+            PowerDataObject::VariableSupply(v) => SourcePdo::Variable {
+                min_voltage_mv: v.raw_min_voltage() as u32 * 50,
+                max_voltage_mv: v.raw_max_voltage() as u32 * 50,
+                current_ma: v.raw_max_current() as u32 * 10,
+            },
+            PowerDataObject::Augmented(Augmented::Spr(pps)) => SourcePdo::Pps {
+                min_voltage_mv: pps.raw_min_voltage() as u32 * 100, // 100mV units
+                max_voltage_mv: pps.raw_max_voltage() as u32 * 100,
+                current_ma: pps.raw_max_current() as u32 * 50, // 50mA units
+            },
+            PowerDataObject::Augmented(Augmented::Epr(avs)) => SourcePdo::Avs {
+                min_voltage_mv: avs.raw_min_voltage() as u32 * 100,
+                max_voltage_mv: avs.raw_max_voltage() as u32 * 100,
+                power_mw: avs.raw_pd_power() as u32 * 1000, // 1W units
+            },
+            PowerDataObject::Augmented(Augmented::Unknown(raw)) => SourcePdo::Unknown(*raw),
+            PowerDataObject::Unknown(u) => SourcePdo::Unknown(u.0),
+        }
+    }
+}
+
+/// Short form for narrow outputs such as a display, e.g. `20V 3.2A` or `3.3-21V 3A`.
+impl core::fmt::Display for SourcePdo {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match *self {
+            SourcePdo::Fixed {
+                voltage_mv,
+                current_ma,
+                ..
+            } => write!(f, "{} {}", Volts(voltage_mv), Amps(current_ma)),
+            SourcePdo::Variable {
+                min_voltage_mv,
+                max_voltage_mv,
+                current_ma,
+            }
+            | SourcePdo::Pps {
+                min_voltage_mv,
+                max_voltage_mv,
+                current_ma,
+            } => write!(
+                f,
+                "{}-{} {}",
+                Milli(min_voltage_mv),
+                Volts(max_voltage_mv),
+                Amps(current_ma)
+            ),
+            SourcePdo::Battery {
+                min_voltage_mv,
+                max_voltage_mv,
+                power_mw,
+            }
+            | SourcePdo::Avs {
+                min_voltage_mv,
+                max_voltage_mv,
+                power_mw,
+            } => write!(
+                f,
+                "{}-{} {}W",
+                Milli(min_voltage_mv),
+                Volts(max_voltage_mv),
+                power_mw / 1000
+            ),
+            SourcePdo::Separator => f.write_str("-"),
+            SourcePdo::Unknown(_) => f.write_str("?"),
+        }
+    }
+}
+
+/// Value in milli-units as whole units with at most one decimal, without unit.
+pub(crate) struct Milli(pub u32);
+
+impl core::fmt::Display for Milli {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self.0 % 1000 / 100 {
+            0 => write!(f, "{}", self.0 / 1000),
+            tenths => write!(f, "{}.{}", self.0 / 1000, tenths),
+        }
+    }
+}
+
+/// mV as V with at most one decimal.
+pub(crate) struct Volts(pub u32);
+
+impl core::fmt::Display for Volts {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}V", Milli(self.0))
+    }
+}
+
+/// mA as A with at most one decimal.
+pub(crate) struct Amps(pub u32);
+
+impl core::fmt::Display for Amps {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}A", Milli(self.0))
+    }
+}
+
+/// Source capabilities as last received, in PDO order.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct CapsSnapshot {
+    /// PDOs by position, starting at object position 1
+    pub pdos: Vec<SourcePdo, MAX_SOURCE_PDOS>,
+    /// Received as EPR_Source_Capabilities
+    pub epr: bool,
+}
+
+impl From<&SourceCapabilities> for CapsSnapshot {
+    fn from(caps: &SourceCapabilities) -> Self {
+        Self {
+            pdos: caps
+                .pdos()
+                .iter()
+                .map(SourcePdo::from)
+                .take(MAX_SOURCE_PDOS)
+                .collect(),
+            epr: caps.is_epr_capabilities(),
+        }
+    }
+}
+
+impl CapsSnapshot {
+    /// Whether a PPS or AVS PDO is offered, the only ones the target voltage adjusts.
+    pub fn offers_adjustable(&self) -> bool {
+        self.pdos
+            .iter()
+            .any(|pdo| matches!(pdo, SourcePdo::Pps { .. } | SourcePdo::Avs { .. }))
+    }
+}
+
+/// Dense one-line summary of source capabilities, for comparing logs across sources.
+///
+/// Formats as e.g. `7 PDOs, max 20000mV 5000mA, PPS yes, AVS no, EPR no`.
+#[derive(Clone, Copy)]
+pub struct CapsSummary<'a>(pub &'a SourceCapabilities);
+
+impl<'a> From<&'a SourceCapabilities> for CapsSummary<'a> {
+    fn from(caps: &'a SourceCapabilities) -> Self {
+        Self(caps)
+    }
+}
+
+impl CapsSummary<'_> {
+    fn pdos(&self) -> impl Iterator<Item = SourcePdo> + '_ {
+        self.0.pdos().iter().map(SourcePdo::from)
+    }
+
+    /// Number of PDOs, separators included
+    pub fn pdo_count(&self) -> usize {
+        self.0.pdos().len()
+    }
+
+    /// Highest voltage offered by any PDO in mV
+    pub fn max_voltage_mv(&self) -> u32 {
+        self.pdos()
+            .map(|pdo| match pdo {
+                SourcePdo::Fixed { voltage_mv, .. } => voltage_mv,
+                SourcePdo::Battery { max_voltage_mv, .. }
+                | SourcePdo::Variable { max_voltage_mv, .. }
+                | SourcePdo::Pps { max_voltage_mv, .. }
+                | SourcePdo::Avs { max_voltage_mv, .. } => max_voltage_mv,
+                SourcePdo::Separator | SourcePdo::Unknown(_) => 0,
+            })
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Highest current offered by any current-rated PDO in mA
+    pub fn max_current_ma(&self) -> u32 {
+        self.pdos()
+            .map(|pdo| match pdo {
+                SourcePdo::Fixed { current_ma, .. }
+                | SourcePdo::Variable { current_ma, .. }
+                | SourcePdo::Pps { current_ma, .. } => current_ma,
+                _ => 0,
+            })
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Whether an SPR PPS PDO is offered
+    pub fn has_pps(&self) -> bool {
+        self.pdos().any(|pdo| matches!(pdo, SourcePdo::Pps { .. }))
+    }
+
+    /// Whether an EPR AVS PDO is offered
+    pub fn has_avs(&self) -> bool {
+        self.pdos().any(|pdo| matches!(pdo, SourcePdo::Avs { .. }))
+    }
+
+    /// Whether the source is EPR capable, or these are EPR capabilities
+    pub fn epr(&self) -> bool {
+        self.0.is_epr_capabilities()
+            || matches!(
+                self.pdos().next(),
+                Some(SourcePdo::Fixed {
+                    epr_mode_capable: true,
+                    ..
+                })
+            )
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for CapsSummary<'_> {
+    fn format(&self, f: defmt::Formatter) {
+        let yes_no = |available: bool| if available { "yes" } else { "no" };
+        defmt::write!(
+            f,
+            "{} PDOs, max {}mV {}mA, PPS {}, AVS {}, EPR {}",
+            self.pdo_count(),
+            self.max_voltage_mv(),
+            self.max_current_ma(),
+            yes_no(self.has_pps()),
+            yes_no(self.has_avs()),
+            yes_no(self.epr())
+        );
+    }
+}
+
+/// Subscribers of `CAPS`, e.g. a display, a host interface and the control task
+const CAPS_SUBSCRIBERS: usize = 3;
+
+/// Every Source_Capabilities received, newest only.
+///
+/// Subscribers that fall behind see the latest snapshot and skip the older ones.
+pub static CAPS: PubSubChannel<CriticalSectionRawMutex, CapsSnapshot, 1, CAPS_SUBSCRIBERS, 0> =
+    PubSubChannel::new();
+
+/// Publish a snapshot of received capabilities without blocking the policy engine.
+pub(crate) fn publish(caps: &SourceCapabilities) {
+    CAPS.immediate_publisher()
+        .publish_immediate(CapsSnapshot::from(caps));
+}
+
+/// Source_Capabilities_Extended data block (USB PD 3.2 Table 6.59).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct SourceCapsExtended {
+    /// USB vendor ID
+    pub vid: u16,
+    /// USB product ID
+    pub pid: u16,
+    /// USB-IF extended ID
+    pub xid: u32,
+    pub fw_version: u8,
+    pub hw_version: u8,
+    /// Number of fixed batteries
+    pub fixed_batteries: u8,
+    /// Number of hot swappable battery slots
+    pub hot_swappable_battery_slots: u8,
+    /// SPR source PDP rating in W
+    pub pdp_watts: u8,
+    /// EPR source PDP rating in W, 0 for SPR sources and PD revisions before 3.1
+    pub epr_pdp_watts: u8,
+}
+
+impl SourceCapsExtended {
+    /// Length of the data block up to the SPR PDP, as sent by PD 3.0 sources
+    const MIN_LEN: usize = 24;
+
+    /// Parse the data block of a Source_Capabilities_Extended message.
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        if data.len() < Self::MIN_LEN {
+            return None;
+        }
+
+        Some(Self {
+            vid: u16::from_le_bytes([data[0], data[1]]),
+            pid: u16::from_le_bytes([data[2], data[3]]),
+            xid: u32::from_le_bytes([data[4], data[5], data[6], data[7]]),
+            fw_version: data[8],
+            hw_version: data[9],
+            fixed_batteries: data[22] & 0xF,
+            hot_swappable_battery_slots: data[22] >> 4,
+            pdp_watts: data[23] & 0x7F,
+            epr_pdp_watts: data.get(24).copied().unwrap_or(0),
+        })
+    }
+}
+
+/// Extended capabilities of the source, queried once a contract is in place.
+pub static SOURCE_CAPS_EXTENDED: Signal<CriticalSectionRawMutex, SourceCapsExtended> =
+    Signal::new();
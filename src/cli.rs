@@ -0,0 +1,446 @@
+//! Line based control interface over a UART.
+//!
+//! Commands that change or read the policy go through `control::call`, like those of
+//! every other transport; the rest are answered here.
+#[cfg(target_os = "none")]
+pub use uart::{CliResources, cli_task};
+
+use core::fmt::{self, Write};
+
+use crate::caps::{CapsSnapshot, SourcePdo};
+use crate::control::Reply;
+use crate::power::{CableOrientation, Contract};
+use crate::stats::Stats;
+
+/// A command typed on the control interface.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Command {
+    /// `set <mV>`: new PPS and AVS target voltage, applied by renegotiating
+    Set { voltage_mv: u32 },
+    /// `status`: print the current contract
+    Status,
+    /// `dump`: print the full state as a single JSON line, for host scripts
+    Dump,
+    /// `force <position>|off`: request a PDO position regardless of the policy
+    Force { position: Option<u8> },
+    /// `history`: print the recent PD events, oldest first
+    History,
+    /// `best [reset]`: print the highest power contract since boot, or forget it
+    Best { reset: bool },
+    /// `help`: list the commands
+    Help,
+}
+
+/// Why a line could not be parsed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ParseError {
+    Empty,
+    UnknownCommand,
+    MissingArgument,
+    InvalidNumber,
+    OutOfRange,
+    TrailingInput,
+}
+
+impl ParseError {
+    /// Message shown to the user.
+    pub fn message(self) -> &'static str {
+        match self {
+            ParseError::Empty => "empty command, try `help`",
+            ParseError::UnknownCommand => "unknown command, try `help`",
+            ParseError::MissingArgument => "missing argument, e.g. `set 20000`",
+            ParseError::InvalidNumber => "expected a number, e.g. `set 20000` or `force 2`",
+            ParseError::OutOfRange => "voltage outside 3300-48000mV, e.g. `set 20000`",
+            ParseError::TrailingInput => "too many arguments, try `help`",
+        }
+    }
+}
+
+/// Lowest `set` target, the bottom of the SPR PPS range
+pub const MIN_TARGET_MV: u32 = 3_300;
+/// Highest `set` target, the top of the EPR AVS range
+pub const MAX_TARGET_MV: u32 = 48_000;
+
+/// Usage shown for `help`.
+pub const HELP: &str = "commands:\r\n  set <mV>  request a new PPS/AVS target voltage\r\n  status    show the current contract\r\n  dump      print the state as one JSON line\r\n  force <n> request PDO n regardless of the policy, `force off` to stop\r\n  history   show the recent PD events\r\n  best      show the best contract since boot, `best reset` to forget it\r\n  help      show this message\r\n";
+
+impl Command {
+    /// Parse a line, ignoring surrounding whitespace.
+    pub fn parse(line: &str) -> Result<Self, ParseError> {
+        let mut words = line.split_whitespace();
+        let command = match words.next().ok_or(ParseError::Empty)? {
+            "set" => Command::Set {
+                voltage_mv: parse_target(words.next())?,
+            },
+            "status" => Command::Status,
+            "dump" => Command::Dump,
+            "history" => Command::History,
+            "best" => match words.next() {
+                None => Command::Best { reset: false },
+                Some("reset") => Command::Best { reset: true },
+                Some(_) => return Err(ParseError::TrailingInput),
+            },
+            "force" => match words.next().ok_or(ParseError::MissingArgument)? {
+                "off" => Command::Force { position: None },
+                position => Command::Force {
+                    position: Some(
+                        position
+                            .parse()
+                            .ok()
+                            .filter(|&position| position > 0)
+                            .ok_or(ParseError::InvalidNumber)?,
+                    ),
+                },
+            },
+            "help" => Command::Help,
+            _ => return Err(ParseError::UnknownCommand),
+        };
+
+        match words.next() {
+            Some(_) => Err(ParseError::TrailingInput),
+            None => Ok(command),
+        }
+    }
+}
+
+/// Parse the argument of `set`, a target voltage in mV.
+pub(crate) fn parse_target(word: Option<&str>) -> Result<u32, ParseError> {
+    let voltage_mv = word
+        .ok_or(ParseError::MissingArgument)?
+        .parse()
+        .map_err(|_| ParseError::InvalidNumber)?;
+    if !(MIN_TARGET_MV..=MAX_TARGET_MV).contains(&voltage_mv) {
+        return Err(ParseError::OutOfRange);
+    }
+    Ok(voltage_mv)
+}
+
+/// Write a control reply as text, each line ended with CRLF.
+///
+/// Capabilities are written one PDO per line, as the JSON objects of `dump`.
+pub fn write_reply(out: &mut impl Write, reply: &Reply) -> fmt::Result {
+    match reply {
+        Reply::TargetSet { voltage_mv } => write!(out, "target {}mV\r\n", voltage_mv),
+        Reply::NoAdjustablePdo { voltage_mv } => write!(
+            out,
+            "error: no PPS or AVS PDO offered, target {}mV kept for the next source\r\n",
+            voltage_mv
+        ),
+        Reply::PdoForced {
+            position: Some(position),
+        } => write!(out, "forcing PDO {}\r\n", position),
+        Reply::PdoForced { position: None } => out.write_str("policy restored\r\n"),
+        Reply::Contract(contract) if contract.is_active() => write!(
+            out,
+            "contract {}mV @ {}mA, PDO {}{}\r\n",
+            contract.voltage_mv,
+            contract.current_ma,
+            contract.pdo_position,
+            if contract.is_epr { " (EPR)" } else { "" }
+        ),
+        Reply::Contract(_) => out.write_str("no contract\r\n"),
+        Reply::Caps(Some(caps)) => {
+            for (index, pdo) in caps.pdos.iter().enumerate() {
+                write!(out, "PDO {} ", index + 1)?;
+                write_pdo(out, pdo)?;
+                out.write_str("\r\n")?;
+            }
+            Ok(())
+        }
+        Reply::Caps(None) => out.write_str("no source capabilities\r\n"),
+        Reply::ResetRequested => out.write_str("hard reset requested\r\n"),
+        Reply::NotAttached => out.write_str("error: no source attached\r\n"),
+    }
+}
+
+/// Longest `dump` line, enough for 11 PDOs; longer dumps are refused rather than
+/// holding up the UART
+pub const DUMP_LEN: usize = 768;
+
+/// Write the state as one line of JSON, without the line ending.
+///
+/// The PDOs are only included while attached, e.g.
+/// `{"orientation":"cc1","contract":{"mv":20000,"ma":3000,"pdo":4,"epr":false,"cable_ok":true},
+/// "pdos":[{"type":"fixed","mv":5000,"ma":3000}],"stats":{...}}` on a single line.
+pub fn write_dump(
+    out: &mut impl Write,
+    orientation: Option<CableOrientation>,
+    contract: &Contract,
+    caps: Option<&CapsSnapshot>,
+    stats: &Stats,
+) -> fmt::Result {
+    let pdos = caps
+        .filter(|_| orientation.is_some())
+        .map(|caps| caps.pdos.as_slice());
+    let orientation = match orientation {
+        None => "none",
+        Some(CableOrientation::Normal) => "cc1",
+        Some(CableOrientation::Flipped) => "cc2",
+        Some(CableOrientation::DebugAccessoryMode) => "debug",
+    };
+    write!(out, "{{\"orientation\":\"{}\",\"contract\":", orientation)?;
+    if contract.is_active() {
+        write!(
+            out,
+            "{{\"mv\":{},\"ma\":{},\"pdo\":{},\"epr\":{},\"cable_ok\":{}}}",
+            contract.voltage_mv,
+            contract.current_ma,
+            contract.pdo_position,
+            contract.is_epr,
+            contract.cable_current_ok
+        )?;
+    } else {
+        out.write_str("null")?;
+    }
+
+    out.write_str(",\"pdos\":[")?;
+    for (index, pdo) in pdos.unwrap_or_default().iter().enumerate() {
+        if index > 0 {
+            out.write_char(',')?;
+        }
+        write_pdo(out, pdo)?;
+    }
+
+    write!(
+        out,
+        "],\"stats\":{{\"attaches\":{},\"contracts\":{},\"hard_resets\":{},\"overruns\":{},\"crc_errors\":{},\"max_time_to_contract_ms\":{}}}}}",
+        stats.attaches,
+        stats.contracts,
+        stats.hard_resets,
+        stats.overruns,
+        stats.crc_errors,
+        stats.max_time_to_contract_ms
+    )
+}
+
+/// One PDO as a JSON object.
+pub(crate) fn write_pdo(out: &mut impl Write, pdo: &SourcePdo) -> fmt::Result {
+    match *pdo {
+        SourcePdo::Fixed {
+            voltage_mv,
+            current_ma,
+            epr_mode_capable,
+        } => write!(
+            out,
+            "{{\"type\":\"fixed\",\"mv\":{},\"ma\":{},\"epr\":{}}}",
+            voltage_mv, current_ma, epr_mode_capable
+        ),
+        SourcePdo::Variable {
+            min_voltage_mv,
+            max_voltage_mv,
+            current_ma,
+        } => write!(
+            out,
+            "{{\"type\":\"variable\",\"min_mv\":{},\"max_mv\":{},\"ma\":{}}}",
+            min_voltage_mv, max_voltage_mv, current_ma
+        ),
+        SourcePdo::Pps {
+            min_voltage_mv,
+            max_voltage_mv,
+            current_ma,
+        } => write!(
+            out,
+            "{{\"type\":\"pps\",\"min_mv\":{},\"max_mv\":{},\"ma\":{}}}",
+            min_voltage_mv, max_voltage_mv, current_ma
+        ),
+        SourcePdo::Battery {
+            min_voltage_mv,
+            max_voltage_mv,
+            power_mw,
+        } => write!(
+            out,
+            "{{\"type\":\"battery\",\"min_mv\":{},\"max_mv\":{},\"mw\":{}}}",
+            min_voltage_mv, max_voltage_mv, power_mw
+        ),
+        SourcePdo::Avs {
+            min_voltage_mv,
+            max_voltage_mv,
+            power_mw,
+        } => write!(
+            out,
+            "{{\"type\":\"avs\",\"min_mv\":{},\"max_mv\":{},\"mw\":{}}}",
+            min_voltage_mv, max_voltage_mv, power_mw
+        ),
+        SourcePdo::Separator => out.write_str("{\"type\":\"separator\"}"),
+        SourcePdo::Unknown(raw) => write!(out, "{{\"type\":\"unknown\",\"raw\":{}}}", raw),
+    }
+}
+
+/// UART handling, only available on the target.
+#[cfg(target_os = "none")]
+mod uart {
+    use core::fmt::Write;
+
+    use embassy_stm32::mode::Async;
+    use embassy_stm32::usart::{self, Uart};
+    use embassy_stm32::{Peri, bind_interrupts, peripherals};
+    use heapless::String;
+
+    use super::{Command, DUMP_LEN, HELP, write_dump, write_reply};
+    use crate::control::{self, Reply};
+    use crate::event;
+    use crate::fmt::{info, warn};
+    use crate::power;
+    use crate::stats::Stats;
+
+    bind_interrupts!(struct Irqs {
+        USART2 => usart::InterruptHandler<peripherals::USART2>;
+    });
+
+    /// Longest accepted command line
+    const MAX_LINE: usize = 32;
+
+    pub struct CliResources {
+        pub usart: Peri<'static, peripherals::USART2>,
+        pub pin_tx: Peri<'static, peripherals::PA2>,
+        pub pin_rx: Peri<'static, peripherals::PA3>,
+        pub tx_dma: Peri<'static, peripherals::DMA1_CH3>,
+        pub rx_dma: Peri<'static, peripherals::DMA1_CH4>,
+    }
+
+    /// Read commands line by line and answer each of them.
+    #[embassy_executor::task]
+    pub async fn cli_task(resources: CliResources) {
+        let mut uart = match Uart::new(
+            resources.usart,
+            resources.pin_rx,
+            resources.pin_tx,
+            Irqs,
+            resources.tx_dma,
+            resources.rx_dma,
+            usart::Config::default(),
+        ) {
+            Ok(uart) => uart,
+            Err(_) => {
+                warn!("Failed to configure the CLI UART");
+                return;
+            }
+        };
+
+        let mut line: String<MAX_LINE> = String::new();
+        let mut overflow = false;
+        loop {
+            let mut byte = [0u8];
+            if uart.read(&mut byte).await.is_err() {
+                // Framing or overrun errors lose the line in progress
+                line.clear();
+                continue;
+            }
+
+            match byte[0] {
+                b'\r' | b'\n' => {
+                    if overflow {
+                        respond(&mut uart, "line too long\r\n").await;
+                    } else if !line.is_empty() {
+                        execute(&mut uart, &line).await;
+                    }
+                    line.clear();
+                    overflow = false;
+                }
+                c if c.is_ascii() => overflow |= line.push(c as char).is_err(),
+                _ => {}
+            }
+        }
+    }
+
+    /// Answer a line, passing the policy commands to `control::call`.
+    async fn execute(uart: &mut Uart<'static, Async>, line: &str) {
+        let command = match Command::parse(line) {
+            Ok(command) => command,
+            Err(err) => {
+                let mut reply: String<80> = String::new();
+                let _ = write!(reply, "error: {}\r\n", err.message());
+                respond(uart, &reply).await;
+                return;
+            }
+        };
+        info!("CLI command: {}", command);
+
+        let mut reply: String<256> = String::new();
+        match command {
+            Command::Set { voltage_mv } => {
+                let answer = control::call(control::Command::SetTarget { voltage_mv }).await;
+                let _ = write_reply(&mut reply, &answer);
+            }
+            Command::Status => {
+                let answer = control::call(control::Command::GetContract).await;
+                let _ = write_reply(&mut reply, &answer);
+                #[cfg(feature = "ina226")]
+                if let Some(telemetry) = crate::telemetry::latest() {
+                    let _ = write!(
+                        reply,
+                        "measured {}mV @ {}mA, {}mW\r\n",
+                        telemetry.vbus_mv, telemetry.current_ma, telemetry.power_mw
+                    );
+                }
+            }
+            Command::Force { position } => {
+                let answer = control::call(control::Command::ForcePdo { position }).await;
+                let _ = write_reply(&mut reply, &answer);
+            }
+            Command::Dump => {
+                let caps = match control::call(control::Command::GetCaps).await {
+                    Reply::Caps(caps) => caps,
+                    _ => None,
+                };
+                let mut dump: String<DUMP_LEN> = String::new();
+                let written = write_dump(
+                    &mut dump,
+                    power::cable_orientation(),
+                    &power::current_contract(),
+                    caps.as_ref(),
+                    &Stats::current(),
+                )
+                .and_then(|_| dump.write_str("\r\n"));
+                if written.is_err() {
+                    dump.clear();
+                    let _ = dump.push_str("error: dump too long\r\n");
+                }
+                respond(uart, &dump).await;
+                return;
+            }
+            Command::History => {
+                let events = event::history();
+                if events.is_empty() {
+                    let _ = reply.push_str("no events\r\n");
+                }
+                for event in &events {
+                    let mut line: String<128> = String::new();
+                    let _ = write!(line, "{:?}\r\n", event);
+                    respond(uart, &line).await;
+                }
+            }
+            Command::Best { reset: true } => {
+                Stats::reset_best_contract();
+                let _ = reply.push_str("best contract cleared\r\n");
+            }
+            Command::Best { reset: false } => {
+                let _ = match Stats::current().best_contract {
+                    Some(best) => write!(
+                        reply,
+                        "best {}mV @ {}mA ({}mW), PDO {}{}\r\n",
+                        best.voltage_mv,
+                        best.current_ma,
+                        best.power_mw(),
+                        best.pdo_position,
+                        if best.is_epr { " (EPR)" } else { "" }
+                    ),
+                    None => write!(reply, "no contract since boot\r\n"),
+                };
+            }
+            Command::Help => {
+                respond(uart, HELP).await;
+                return;
+            }
+        }
+        respond(uart, &reply).await;
+    }
+
+    async fn respond(uart: &mut Uart<'static, Async>, reply: &str) {
+        if uart.write(reply.as_bytes()).await.is_err() {
+            warn!("CLI write failed");
+        }
+    }
+}
@@ -0,0 +1,145 @@
+//! Clock setup for UCPD based firmwares.
+//!
+//! Constraints of the clock tree:
+//! - UCPD runs from HSI16, which must stay on whatever drives the system clock.
+//! - USB needs 48MHz within 0.25%. The PLL Q output only divides by 2, 4, 6 or 8, so from
+//!   the 340MHz VCO of the 170MHz system clock it gives 170, 85, 56.67 or 42.5MHz, none of
+//!   them usable. USB therefore runs from HSI48, trimmed by the CRS against the start of
+//!   frame packets of the host, which leaves the PLL to the system clock.
+//!
+//! The combination is checked by `pd_usb_clock_config` at startup, see `check_clocks`.
+#[cfg(target_os = "none")]
+use embassy_stm32::rcc::{
+    Hse, HseMode, Hsi48Config, Pll, PllMul, PllPDiv, PllPreDiv, PllQDiv, PllRDiv, PllSource,
+    Sysclk, mux,
+};
+#[cfg(target_os = "none")]
+use embassy_stm32::time::Hertz;
+
+/// HSE crystal frequency
+pub const HSE_HZ: u32 = 8_000_000;
+/// HSI16 frequency
+const HSI_HZ: u32 = 16_000_000;
+
+/// USB clock frequency and the tolerance of full speed USB
+const USB_HZ: u32 = 48_000_000;
+const USB_TOLERANCE_HZ: u32 = USB_HZ / 400;
+
+/// Source of the 48MHz clock of the USB peripheral.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Usb48Source {
+    /// HSI48 synchronized to the host by the CRS
+    Hsi48,
+    /// The PLL Q output, which must then run at 48MHz
+    PllQ,
+}
+
+/// USB clock source used by `pd_usb_clock_config`
+pub const USB_CLOCK_SOURCE: Usb48Source = Usb48Source::Hsi48;
+
+/// Check that the clock tree serves UCPD and, with `usb`, USB from `source`.
+///
+/// `hsi48` is whether HSI48 is on and `pll_q_hz` the PLL Q output, 0 when it is off.
+/// Returns why the combination doesn't work otherwise.
+pub const fn check_clocks(
+    hsi16: bool,
+    usb: bool,
+    source: Usb48Source,
+    hsi48: bool,
+    pll_q_hz: u32,
+) -> Result<(), &'static str> {
+    if !hsi16 {
+        return Err("UCPD needs HSI16 as its kernel clock");
+    }
+    if !usb {
+        return Ok(());
+    }
+    match source {
+        Usb48Source::Hsi48 if !hsi48 => Err("USB from HSI48 needs HSI48 on, trimmed by the CRS"),
+        Usb48Source::PllQ if pll_q_hz.abs_diff(USB_HZ) > USB_TOLERANCE_HZ => {
+            Err("USB needs 48MHz from PLL Q, use HSI48 with CRS with the 170MHz PLL")
+        }
+        _ => Ok(()),
+    }
+}
+
+/// PLL Q output frequency of `rcc` in Hz, 0 if the PLL or its Q output is off.
+#[cfg(target_os = "none")]
+fn pll_q_hz(rcc: &embassy_stm32::rcc::Config) -> u32 {
+    let Some(pll) = &rcc.pll else {
+        return 0;
+    };
+    let input = match pll.source {
+        PllSource::HSE => rcc.hse.as_ref().map_or(Hertz(0), |hse| hse.freq),
+        PllSource::HSI => Hertz(HSI_HZ),
+        _ => Hertz(0),
+    };
+    pll.divq
+        .map_or(0, |divq| (input / pll.prediv * pll.mul / divq).0)
+}
+
+/// Peripheral configuration with clocks suitable for UCPD.
+///
+/// Runs at 170MHz from an 8MHz HSE crystal through the PLL, with boost mode enabled.
+/// HSI16 is kept on as the UCPD kernel clock.
+///
+/// With `dead_battery`, the UCPD dead-battery pull-downs stay active through init. DB1 (PA9)
+/// must be wired to CC1 (PB6) and DB2 (PA10) to CC2 (PB4), so the UCPD presents Rd and the
+/// source supplies VBUS before the firmware is running.
+#[cfg(target_os = "none")]
+pub fn pd_clock_config(dead_battery: bool) -> embassy_stm32::Config {
+    let mut config = embassy_stm32::Config::default();
+    // HSI must be enabled for UCPD
+    config.rcc.hsi = true;
+    config.rcc.hse = Some(Hse {
+        freq: Hertz(HSE_HZ),
+        mode: HseMode::Oscillator,
+    });
+    config.rcc.pll = Some(Pll {
+        source: PllSource::HSE,
+        prediv: PllPreDiv::DIV2,
+        mul: PllMul::MUL85, // 170 MHz
+        divp: Some(PllPDiv::DIV2),
+        divq: Some(PllQDiv::DIV2),
+        divr: Some(PllRDiv::DIV2),
+    });
+    config.rcc.boost = true;
+    config.rcc.sys = Sysclk::PLL1_R;
+    config.enable_ucpd1_dead_battery = dead_battery;
+    config
+}
+
+/// `pd_clock_config` with the 48MHz USB clock added, for USB alongside UCPD.
+///
+/// The USB peripheral on PA11 (D-) and PA12 (D+) runs from `USB_CLOCK_SOURCE`. Panics if
+/// the resulting configuration doesn't pass `check_clocks`, e.g. after changing it here,
+/// rather than enumerate on a wrong clock.
+#[cfg(target_os = "none")]
+pub fn pd_usb_clock_config(dead_battery: bool) -> embassy_stm32::Config {
+    let mut config = pd_clock_config(dead_battery);
+    config.rcc.mux.clk48sel = match USB_CLOCK_SOURCE {
+        Usb48Source::Hsi48 => {
+            config.rcc.hsi48 = Some(Hsi48Config {
+                sync_from_usb: true,
+            });
+            mux::Clk48sel::HSI48
+        }
+        Usb48Source::PllQ => mux::Clk48sel::PLL1_Q,
+    };
+    let source = match config.rcc.mux.clk48sel {
+        mux::Clk48sel::PLL1_Q => Usb48Source::PllQ,
+        _ => Usb48Source::Hsi48,
+    };
+    let checked = check_clocks(
+        config.rcc.hsi,
+        true,
+        source,
+        config.rcc.hsi48.is_some(),
+        pll_q_hz(&config.rcc),
+    );
+    if let Err(reason) = checked {
+        panic!("Invalid clock configuration: {}", reason);
+    }
+    config
+}
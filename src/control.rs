@@ -0,0 +1,132 @@
+//! Typed command/reply interface to the sink policy, independent of the transport.
+//!
+//! The backbone for host control over UART or USB: a transport turns its input into a
+//! `Command`, passes it to `call` and encodes the `Reply`. `control_task` applies the
+//! commands to the shared policy state, one at a time.
+#[cfg(target_os = "none")]
+pub use task::control_task;
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
+use embassy_sync::mutex::Mutex;
+
+use crate::caps::CapsSnapshot;
+use crate::fmt::info;
+use crate::power::{self, Contract, RENEGOTIATE};
+
+/// A request to the policy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Command {
+    /// New AVS and PPS target voltage, applied by renegotiating
+    SetTarget { voltage_mv: u32 },
+    /// Request a PDO position regardless of the policy, `None` to go back to the policy
+    ForcePdo { position: Option<u8> },
+    /// The contract currently in place
+    GetContract,
+    /// The capabilities of the attached source
+    GetCaps,
+    /// Hard reset the link, e.g. to recover a stuck source
+    ForceReset,
+}
+
+/// The answer to a `Command`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Reply {
+    /// The target was stored, renegotiation follows while attached
+    TargetSet { voltage_mv: u32 },
+    /// The target was stored, but the attached source offers no PPS or AVS PDO to apply it to
+    NoAdjustablePdo { voltage_mv: u32 },
+    /// The forced position was stored and renegotiation follows, `None` for the policy
+    PdoForced { position: Option<u8> },
+    /// The contract in place, `Contract::NONE` without one
+    Contract(Contract),
+    /// The latest capabilities, `None` while detached or before the first ones arrived
+    Caps(Option<CapsSnapshot>),
+    /// The hard reset was handed to the driver
+    ResetRequested,
+    /// The command needs an attached source
+    NotAttached,
+}
+
+/// Commands queued for `control_task`, with the number of their call
+static COMMANDS: Channel<CriticalSectionRawMutex, (u32, Command), 1> = Channel::new();
+/// Replies of `control_task`, with the number of the call they answer
+static REPLIES: Channel<CriticalSectionRawMutex, (u32, Reply), 1> = Channel::new();
+/// Held for a command and its reply, so replies of concurrent transports don't cross
+static CALLER: Mutex<CriticalSectionRawMutex, ()> = Mutex::new(());
+/// Number of the next call
+static NEXT_CALL: AtomicU32 = AtomicU32::new(0);
+
+/// Send `command` to `control_task` and wait for its reply.
+pub async fn call(command: Command) -> Reply {
+    let _caller = CALLER.lock().await;
+    let call = NEXT_CALL.fetch_add(1, Ordering::Relaxed);
+    COMMANDS.send((call, command)).await;
+    loop {
+        let (answered, reply) = REPLIES.receive().await;
+        // Callers dropped before their reply, e.g. by a USB disconnect, leave it behind
+        if answered == call {
+            return reply;
+        }
+    }
+}
+
+/// Apply `command` to the policy, with `caps` the latest capabilities of the attachment.
+fn execute(command: Command, caps: Option<&CapsSnapshot>) -> Reply {
+    info!("Control command: {}", command);
+    match command {
+        Command::SetTarget { voltage_mv } => {
+            power::set_target_voltage(voltage_mv);
+            let adjustable = caps
+                .filter(|_| power::cable_orientation().is_some())
+                .is_none_or(CapsSnapshot::offers_adjustable);
+            if !adjustable {
+                // Fixed PDOs are chosen by the policy, a renegotiation would change nothing
+                return Reply::NoAdjustablePdo { voltage_mv };
+            }
+            RENEGOTIATE.signal(());
+            Reply::TargetSet { voltage_mv }
+        }
+        Command::ForcePdo { position } => {
+            power::force_pdo(position);
+            RENEGOTIATE.signal(());
+            Reply::PdoForced { position }
+        }
+        Command::GetContract => Reply::Contract(power::current_contract()),
+        Command::GetCaps => Reply::Caps(
+            caps.filter(|_| power::cable_orientation().is_some())
+                .cloned(),
+        ),
+        Command::ForceReset if power::cable_orientation().is_none() => Reply::NotAttached,
+        Command::ForceReset => {
+            power::request_hard_reset();
+            Reply::ResetRequested
+        }
+    }
+}
+
+/// Command handling, only available on the target.
+#[cfg(target_os = "none")]
+mod task {
+    use super::{COMMANDS, REPLIES, execute};
+    use crate::caps::CAPS;
+
+    /// Apply commands from `call` to the policy and send back the replies.
+    #[embassy_executor::task]
+    pub async fn control_task() {
+        let mut caps_subscriber = CAPS.subscriber().unwrap();
+        let mut caps = None;
+        loop {
+            let (call, command) = COMMANDS.receive().await;
+            // Only the newest capabilities are kept while nobody asks
+            while let Some(snapshot) = caps_subscriber.try_next_message_pure() {
+                caps = Some(snapshot);
+            }
+            REPLIES.send((call, execute(command, caps.as_ref()))).await;
+        }
+    }
+}
@@ -0,0 +1,150 @@
+//! Live PD status on an SSD1306 OLED over I2C.
+//!
+//! Shows the cable orientation, the contract with SPR/EPR mode and the PDOs of the last
+//! received capabilities. Only the shared `Contract` and `CapsSnapshot` are used, so the
+//! display doesn't depend on the UCPD driver.
+#[cfg(target_os = "none")]
+pub use oled::{DisplayResources, display_task};
+
+use core::fmt::Write;
+use heapless::String;
+
+use crate::caps::CapsSnapshot;
+use crate::power::{CableOrientation, Contract};
+
+/// Characters per line with the 5x8 font on a 128 pixel wide display
+pub const LINE_LEN: usize = 25;
+/// Lines with the 5x8 font on a 64 pixel high display
+pub const LINES: usize = 8;
+/// PDOs shown per line, in columns
+const PDOS_PER_LINE: usize = 2;
+/// Width of a PDO column, the widest PDO is e.g. `3.3-21V 3A`
+const PDO_COLUMN_LEN: usize = 12;
+
+/// One line of text on the display.
+pub type Line = String<LINE_LEN>;
+
+/// Lay out the status as text lines: orientation, contract, then the PDOs in two columns.
+pub fn status_lines(
+    orientation: Option<CableOrientation>,
+    contract: &Contract,
+    caps: Option<&CapsSnapshot>,
+) -> [Line; LINES] {
+    let mut lines: [Line; LINES] = Default::default();
+
+    let _ = match orientation {
+        None => write!(lines[0], "No source"),
+        Some(CableOrientation::Normal) => write!(lines[0], "CC1"),
+        Some(CableOrientation::Flipped) => write!(lines[0], "CC2"),
+        Some(CableOrientation::DebugAccessoryMode) => write!(lines[0], "Debug acc."),
+    };
+    if let Some(caps) = caps.filter(|_| orientation.is_some()) {
+        let _ = write!(
+            lines[0],
+            " {} PDOs {}",
+            caps.pdos.len(),
+            if caps.epr { "EPR" } else { "SPR" }
+        );
+    }
+    let _ = write!(lines[1], "{}", contract);
+
+    let Some(caps) = caps.filter(|_| orientation.is_some()) else {
+        return lines;
+    };
+    for (index, pdo) in caps.pdos.iter().enumerate() {
+        let Some(line) = lines.get_mut(2 + index / PDOS_PER_LINE) else {
+            break;
+        };
+        let column_start = (index % PDOS_PER_LINE) * (PDO_COLUMN_LEN + 1);
+        while line.len() < column_start {
+            let _ = line.push(' ');
+        }
+        let mut cell: String<PDO_COLUMN_LEN> = String::new();
+        let _ = write!(cell, "{}", pdo);
+        let _ = line.push_str(&cell);
+    }
+    lines
+}
+
+/// SSD1306 handling, only available on the target.
+#[cfg(target_os = "none")]
+mod oled {
+    use embassy_futures::select::{Either, select};
+    use embassy_stm32::i2c::{self, I2c};
+    use embassy_stm32::time::Hertz;
+    use embassy_stm32::{Peri, peripherals};
+    use embassy_time::{Duration, Timer};
+    use embedded_graphics::mono_font::MonoTextStyle;
+    use embedded_graphics::mono_font::ascii::FONT_5X8;
+    use embedded_graphics::pixelcolor::BinaryColor;
+    use embedded_graphics::prelude::*;
+    use embedded_graphics::text::{Baseline, Text};
+    use ssd1306::prelude::*;
+    use ssd1306::{I2CDisplayInterface, Ssd1306};
+
+    use super::status_lines;
+    use crate::caps::CAPS;
+    use crate::fmt::{info, warn};
+    use crate::power::{cable_orientation, current_contract};
+
+    /// Redraw interval, for contract and orientation changes
+    const REFRESH_INTERVAL: Duration = Duration::from_millis(250);
+    /// Height of a text line in pixels
+    const LINE_HEIGHT: i32 = 8;
+
+    pub struct DisplayResources {
+        pub i2c: Peri<'static, peripherals::I2C1>,
+        pub pin_scl: Peri<'static, peripherals::PA15>,
+        pub pin_sda: Peri<'static, peripherals::PB7>,
+    }
+
+    /// Render the PD status on a 128x64 SSD1306, redrawing on new capabilities and periodically.
+    #[embassy_executor::task]
+    pub async fn display_task(resources: DisplayResources) {
+        let i2c = I2c::new_blocking(
+            resources.i2c,
+            resources.pin_scl,
+            resources.pin_sda,
+            Hertz::khz(400),
+            i2c::Config::default(),
+        );
+        let interface = I2CDisplayInterface::new(i2c);
+        let mut display = Ssd1306::new(interface, DisplaySize128x64, DisplayRotation::Rotate0)
+            .into_buffered_graphics_mode();
+        if display.init().is_err() {
+            warn!("SSD1306 not responding, display disabled");
+            return;
+        }
+        info!("Display ready");
+
+        let style = MonoTextStyle::new(&FONT_5X8, BinaryColor::On);
+        let mut caps_subscriber = CAPS.subscriber().unwrap();
+        let mut caps = None;
+        loop {
+            let orientation = cable_orientation();
+            if orientation.is_none() {
+                caps = None;
+            }
+
+            display.clear_buffer();
+            let lines = status_lines(orientation, &current_contract(), caps.as_ref());
+            for (row, line) in lines.iter().enumerate() {
+                let position = Point::new(0, row as i32 * LINE_HEIGHT);
+                let _ =
+                    Text::with_baseline(line, position, style, Baseline::Top).draw(&mut display);
+            }
+            if display.flush().is_err() {
+                warn!("Display update failed");
+            }
+
+            if let Either::First(snapshot) = select(
+                caps_subscriber.next_message_pure(),
+                Timer::after(REFRESH_INTERVAL),
+            )
+            .await
+            {
+                caps = Some(snapshot);
+            }
+        }
+    }
+}
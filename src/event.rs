@@ -0,0 +1,77 @@
+//! Structured log of the PD negotiation timeline.
+use core::cell::RefCell;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
+use heapless::{HistoryBuf, Vec};
+
+use crate::battery::{BatteryCapabilities, BatteryStatus};
+use crate::fmt::info;
+use crate::power::{CableOrientation, Contract, HardResetOrigin};
+use crate::source_status::SourceStatus;
+
+/// A step in the PD negotiation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum PdEvent {
+    /// Cable attached and debounced
+    Attached(CableOrientation),
+    /// Source_Capabilities received
+    CapsReceived { pdo_count: u8, epr: bool },
+    /// Request sent for the given contract
+    Requested(Contract),
+    /// Request accepted, contract in place
+    Accepted(Contract),
+    /// Request rejected by the source, as seen by the driver
+    Rejected,
+    /// PS_RDY received, the new voltage is stable
+    PsRdy,
+    /// PR_Swap requested by the source and answered
+    PrSwap { accepted: bool },
+    /// DR_Swap requested by the source and answered
+    DrSwap { accepted: bool },
+    /// GotoMin received, the load is off until the next contract
+    GotoMin,
+    /// Hard reset sent or received
+    HardReset(HardResetOrigin),
+    /// Battery_Capabilities received from the source
+    BatteryCapabilities(BatteryCapabilities),
+    /// Battery_Status received from the source
+    BatteryStatus(BatteryStatus),
+    /// Status received from the source
+    SourceStatus(SourceStatus),
+    /// VBUS collapsed during a contract, the contract was torn down
+    Brownout { vbus_mv: u32 },
+    /// No PD communication after attach, the source only advertises `current_ma` at 5V
+    /// with its Rp
+    LegacySource { current_ma: u32 },
+    /// Cable detached
+    Detached,
+}
+
+/// Number of events buffered for a consumer before new ones are dropped
+const EVENT_QUEUE_DEPTH: usize = 16;
+
+/// Negotiation events for a host-side consumer.
+pub static PD_EVENTS: Channel<CriticalSectionRawMutex, PdEvent, EVENT_QUEUE_DEPTH> = Channel::new();
+
+/// Number of recent events kept for post-mortem dumps
+pub const HISTORY_LEN: usize = 32;
+
+/// The latest events, the oldest overwritten first, whether or not anyone consumes them.
+static HISTORY: Mutex<CriticalSectionRawMutex, RefCell<HistoryBuf<PdEvent, HISTORY_LEN>>> =
+    Mutex::new(RefCell::new(HistoryBuf::new()));
+
+/// Log an event and queue it for consumers.
+///
+/// Events are dropped if the queue is full, so logging never blocks the negotiation.
+pub fn log_event(event: PdEvent) {
+    info!("PD event: {}", event);
+    HISTORY.lock(|history| history.borrow_mut().write(event));
+    let _ = PD_EVENTS.try_send(event);
+}
+
+/// Copy of the recent events, oldest first.
+pub fn history() -> Vec<PdEvent, HISTORY_LEN> {
+    HISTORY.lock(|history| history.borrow().oldest_ordered().copied().collect())
+}
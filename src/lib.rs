@@ -1,2 +1,35 @@
-#![no_std]
+#![cfg_attr(not(feature = "std"), no_std)]
+
+// This mod MUST go first, so that the others see its macros.
+pub(crate) mod fmt;
+
+pub mod alert;
+pub mod battery;
+pub mod cable;
+pub mod caps;
+pub mod cli;
+pub mod clock;
+pub mod control;
+#[cfg(feature = "display")]
+pub mod display;
+pub mod event;
+pub mod load;
+pub mod persist;
 pub mod power;
+pub mod pps_sweep;
+pub mod profile;
+#[cfg(feature = "std")]
+pub mod sim;
+pub mod sink_caps;
+#[cfg(feature = "source")]
+pub mod source;
+pub mod source_status;
+pub mod stats;
+pub mod status;
+#[cfg(feature = "ina226")]
+pub mod telemetry;
+pub mod temp;
+#[cfg(feature = "usb-cli")]
+pub mod usb_cli;
+pub mod vbus;
+pub mod vconn;
@@ -0,0 +1,61 @@
+//! Load switch between VBUS and the downstream circuit.
+use core::sync::atomic::{AtomicBool, Ordering};
+
+#[cfg(target_os = "none")]
+pub use pin::install;
+
+static LOAD_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Whether the load is currently connected to VBUS.
+pub fn is_enabled() -> bool {
+    LOAD_ENABLED.load(Ordering::Relaxed)
+}
+
+/// Connect the load, once the source has confirmed the contract with PS_RDY.
+pub(crate) fn enable() {
+    set_enabled(true);
+}
+
+/// Disconnect the load, on detach, hard reset or a contract that could not be confirmed.
+pub(crate) fn disable() {
+    set_enabled(false);
+}
+
+fn set_enabled(enabled: bool) {
+    if LOAD_ENABLED.swap(enabled, Ordering::Relaxed) != enabled {
+        crate::fmt::info!("Load {}", if enabled { "enabled" } else { "disabled" });
+    }
+    #[cfg(target_os = "none")]
+    pin::set_enabled(enabled);
+}
+
+/// Enable pin of the load switch, only available on the target.
+#[cfg(target_os = "none")]
+mod pin {
+    use core::cell::RefCell;
+
+    use embassy_stm32::gpio::Output;
+    use embassy_sync::blocking_mutex::Mutex;
+    use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+
+    static LOAD_PIN: Mutex<CriticalSectionRawMutex, RefCell<Option<Output<'static>>>> =
+        Mutex::new(RefCell::new(None));
+
+    /// Take over the active-high enable pin of the load switch, starting disabled.
+    pub fn install(mut pin: Output<'static>) {
+        pin.set_low();
+        LOAD_PIN.lock(|load_pin| *load_pin.borrow_mut() = Some(pin));
+    }
+
+    pub(super) fn set_enabled(enabled: bool) {
+        LOAD_PIN.lock(|load_pin| {
+            if let Some(pin) = load_pin.borrow_mut().as_mut() {
+                if enabled {
+                    pin.set_high();
+                } else {
+                    pin.set_low();
+                }
+            }
+        });
+    }
+}
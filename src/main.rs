@@ -9,39 +9,141 @@ use panic_halt as _;
 use {defmt_rtt as _, panic_probe as _};
 
 use embassy_executor::Spawner;
-use embassy_stm32::{
-    gpio::{Level, Output, Speed},
-    rcc::{Hse, HseMode, Pll, PllMul, PllPDiv, PllPreDiv, PllQDiv, PllRDiv, PllSource, Sysclk},
-    time::mhz,
-};
+#[cfg(feature = "pwm-led")]
+use embassy_stm32::gpio::OutputType;
+use embassy_stm32::gpio::{Input, Level, Output, Pull, Speed};
+#[cfg(feature = "pwm-led")]
+use embassy_stm32::peripherals;
+#[cfg(feature = "pwm-led")]
+use embassy_stm32::time::khz;
+#[cfg(feature = "pwm-led")]
+use embassy_stm32::timer::low_level::CountingMode;
+#[cfg(feature = "pwm-led")]
+use embassy_stm32::timer::simple_pwm::{PwmPin, SimplePwm};
+#[cfg(feature = "pwm-led")]
+use embassy_time::Instant;
 use embassy_time::{Duration, Timer};
-use stm32g431_pd_demo::power::{self, UcpdResources};
+use stm32g431_pd_demo::cli::{self, CliResources};
+use stm32g431_pd_demo::clock;
+use stm32g431_pd_demo::control;
+#[cfg(feature = "display")]
+use stm32g431_pd_demo::display::{self, DisplayResources};
+use stm32g431_pd_demo::persist::{self, ContractStore};
+use stm32g431_pd_demo::power::UcpdResources;
+#[cfg(not(feature = "source"))]
+use stm32g431_pd_demo::power::{self, UcpdConfig};
+use stm32g431_pd_demo::profile;
+#[cfg(feature = "source")]
+use stm32g431_pd_demo::source;
+use stm32g431_pd_demo::source_status;
+use stm32g431_pd_demo::stats;
+use stm32g431_pd_demo::status;
+#[cfg(feature = "ina226")]
+use stm32g431_pd_demo::telemetry::{self, Ina226Resources};
+use stm32g431_pd_demo::temp::{self, TempSensor};
+#[cfg(feature = "usb-cli")]
+use stm32g431_pd_demo::usb_cli::{self, UsbResources};
+#[cfg(not(feature = "source"))]
+use stm32g431_pd_demo::vbus::VbusMonitor;
+#[cfg(not(feature = "source"))]
+use stm32g431_pd_demo::vconn;
 
 #[embassy_executor::main]
 async fn main(spawner: Spawner) {
-    let mut stm32_config = embassy_stm32::Config::default();
-    // HSI must be enabled for UCPD
-    stm32_config.rcc.hsi = true;
-    stm32_config.rcc.hse = Some(Hse {
-        freq: mhz(8),
-        mode: HseMode::Oscillator,
-    });
-    stm32_config.rcc.pll = Some(Pll {
-        source: PllSource::HSE,
-        prediv: PllPreDiv::DIV2,
-        mul: PllMul::MUL85, // 170 MHz
-        divp: Some(PllPDiv::DIV2),
-        divq: Some(PllQDiv::DIV2),
-        divr: Some(PllRDiv::DIV2),
-    });
-    stm32_config.rcc.boost = true;
-    stm32_config.rcc.sys = Sysclk::PLL1_R;
-    // connect DBx pins to CCx for dead battery feature and enable this:
-    // stm32_config.enable_ucpd1_dead_battery = true;
-
+    // Dead-battery operation keeps the UCPD pull-downs active, see `pd_clock_config`
+    #[cfg(not(feature = "usb-cli"))]
+    let stm32_config = clock::pd_clock_config(cfg!(feature = "dead-battery"));
+    #[cfg(feature = "usb-cli")]
+    let stm32_config = clock::pd_usb_clock_config(cfg!(feature = "dead-battery"));
     let p = embassy_stm32::init(stm32_config);
-    let led = Output::new(p.PC6, Level::High, Speed::Low);
-    spawner.spawn(blink_led(led).unwrap());
+
+    // Pick up where the last session left off
+    let mut contract_store = ContractStore::new(p.FLASH);
+    if let Some(saved) = contract_store.load() {
+        persist::restore(saved);
+    }
+    spawner.spawn(persist::persist_task(contract_store).unwrap());
+
+    #[cfg(not(feature = "pwm-led"))]
+    {
+        let led = Output::new(p.PC6, Level::High, Speed::Low);
+        spawner.spawn(blink_led(led).unwrap());
+    }
+    // PC6 is TIM3_CH1
+    #[cfg(feature = "pwm-led")]
+    {
+        let led_pin = PwmPin::new(p.PC6, OutputType::PushPull);
+        let pwm = SimplePwm::new(
+            p.TIM3,
+            Some(led_pin),
+            None,
+            None,
+            None,
+            khz(1),
+            CountingMode::EdgeAlignedUp,
+        );
+        spawner.spawn(pwm_led(pwm).unwrap());
+    }
+
+    // Profile button on PA0, shorts to ground when pressed
+    let button = Input::new(p.PA0, Pull::Up);
+    spawner.spawn(profile::profile_task(button).unwrap());
+
+    // Control interface on USART2, TX on PA2 and RX on PA3
+    let cli_resources = CliResources {
+        usart: p.USART2,
+        pin_tx: p.PA2,
+        pin_rx: p.PA3,
+        tx_dma: p.DMA1_CH3,
+        rx_dma: p.DMA1_CH4,
+    };
+    spawner.spawn(cli::cli_task(cli_resources).unwrap());
+
+    // 10k NTC to ground on PA4 (ADC2_IN17), pulled up by 10k
+    let temp_sensor = TempSensor::new(p.ADC2, p.PA4, 10_000);
+    spawner.spawn(temp::thermal_task(temp_sensor).unwrap());
+
+    // Liveness log for soak tests
+    spawner.spawn(stats::heartbeat_task().unwrap());
+
+    // Typed command/reply access to the policy for host interfaces
+    spawner.spawn(control::control_task().unwrap());
+
+    // Source temperature and protection events while a contract is in place
+    spawner.spawn(source_status::status_poll_task().unwrap());
+
+    // INA226 on I2C3, SCL on PA8 and SDA on PB5, with a 10mΩ shunt in VBUS
+    #[cfg(feature = "ina226")]
+    {
+        let ina226_resources = Ina226Resources {
+            i2c: p.I2C3,
+            pin_scl: p.PA8,
+            pin_sda: p.PB5,
+        };
+        spawner.spawn(telemetry::ina226_task(ina226_resources, 10).unwrap());
+    }
+
+    // USB CDC-ACM control, D- on PA11 and D+ on PA12, clocked from HSI48
+    #[cfg(feature = "usb-cli")]
+    {
+        let usb_resources = UsbResources {
+            usb: p.USB,
+            pin_dp: p.PA12,
+            pin_dm: p.PA11,
+        };
+        spawner.spawn(usb_cli::usb_cdc_task(usb_resources).unwrap());
+    }
+
+    // SSD1306 on I2C1, SCL on PA15 and SDA on PB7
+    #[cfg(feature = "display")]
+    {
+        let display_resources = DisplayResources {
+            i2c: p.I2C1,
+            pin_scl: p.PA15,
+            pin_sda: p.PB7,
+        };
+        spawner.spawn(display::display_task(display_resources).unwrap());
+    }
 
     let ucpd_resources = UcpdResources {
         pin_cc1: p.PB6,
@@ -50,15 +152,62 @@ async fn main(spawner: Spawner) {
         rx_dma: p.DMA1_CH1,
         tx_dma: p.DMA1_CH2,
     };
-    spawner.spawn(power::ucpd_task(ucpd_resources).unwrap());
+    #[cfg(not(feature = "source"))]
+    {
+        // VBUS through a 100k/10k divider on PA1 (ADC1_IN2)
+        let vbus = VbusMonitor::new(p.ADC1, p.PA1, 11, 500);
+        // Active-high enable of the load switch on PB0
+        let load_enable = Output::new(p.PB0, Level::Low, Speed::Low);
+        // Active-high enables of the VCONN switches onto CC1 on PB12 and CC2 on PB13
+        vconn::install(
+            Output::new(p.PB12, Level::Low, Speed::Low),
+            Output::new(p.PB13, Level::Low, Speed::Low),
+        );
+        let ucpd_config = UcpdConfig::default();
+        spawner
+            .spawn(power::ucpd_task(ucpd_resources, vbus, load_enable, ucpd_config, None).unwrap());
+    }
+    #[cfg(feature = "source")]
+    {
+        // Active-high enable of the 5V supply to VBUS on PB1
+        let vbus_enable = Output::new(p.PB1, Level::Low, Speed::Low);
+        spawner.spawn(source::ucpd_source_task(ucpd_resources, vbus_enable).unwrap());
+    }
 }
 
+/// Show the PD state on the LED, the pattern is picked up at the start of each period.
+#[cfg(not(feature = "pwm-led"))]
 #[embassy_executor::task]
 async fn blink_led(mut led: Output<'static>) {
     loop {
-        led.set_high();
-        Timer::after(Duration::from_millis(500)).await;
-        led.set_low();
-        Timer::after(Duration::from_millis(500)).await;
+        for &(on_ms, off_ms) in status::pd_state().led_pattern() {
+            if on_ms > 0 {
+                led.set_high();
+                Timer::after(Duration::from_millis(on_ms)).await;
+            }
+            if off_ms > 0 {
+                led.set_low();
+                Timer::after(Duration::from_millis(off_ms)).await;
+            }
+        }
+    }
+}
+
+/// Brightness update interval of the PWM LED, smooth enough for breathing
+#[cfg(feature = "pwm-led")]
+const PWM_LED_STEP: Duration = Duration::from_millis(20);
+
+/// Show the PD state as a brightness pattern on the PWM driven LED.
+#[cfg(feature = "pwm-led")]
+#[embassy_executor::task]
+async fn pwm_led(mut pwm: SimplePwm<'static, peripherals::TIM3>) {
+    let mut led = pwm.ch1();
+    led.enable();
+    let start = Instant::now();
+    loop {
+        let elapsed_ms = start.elapsed().as_millis() as u32;
+        let pattern = status::pd_state().led_brightness();
+        led.set_duty_cycle_percent(pattern.brightness_at(elapsed_ms));
+        Timer::after(PWM_LED_STEP).await;
     }
 }
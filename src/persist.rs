@@ -0,0 +1,163 @@
+//! Last accepted contract, kept in flash so a power cycle requests the same voltage again.
+use core::cell::Cell;
+
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
+
+#[cfg(target_os = "none")]
+pub use store::{ContractStore, persist_task};
+
+use crate::fmt::info;
+use crate::profile::Profile;
+
+/// Contract details stored across power cycles.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct SavedContract {
+    /// Profile selected when the contract was accepted
+    pub profile: Profile,
+    /// Object position of the accepted PDO
+    pub pdo_position: u8,
+    /// Voltage of the accepted contract in mV
+    pub voltage_mv: u32,
+}
+
+/// Marks a valid record, erased flash reads as all ones
+const MAGIC: u32 = 0x5044_0001;
+/// Size of an encoded record, a multiple of the 8 byte flash write size
+pub const RECORD_SIZE: usize = 16;
+
+impl SavedContract {
+    /// Encode as magic, profile and position, voltage and checksum words.
+    pub fn to_bytes(&self) -> [u8; RECORD_SIZE] {
+        let selection = self.profile as u32 | ((self.pdo_position as u32) << 8);
+        let words = [
+            MAGIC,
+            selection,
+            self.voltage_mv,
+            checksum(selection, self.voltage_mv),
+        ];
+
+        let mut bytes = [0; RECORD_SIZE];
+        for (chunk, word) in bytes.chunks_exact_mut(4).zip(words) {
+            chunk.copy_from_slice(&word.to_le_bytes());
+        }
+        bytes
+    }
+
+    /// Decode a record, `None` for erased or corrupted flash.
+    pub fn from_bytes(bytes: &[u8; RECORD_SIZE]) -> Option<Self> {
+        let word = |i: usize| u32::from_le_bytes(bytes[i * 4..i * 4 + 4].try_into().unwrap());
+        let (selection, voltage_mv) = (word(1), word(2));
+        if word(0) != MAGIC || word(3) != checksum(selection, voltage_mv) {
+            return None;
+        }
+
+        Some(Self {
+            profile: Profile::from_index(selection as u8)?,
+            pdo_position: (selection >> 8) as u8,
+            voltage_mv,
+        })
+    }
+}
+
+fn checksum(selection: u32, voltage_mv: u32) -> u32 {
+    !(MAGIC ^ selection ^ voltage_mv)
+}
+
+/// Contracts waiting to be written, only the latest is kept.
+static SAVE_REQUEST: Signal<CriticalSectionRawMutex, SavedContract> = Signal::new();
+
+/// Contract read at boot, requested again on the first attach.
+static RESTORED: Mutex<CriticalSectionRawMutex, Cell<Option<SavedContract>>> =
+    Mutex::new(Cell::new(None));
+
+/// Select the profile of a stored contract and prefer its voltage on the first attach.
+pub fn restore(saved: SavedContract) {
+    info!("Restoring {} at {}mV", saved.profile, saved.voltage_mv);
+    saved.profile.select();
+    RESTORED.lock(|restored| restored.set(Some(saved)));
+}
+
+/// Take the restored contract, if it has not been requested yet.
+pub(crate) fn take_restored() -> Option<SavedContract> {
+    RESTORED.lock(|restored| restored.take())
+}
+
+/// Queue an accepted contract for writing, without blocking the negotiation.
+pub(crate) fn save(contract: SavedContract) {
+    SAVE_REQUEST.signal(contract);
+}
+
+/// Flash storage, only available on the target.
+#[cfg(target_os = "none")]
+mod store {
+    use embassy_stm32::Peri;
+    use embassy_stm32::flash::{Blocking, Flash};
+    use embassy_stm32::peripherals::FLASH;
+
+    use super::{RECORD_SIZE, SAVE_REQUEST, SavedContract};
+    use crate::fmt::{debug, warn};
+
+    /// Last 2K page of the 128K flash, left out of FLASH in memory.x so the linker keeps
+    /// the firmware below it
+    const STORE_OFFSET: u32 = 126 * 1024;
+    const PAGE_SIZE: u32 = 2 * 1024;
+
+    /// Keeps the last accepted contract in the last flash page.
+    pub struct ContractStore {
+        flash: Flash<'static, Blocking>,
+        /// Contract currently in flash, to skip writes that change nothing
+        stored: Option<SavedContract>,
+    }
+
+    impl ContractStore {
+        pub fn new(flash: Peri<'static, FLASH>) -> Self {
+            Self {
+                flash: Flash::new_blocking(flash),
+                stored: None,
+            }
+        }
+
+        /// Read the stored contract, `None` if the page is erased or invalid.
+        pub fn load(&mut self) -> Option<SavedContract> {
+            let mut bytes = [0; RECORD_SIZE];
+            if self.flash.blocking_read(STORE_OFFSET, &mut bytes).is_err() {
+                warn!("Failed to read stored contract");
+                return None;
+            }
+            self.stored = SavedContract::from_bytes(&bytes);
+            self.stored
+        }
+
+        /// Write a contract, unless it is already stored.
+        pub fn save(&mut self, contract: SavedContract) {
+            if self.stored == Some(contract) {
+                return;
+            }
+
+            debug!("Storing {}", contract);
+            let result = self
+                .flash
+                .blocking_erase(STORE_OFFSET, STORE_OFFSET + PAGE_SIZE)
+                .and_then(|_| {
+                    self.flash
+                        .blocking_write(STORE_OFFSET, &contract.to_bytes())
+                });
+            match result {
+                Ok(()) => self.stored = Some(contract),
+                Err(_) => warn!("Failed to store contract"),
+            }
+        }
+    }
+
+    /// Write accepted contracts to flash, off the negotiation path.
+    #[embassy_executor::task]
+    pub async fn persist_task(mut store: ContractStore) {
+        loop {
+            let contract = SAVE_REQUEST.wait().await;
+            store.save(contract);
+        }
+    }
+}
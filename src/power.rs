@@ -1,24 +1,58 @@
 //! Handles USB PD negotiation.
-use defmt::{Format, info, warn};
-use embassy_futures::select::{Either, select};
-use embassy_stm32::ucpd::{self, CcPhy, CcPull, CcSel, CcVState, PdPhy, Ucpd};
-use embassy_stm32::{Peri, bind_interrupts, peripherals};
-use embassy_time::{Duration, Timer, with_timeout};
-use uom::si::power::watt;
+use core::cell::Cell;
+use core::cmp::Reverse;
+#[cfg(target_os = "none")]
+use core::future::poll_fn;
+use core::marker::PhantomData;
+use core::sync::atomic::{AtomicBool, AtomicU8, AtomicU32, Ordering};
+#[cfg(target_os = "none")]
+use core::task::Poll;
+use embassy_futures::select::{Either, Either3, Either4, select, select3, select4};
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
+use embassy_sync::signal::Signal;
+use embassy_sync::waitqueue::AtomicWaker;
+use embassy_sync::watch::Watch;
+use embassy_time::{Duration, Timer};
+use uom::si::electric_potential::millivolt;
+use uom::si::power::{milliwatt, watt};
 use usbpd::protocol_layer::message::data::request::{
-    Avs, CurrentRequest, FixedVariableSupply, PowerSource, VoltageRequest,
+    Avs, Battery, CurrentRequest, FixedVariableSupply, PowerSource, Pps, VoltageRequest,
 };
 use usbpd::protocol_layer::message::data::source_capabilities::{
-    Augmented, PowerDataObject, SourceCapabilities,
+    Augmented, FixedSupply, PowerDataObject, SourceCapabilities,
 };
 use usbpd::sink::device_policy_manager::{DevicePolicyManager, Event};
-use usbpd::sink::policy_engine::Sink;
 use usbpd::timers::Timer as SinkTimer;
-use usbpd::units::Power;
-use usbpd_traits::Driver as SinkDriver;
-use {defmt_rtt as _, panic_probe as _};
+use usbpd::units::{ElectricPotential, Power};
+
+use crate::alert::{ALERTS, Alert};
+use crate::cable;
+use crate::caps::{self, CapsSummary};
+use crate::event::{PdEvent, log_event};
+use crate::fmt::{debug, error, info, warn};
+use crate::load;
+use crate::persist::{self, SavedContract};
+use crate::profile::{PPS_PROFILE_MV, PROFILE_CHANGED, Profile};
+use crate::sink_caps::{MAX_SINK_PDOS, SinkCapabilitiesBuilder, SinkPdo, VSAFE_5V};
+use crate::stats;
+use crate::status::{PdState, set_pd_state};
+use crate::temp;
+use crate::vbus::VbusMonitor;
+
+#[cfg(target_os = "none")]
+pub(crate) use hw::Irqs;
+#[cfg(target_os = "none")]
+pub use hw::{UcpdResources, ucpd_task};
+
+#[cfg(target_os = "none")]
+mod hw;
+#[cfg(any(target_os = "none", test))]
+mod query;
 
 /// Print source capabilities in a nice format using defmt
+#[cfg(feature = "pd-verbose")]
 fn print_capabilities(caps: &SourceCapabilities) {
     let is_epr = caps.is_epr_capabilities();
     if is_epr {
@@ -40,7 +74,17 @@ fn print_capabilities(caps: &SourceCapabilities) {
     info!("=========================================");
 }
 
+/// Print a one-line summary of the negotiated contract
+fn print_contract(voltage_mv: u32, current_ma: u32, position: u8, epr: bool) {
+    let epr = if epr { " (EPR)" } else { "" };
+    info!(
+        "Contract: {}mV @ {}mA{} PDO#{}",
+        voltage_mv, current_ma, epr, position
+    );
+}
+
 /// Print a single PDO
+#[cfg(feature = "pd-verbose")]
 fn print_pdo(position: u8, pdo: &PowerDataObject) {
     match pdo {
         PowerDataObject::FixedSupply(f) => {
@@ -121,105 +165,426 @@ fn print_pdo(position: u8, pdo: &PowerDataObject) {
     }
 }
 
-bind_interrupts!(struct Irqs {
-    UCPD1 => ucpd::InterruptHandler<peripherals::UCPD1>;
-});
+/// Timing knobs of the attach detection and negotiation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timing {
+    /// How long the CC lines must be stable before an attach is accepted (tCCDebounce)
+    pub cc_debounce: Duration,
+    /// Time the CC lines may show a partner without settling into an attach, before the
+    /// pull-downs are reset to start attach detection from scratch
+    pub attach_timeout: Duration,
+    /// Time allowed from attach until the first contract, before tearing down and retrying
+    pub negotiation_timeout: Duration,
+    /// Delay between EPR mode entry and the first EPR request in ms, for chargers that need
+    /// to settle. Keep it well below tSenderResponse (24ms), the source doesn't wait longer.
+    pub post_epr_entry_delay_ms: u64,
+    /// Time each intermediate contract of the ramp ladder holds before the next request in
+    /// ms, see `Device::with_ramp`
+    pub ramp_settle_ms: u64,
+}
+
+impl Default for Timing {
+    fn default() -> Self {
+        Self {
+            cc_debounce: Timing::CC_DEBOUNCE_MIN,
+            attach_timeout: Duration::from_secs(10),
+            negotiation_timeout: Duration::from_secs(5),
+            post_epr_entry_delay_ms: 5,
+            ramp_settle_ms: 500,
+        }
+    }
+}
+
+impl Timing {
+    /// Lower bound of tCCDebounce
+    pub const CC_DEBOUNCE_MIN: Duration = Duration::from_millis(100);
+    /// Upper bound of tCCDebounce
+    pub const CC_DEBOUNCE_MAX: Duration = Duration::from_millis(200);
+
+    /// Clamp the settings into the ranges allowed by the spec.
+    pub fn validated(mut self) -> Self {
+        let cc_debounce = self
+            .cc_debounce
+            .clamp(Self::CC_DEBOUNCE_MIN, Self::CC_DEBOUNCE_MAX);
+        if cc_debounce != self.cc_debounce {
+            warn!(
+                "CC debounce of {}ms outside of tCCDebounce, using {}ms",
+                self.cc_debounce.as_millis(),
+                cc_debounce.as_millis()
+            );
+            self.cc_debounce = cc_debounce;
+        }
+        self
+    }
+}
+
+/// Side of the link that started a hard reset.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum HardResetOrigin {
+    /// Sent by the sink, on request or by the policy engine after a protocol error
+    Sent,
+    /// Received from the source
+    Received,
+}
+
+/// Termination presented on the CC lines while waiting for an attach.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum CcTermination {
+    /// Rd on both CC lines, the regular sink
+    Rd,
+    /// No termination, the source never sees the sink, e.g. to test the attach timeout.
+    /// Not a sink termination, kept by `UcpdConfig::validated` with a warning.
+    Open,
+    /// Rp advertising the given current, a source termination refused by
+    /// `UcpdConfig::validated`
+    Rp(RpCurrent),
+}
+
+/// Configuration of the UCPD task.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UcpdConfig {
+    /// Debounce, timeouts and delays
+    pub timing: Timing,
+    /// Termination on the CC lines, Rd unless testing special adapters
+    pub cc_termination: CcTermination,
+    /// Accept PR_Swap requests instead of rejecting them.
+    ///
+    /// Only set this on hardware that can source VBUS. The firmware answers the request,
+    /// the source role itself is not implemented.
+    pub dual_role: bool,
+    /// Accept DR_Swap requests and become the DFP, instead of rejecting them
+    pub accept_dr_swap: bool,
+    /// Operational PDP in W, requested on EPR mode entry
+    pub operational_pdp_watts: u32,
+    /// Use EPR mode with EPR capable sources, otherwise stay in SPR mode
+    pub epr_enabled: bool,
+    /// Lowest voltage the load works with in mV, see `Device::with_min_voltage`
+    pub min_voltage_mv: u32,
+    /// Lowest current the load needs in mA, checked against the Rp level of sources that
+    /// don't speak PD. The load stays off on those advertising less.
+    pub min_current_ma: u32,
+    /// Retries of a transmission discarded due to a collision, nRetryCount by default
+    pub tx_retries: u8,
+    /// Request vSafe5V on attach before the target, see `Device::with_safe_5v_first`
+    pub safe_5v_first: bool,
+    /// No USB Suspend flag of requests, see `Device::with_no_usb_suspend`
+    pub no_usb_suspend: bool,
+    /// USB Communications Capable flag, see `Device::with_usb_communications_capable`
+    pub usb_communications_capable: bool,
+    /// Never connect the load, see `Device::with_dry_run`
+    pub dry_run: bool,
+    /// Request only vSafe5V in a dry run, see `Device::with_dry_run`
+    pub dry_run_safe_5v: bool,
+    /// Renegotiate on capabilities with more power, see `Device::with_upgrade_on_caps_change`
+    pub upgrade_on_caps_change: bool,
+    /// Only ever request the first PDO, see `Device::with_accept_first`
+    pub accept_first: bool,
+    /// Ceiling on every requested current in mA, see `Device::with_global_max_current`
+    pub global_max_current_ma: u32,
+    /// Prefer a fixed EPR PDO at the target over AVS, see `Device::with_prefer_epr_fixed`
+    pub prefer_epr_fixed: bool,
+    /// Intermediate voltages in mV stepped through towards the target, see `Device::with_ramp`
+    pub ramp: &'static [u32],
+    /// Source VCONN to the cable while querying its e-marker, see [`crate::vconn`].
+    ///
+    /// Without a VCONN_Swap the source remains the VCONN source and may drive VCONN
+    /// itself, which the board's 5V would then back-drive. VCONN is only switched on
+    /// while the line reads unpowered; enable this only with switches that block
+    /// reverse current.
+    pub vconn_source: bool,
+}
+
+impl Default for UcpdConfig {
+    fn default() -> Self {
+        Self {
+            timing: Timing::default(),
+            cc_termination: CcTermination::Rd,
+            dual_role: false,
+            accept_dr_swap: false,
+            operational_pdp_watts: DEFAULT_OPERATIONAL_PDP_WATTS,
+            epr_enabled: true,
+            min_voltage_mv: 0,
+            min_current_ma: 0,
+            tx_retries: N_RETRY_COUNT,
+            safe_5v_first: false,
+            no_usb_suspend: true,
+            usb_communications_capable: true,
+            dry_run: false,
+            dry_run_safe_5v: false,
+            upgrade_on_caps_change: false,
+            accept_first: false,
+            global_max_current_ma: DEFAULT_GLOBAL_MAX_CURRENT_MA,
+            prefer_epr_fixed: true,
+            ramp: &[],
+            vconn_source: false,
+        }
+    }
+}
 
-pub struct UcpdResources {
-    pub ucpd: Peri<'static, peripherals::UCPD1>,
-    pub pin_cc1: Peri<'static, peripherals::PB6>,
-    pub pin_cc2: Peri<'static, peripherals::PB4>,
-    pub rx_dma: Peri<'static, peripherals::DMA1_CH1>,
-    pub tx_dma: Peri<'static, peripherals::DMA1_CH2>,
+impl UcpdConfig {
+    /// Clamp the settings into the ranges allowed by the spec.
+    ///
+    /// Rd is the only legal sink termination: Rp is replaced by it, `Open` is kept for
+    /// testing but warned about.
+    pub fn validated(mut self) -> Self {
+        self.timing = self.timing.validated();
+        match self.cc_termination {
+            CcTermination::Rd => {}
+            CcTermination::Open => {
+                warn!("Open CC lines are not a sink termination, no source will attach")
+            }
+            CcTermination::Rp(current) => {
+                warn!(
+                    "Rp ({}mA) is not a sink termination, using Rd",
+                    current.current_ma()
+                );
+                self.cc_termination = CcTermination::Rd;
+            }
+        }
+        self
+    }
 }
 
-#[derive(Debug, Format)]
-enum CableOrientation {
+/// Orientation of the attached cable, from the CC line carrying Rp.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum CableOrientation {
+    /// Source on CC1
     Normal,
+    /// Source on CC2
     Flipped,
+    /// Rp on both CC lines
     DebugAccessoryMode,
 }
 
-struct UcpdSinkDriver<'d> {
-    /// The UCPD PD phy instance.
-    pd_phy: PdPhy<'d, peripherals::UCPD1>,
+/// Tasks that may wait in `wait_for_detach` or `wait_for_contract` at the same time
+const STATE_WAITERS: usize = 4;
+
+/// Orientation on every attach, `None` on detach, with a receiver per waiting task.
+static ORIENTATION: Watch<CriticalSectionRawMutex, Option<CableOrientation>, STATE_WAITERS> =
+    Watch::new();
+
+/// Copy of the latest orientation for readers that don't wait.
+static CURRENT_ORIENTATION: Mutex<CriticalSectionRawMutex, Cell<Option<CableOrientation>>> =
+    Mutex::new(Cell::new(None));
+
+/// Orientation of the attached cable, `None` when detached.
+pub fn cable_orientation() -> Option<CableOrientation> {
+    CURRENT_ORIENTATION.lock(|orientation| orientation.get())
 }
 
-impl<'d> UcpdSinkDriver<'d> {
-    fn new(pd_phy: PdPhy<'d, peripherals::UCPD1>) -> Self {
-        Self { pd_phy }
+/// Wait until the cable is detached, returning right away if nothing is attached.
+///
+/// Up to `STATE_WAITERS` tasks may wait here and in `wait_for_contract` at a time.
+pub async fn wait_for_detach() {
+    let mut receiver = ORIENTATION
+        .receiver()
+        .expect("too many tasks waiting for a detach");
+    while cable_orientation().is_some() {
+        if receiver.changed().await.is_none() {
+            return;
+        }
     }
 }
 
-impl SinkDriver for UcpdSinkDriver<'_> {
-    async fn wait_for_vbus(&self) {
-        // The sink policy engine is only running when attached. Therefore VBus is present.
-    }
+/// USB data role of the sink.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum DataRole {
+    /// Upstream facing port, the role of a sink after attach
+    Ufp,
+    /// Downstream facing port, after an accepted DR_Swap
+    Dfp,
+}
 
-    async fn receive(&mut self, buffer: &mut [u8]) -> Result<usize, usbpd_traits::DriverRxError> {
-        self.pd_phy.receive(buffer).await.map_err(|err| match err {
-            ucpd::RxError::Crc | ucpd::RxError::Overrun => usbpd_traits::DriverRxError::Discarded,
-            ucpd::RxError::HardReset => usbpd_traits::DriverRxError::HardReset,
-        })
+static DATA_ROLE_DFP: AtomicBool = AtomicBool::new(false);
+
+/// Current data role, reset to UFP on attach and hard reset.
+pub fn data_role() -> DataRole {
+    if DATA_ROLE_DFP.load(Ordering::Relaxed) {
+        DataRole::Dfp
+    } else {
+        DataRole::Ufp
     }
+}
 
-    async fn transmit(&mut self, data: &[u8]) -> Result<(), usbpd_traits::DriverTxError> {
-        self.pd_phy.transmit(data).await.map_err(|err| match err {
-            ucpd::TxError::Discarded => usbpd_traits::DriverTxError::Discarded,
-            ucpd::TxError::HardReset => usbpd_traits::DriverTxError::HardReset,
-        })
+/// Progress of the vSafe5V first request, see `Device::with_safe_5v_first`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SafeFirst {
+    /// Request the target right away
+    Off,
+    /// The first request is for vSafe5V
+    Request,
+    /// vSafe5V requested, renegotiate for the target
+    Upgrade,
+    /// Upgraded, requests go for the target
+    Done,
+}
+
+/// Messages sent by the driver on behalf of the policy, outside of the policy engine.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub(crate) enum DriverRequest {
+    /// Query the source's extended capabilities with Get_Source_Cap_Extended
+    SourceCapExtended,
+    /// Query a battery of the source with Get_Battery_Cap
+    BatteryCapabilities { battery: u8 },
+    /// Query a battery of the source with Get_Battery_Status
+    BatteryStatus { battery: u8 },
+    /// Query the cable's e-marker with Discover Identity over SOP'
+    DiscoverCableIdentity,
+    /// Query the source's temperature and events with Get_Status
+    Status,
+}
+
+/// Requests queued for the driver before it gets to them
+const DRIVER_REQUEST_DEPTH: usize = 4;
+
+/// Queued by the policy and other tasks to have the driver send a message the policy
+/// engine can't.
+pub(crate) static DRIVER_REQUEST: Channel<
+    CriticalSectionRawMutex,
+    DriverRequest,
+    DRIVER_REQUEST_DEPTH,
+> = Channel::new();
+
+/// Whether the policy engine waits for an event in PE_SNK_Ready, with no AMS in progress
+static PE_READY: AtomicBool = AtomicBool::new(false);
+/// Woken when the policy engine gets to PE_SNK_Ready
+static PE_READY_WAKER: AtomicWaker = AtomicWaker::new();
+
+/// Marks the policy engine ready while alive.
+///
+/// The engine only asks for events in PE_SNK_Ready, so `get_event` holds one while it
+/// waits. Dropped as soon as the engine leaves the state, including when a received
+/// message wins over the event.
+struct ReadyGuard;
+
+impl ReadyGuard {
+    fn new() -> Self {
+        PE_READY.store(true, Ordering::Relaxed);
+        PE_READY_WAKER.wake();
+        ReadyGuard
     }
+}
 
-    async fn transmit_hard_reset(&mut self) -> Result<(), usbpd_traits::DriverTxError> {
-        self.pd_phy
-            .transmit_hardreset()
-            .await
-            .map_err(|err| match err {
-                ucpd::TxError::Discarded => usbpd_traits::DriverTxError::Discarded,
-                ucpd::TxError::HardReset => usbpd_traits::DriverTxError::HardReset,
-            })
+impl Drop for ReadyGuard {
+    fn drop(&mut self) {
+        PE_READY.store(false, Ordering::Relaxed);
     }
 }
 
-async fn wait_detached<T: ucpd::Instance>(cc_phy: &mut CcPhy<'_, T>) {
-    loop {
-        let (cc1, cc2) = cc_phy.vstate();
-        if cc1 == CcVState::LOWEST && cc2 == CcVState::LOWEST {
-            return;
+/// Whether the policy engine is idle in PE_SNK_Ready, where a query of the driver doesn't
+/// run into an AMS in progress.
+pub(crate) fn pe_ready() -> bool {
+    PE_READY.load(Ordering::Relaxed)
+}
+
+/// The next request for the driver, taken only while the policy engine is in
+/// PE_SNK_Ready so the driver's exchange doesn't interleave with an AMS.
+#[cfg(target_os = "none")]
+pub(crate) async fn ready_driver_request() -> DriverRequest {
+    poll_fn(|cx| {
+        PE_READY_WAKER.register(cx.waker());
+        if !PE_READY.load(Ordering::Relaxed) {
+            return Poll::Pending;
         }
-        cc_phy.wait_for_vstate_change().await;
-    }
+        DRIVER_REQUEST.poll_receive(cx)
+    })
+    .await
 }
 
-// Returns true when the cable was attached.
-async fn wait_attached<T: ucpd::Instance>(cc_phy: &mut CcPhy<'_, T>) -> CableOrientation {
-    loop {
-        let (cc1, cc2) = cc_phy.vstate();
-        if cc1 == CcVState::LOWEST && cc2 == CcVState::LOWEST {
-            // Detached, wait until attached by monitoring the CC lines.
-            cc_phy.wait_for_vstate_change().await;
-            continue;
+/// Control message types answering the driver's messages (USB PD 3.2 Table 6.5), for the
+/// driver and the tests of its query handling
+#[cfg(any(target_os = "none", test))]
+const CONTROL_GOOD_CRC: u16 = 0b0_0001;
+#[cfg(any(target_os = "none", test))]
+const CONTROL_REJECT: u16 = 0b0_0100;
+#[cfg(any(target_os = "none", test))]
+const CONTROL_NOT_SUPPORTED: u16 = 0b1_0000;
+
+/// Why a message sent by the driver outside of the policy engine got no answer.
+#[cfg(any(target_os = "none", test))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub(crate) enum QueryError {
+    /// Transmission failed, or no acknowledged answer within tSenderResponse
+    NoResponse,
+    /// The source answered with Reject
+    Rejected,
+    /// The source answered with Not_Supported, it doesn't implement the message. Benign
+    /// for the optional queries, the contract stays in place.
+    NotSupported,
+}
+
+/// Kind of a message, its type numbers overlap between the kinds (USB PD 3.2 6.2.1.1.2).
+#[cfg(any(target_os = "none", test))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum MessageKind {
+    /// Control message, without data objects
+    Control,
+    /// Data message, with at least one data object
+    Data,
+    /// Extended message
+    Extended,
+}
+
+#[cfg(any(target_os = "none", test))]
+impl MessageKind {
+    /// Kind of the message with the given header.
+    pub(crate) fn of(header: u16) -> Self {
+        if header & (1 << 15) != 0 {
+            MessageKind::Extended
+        } else if (header >> 12) & 0x7 == 0 {
+            MessageKind::Control
+        } else {
+            MessageKind::Data
         }
+    }
+}
 
-        // Attached, wait for CC lines to be stable for tCCDebounce (100..200ms).
-        if with_timeout(Duration::from_millis(100), cc_phy.wait_for_vstate_change())
-            .await
-            .is_ok()
-        {
-            // State has changed, restart detection procedure.
-            continue;
-        };
+/// A message received while the driver waits for the answer to its message.
+#[cfg(any(target_os = "none", test))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum QueryReply {
+    /// GoodCRC for the message with the given MessageID
+    GoodCrc { message_id: u8 },
+    /// The expected answer
+    Answer,
+    /// Reject or Not_Supported instead of the answer
+    Refused(QueryError),
+    /// Not part of the exchange, left to the policy engine
+    Unrelated,
+}
 
-        // State was stable for the complete debounce period, check orientation.
-        return match (cc1, cc2) {
-            (_, CcVState::LOWEST) => CableOrientation::Normal, // CC1 connected
-            (CcVState::LOWEST, _) => CableOrientation::Flipped, // CC2 connected
-            _ => CableOrientation::DebugAccessoryMode,         // Both connected (special cable)
-        };
+#[cfg(any(target_os = "none", test))]
+impl QueryReply {
+    /// Classify a message by its header, expecting an answer of `answer_type` and
+    /// `answer_kind`.
+    pub(crate) fn classify(header: u16, answer_kind: MessageKind, answer_type: u16) -> Self {
+        let kind = MessageKind::of(header);
+        let is_control = kind == MessageKind::Control;
+        let message_type = header & 0x1F;
+        if is_control && message_type == CONTROL_GOOD_CRC {
+            return QueryReply::GoodCrc {
+                message_id: ((header >> 9) & 0x7) as u8,
+            };
+        }
+        if kind == answer_kind && message_type == answer_type {
+            return QueryReply::Answer;
+        }
+        match message_type {
+            CONTROL_REJECT if is_control => QueryReply::Refused(QueryError::Rejected),
+            CONTROL_NOT_SUPPORTED if is_control => QueryReply::Refused(QueryError::NotSupported),
+            _ => QueryReply::Unrelated,
+        }
     }
 }
 
-struct EmbassySinkTimer {}
+/// Timer of the policy engine and the sink policy, backed by the embassy time driver.
+pub struct EmbassySinkTimer {}
 
 impl SinkTimer for EmbassySinkTimer {
     async fn after_millis(milliseconds: u64) {
@@ -227,235 +592,3102 @@ impl SinkTimer for EmbassySinkTimer {
     }
 }
 
-/// Target voltage for AVS request (24V)
-const TARGET_AVS_VOLTAGE_V: u32 = 24;
-/// Target current for AVS request (5A in 50mA units)
-const TARGET_AVS_CURRENT_RAW: u16 = 5 * 20; // 5A = 100 in 50mA units
-/// Operational PDP for EPR mode entry (24V × 5A = 120W)
-const OPERATIONAL_PDP_WATTS: u32 = 120;
+#[cfg(any(
+    all(feature = "profile-20v", feature = "profile-28v"),
+    all(feature = "profile-20v", feature = "profile-pps-15v"),
+    all(feature = "profile-28v", feature = "profile-pps-15v"),
+))]
+compile_error!("select at most one of the profile-20v, profile-28v and profile-pps-15v features");
 
-#[derive(Default)]
-struct Device {
-    /// Tracks whether we've requested to enter EPR mode
-    entered_epr_mode: bool,
+/// Default target voltage for AVS request: 24V, or 20V or 28V with the `profile-20v` and
+/// `profile-28v` features
+const DEFAULT_TARGET_AVS_MV: u32 = if cfg!(feature = "profile-20v") {
+    20_000
+} else if cfg!(feature = "profile-28v") {
+    28_000
+} else {
+    24_000
+};
+/// Prefer SPR PPS over the default policy, with the `profile-pps-15v` feature
+const DEFAULT_PPS_PREFERRED: bool = cfg!(feature = "profile-pps-15v");
+/// Default target voltage for PPS request: 15V with the `profile-pps-15v` feature,
+/// otherwise the PPS profile's
+const DEFAULT_TARGET_PPS_MV: u32 = if cfg!(feature = "profile-pps-15v") {
+    15_000
+} else {
+    PPS_PROFILE_MV
+};
+/// Default target current for AVS request (5A)
+const DEFAULT_TARGET_AVS_CURRENT_MA: u32 = 5_000;
+/// Default ceiling on every requested current, high enough to never apply
+const DEFAULT_GLOBAL_MAX_CURRENT_MA: u32 = u32::MAX;
+/// Default target current for PPS request (3A)
+const DEFAULT_TARGET_PPS_CURRENT_MA: u32 = 3_000;
+/// nRetryCount of PD 3.x
+const N_RETRY_COUNT: u8 = 2;
+/// Default operational PDP for EPR mode entry (24V × 5A = 120W)
+pub const DEFAULT_OPERATIONAL_PDP_WATTS: u32 = 120;
+/// Default minimum EPR PDP worth staying in EPR mode for
+const DEFAULT_MIN_EPR_PDP_WATTS: u32 = DEFAULT_OPERATIONAL_PDP_WATTS;
+/// Power levels advertised in response to Get_Sink_Cap
+pub const SINK_PDOS: [SinkPdo; 3] = [
+    VSAFE_5V,
+    SinkPdo::Fixed {
+        voltage_mv: 20_000,
+        current_ma: 5_000,
+    },
+    SinkPdo::Pps {
+        min_voltage_mv: 5_000,
+        max_voltage_mv: 21_000,
+        current_ma: 5_000,
+    },
+];
+
+/// A contract to request, as one entry of a preference list.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Preference {
+    /// EPR AVS at `voltage_mv`, drawing the AVS target current
+    Avs { voltage_mv: u32 },
+    /// SPR PPS at `voltage_mv`, drawing the PPS target current
+    Pps { voltage_mv: u32 },
+    /// Fixed supply at `voltage_mv`
+    Fixed { voltage_mv: u32 },
+    /// Variable supply whose range covers `voltage_mv`
+    Variable { voltage_mv: u32 },
+    /// Battery supply whose range covers `voltage_mv`, drawing `power_mw`
+    Battery { voltage_mv: u32, power_mw: u32 },
+    /// Lowest fixed supply voltage that provides `power_mw`, for the best converter efficiency.
+    ///
+    /// If no fixed PDO provides it, the most powerful one is requested with Capability
+    /// Mismatch set, so later entries of the list are not tried.
+    LowestSufficient { power_mw: u32 },
 }
 
-impl DevicePolicyManager for Device {
-    async fn inform(&mut self, source_capabilities: &SourceCapabilities) {
-        // Print capabilities when we receive them
-        print_capabilities(source_capabilities);
+/// Current a source advertises with its Rp, all that a source without PD offers at 5V.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum RpCurrent {
+    /// Default USB Power
+    Default,
+    /// 1.5A
+    Current1A5,
+    /// 3.0A
+    Current3A0,
+}
+
+impl RpCurrent {
+    /// Current in mA, assuming USB 2.0's 500mA for Default USB Power.
+    pub fn current_ma(self) -> u32 {
+        match self {
+            RpCurrent::Default => 500,
+            RpCurrent::Current1A5 => 1_500,
+            RpCurrent::Current3A0 => 3_000,
+        }
     }
+}
 
-    async fn get_event(&mut self, source_capabilities: &SourceCapabilities) -> Event {
-        // After initial SPR negotiation, enter EPR mode if source is EPR capable
-        if !self.entered_epr_mode {
-            if let Some(PowerDataObject::FixedSupply(fixed)) = source_capabilities.pdos().first() {
-                if fixed.epr_mode_capable() {
-                    info!("Source is EPR capable, entering EPR mode");
-                    self.entered_epr_mode = true;
-                    return Event::EnterEprMode(Power::new::<watt>(OPERATIONAL_PDP_WATTS));
-                }
-            }
+/// Details of the power contract negotiated with the source.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Contract {
+    /// Negotiated voltage in mV (0 when there is no contract)
+    pub voltage_mv: u32,
+    /// Negotiated operating current in mA
+    pub current_ma: u32,
+    /// Object position of the requested PDO (1-based, 0 when there is no contract)
+    pub pdo_position: u8,
+    /// Whether the contract was negotiated in EPR mode
+    pub is_epr: bool,
+    /// Whether the cable is rated for the current, see `cable::current_ok`. Updated once
+    /// the cable's e-marker was read.
+    pub cable_current_ok: bool,
+}
+
+impl Contract {
+    /// State published while no source is attached.
+    pub const NONE: Self = Self {
+        voltage_mv: 0,
+        current_ma: 0,
+        pdo_position: 0,
+        is_epr: false,
+        cable_current_ok: true,
+    };
+
+    /// Returns true if this describes an actual contract.
+    pub fn is_active(&self) -> bool {
+        self.pdo_position != 0
+    }
+
+    /// Negotiated power in mW.
+    pub fn power_mw(&self) -> u32 {
+        (self.voltage_mv as u64 * self.current_ma as u64 / 1000) as u32
+    }
+}
+
+impl Default for Contract {
+    /// `Contract::NONE`, the only representation of no contract.
+    fn default() -> Self {
+        Self::NONE
+    }
+}
+
+/// Short form for narrow outputs such as a display, e.g. `20V 3.2A EPR`.
+impl core::fmt::Display for Contract {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        if !self.is_active() {
+            return f.write_str("no contract");
         }
-        core::future::pending().await
+        write!(
+            f,
+            "{} {} {}",
+            caps::Volts(self.voltage_mv),
+            caps::Amps(self.current_ma),
+            if self.is_epr { "EPR" } else { "SPR" }
+        )
     }
+}
 
-    async fn request(&mut self, source_capabilities: &SourceCapabilities) -> PowerSource {
-        // Check if source is EPR capable (from first PDO)
-        let source_epr_capable = source_capabilities
-            .pdos()
-            .first()
-            .map(|pdo| {
-                if let PowerDataObject::FixedSupply(fixed) = pdo {
-                    fixed.epr_mode_capable()
-                } else {
-                    false
-                }
-            })
-            .unwrap_or(false);
+/// Application hook called on every accepted power transition.
+///
+/// Implemented for closures and functions taking the new contract, e.g. to switch
+/// application loads without changing the policy.
+pub trait PowerTransitionHandler {
+    /// The source confirmed `contract` with PS_RDY.
+    fn on_transition(&mut self, contract: &Contract);
+}
 
-        // If we have EPR capabilities, look for AVS PDO that supports our target voltage
-        if source_capabilities.is_epr_capabilities() {
-            for (position, pdo) in source_capabilities.epr_pdos() {
-                if pdo.is_zero_padding() {
-                    continue;
-                }
+impl<F: FnMut(&Contract)> PowerTransitionHandler for F {
+    fn on_transition(&mut self, contract: &Contract) {
+        self(contract)
+    }
+}
 
-                if let PowerDataObject::Augmented(Augmented::Epr(avs)) = pdo {
-                    let min_mv = avs.raw_min_voltage() as u32 * 100;
-                    let max_mv = avs.raw_max_voltage() as u32 * 100;
-                    let target_mv = TARGET_AVS_VOLTAGE_V * 1000;
-
-                    // Check if this AVS PDO supports our target voltage
-                    if min_mv <= target_mv && target_mv <= max_mv {
-                        // Calculate max current from PDP (in 50mA units)
-                        let pdp_mw = avs.raw_pd_power() as u32 * 1000;
-                        let max_current_ma = pdp_mw / TARGET_AVS_VOLTAGE_V; // mA at target voltage
-                        let max_current_raw = (max_current_ma / 50) as u16; // Convert to 50mA units
-
-                        let current = if TARGET_AVS_CURRENT_RAW > max_current_raw {
-                            warn!(
-                                "Source max {}mA < target {}mA at {}V, using source max",
-                                max_current_raw as u32 * 50,
-                                TARGET_AVS_CURRENT_RAW as u32 * 50,
-                                TARGET_AVS_VOLTAGE_V
-                            );
-                            max_current_raw
-                        } else {
-                            TARGET_AVS_CURRENT_RAW
-                        };
-
-                        // AVS voltage is in 25mV units with LSB 2 bits = 0 (effective 100mV steps)
-                        // Per USB PD 3.2 Table 6.26: "Output voltage in 25 mV units,
-                        // the least two significant bits Shall be set to zero"
-                        let voltage_raw = ((TARGET_AVS_VOLTAGE_V * 1000 / 25) & !0x3) as u16;
+/// Latest contract, updated on every accepted request and reset on detach, with a receiver
+/// per waiting task.
+static CONTRACT: Watch<CriticalSectionRawMutex, Contract, STATE_WAITERS> = Watch::new();
 
-                        info!(
-                            "Requesting {}V AVS at position {} with {}mA (voltage_raw={})",
-                            TARGET_AVS_VOLTAGE_V,
-                            position,
-                            current as u32 * 50,
-                            voltage_raw
-                        );
+/// Copy of the latest contract for readers that don't wait.
+static CURRENT_CONTRACT: Mutex<CriticalSectionRawMutex, Cell<Contract>> =
+    Mutex::new(Cell::new(Contract::NONE));
 
-                        let rdo = Avs(0)
-                            .with_object_position(position)
-                            .with_usb_communications_capable(true)
-                            .with_no_usb_suspend(true)
-                            .with_epr_mode_capable(true)
-                            .with_raw_output_voltage(voltage_raw)
-                            .with_raw_operating_current(current);
-
-                        return PowerSource::EprRequest {
-                            rdo: rdo.0,
-                            pdo: *pdo,
-                        };
-                    }
-                }
-            }
+/// The contract currently in place, `Contract::NONE` when detached.
+pub fn current_contract() -> Contract {
+    CURRENT_CONTRACT.lock(|contract| contract.get())
+}
 
-            warn!(
-                "AVS PDO supporting {}V not found, falling back to SPR",
-                TARGET_AVS_VOLTAGE_V
-            );
+/// Wait for the next contract to be negotiated, renegotiations included.
+///
+/// Up to `STATE_WAITERS` tasks may wait here and in `wait_for_detach` at a time.
+pub async fn wait_for_contract() -> Contract {
+    let mut receiver = CONTRACT
+        .receiver()
+        .expect("too many tasks waiting for a contract");
+    // Only contracts published from now on, not the one in place
+    let _ = receiver.try_changed();
+    loop {
+        let contract = receiver.changed().await;
+        if contract.is_active() {
+            return contract;
         }
+    }
+}
 
-        // For SPR request: manually construct RDO with epr_mode_capable bit if source supports EPR
-        // This is required before EPR mode entry - the source checks this bit
-        if source_epr_capable {
-            // Find highest SPR fixed voltage
-            if let Some((position, pdo)) = source_capabilities
-                .spr_pdos()
-                .filter(|(_, p)| matches!(p, PowerDataObject::FixedSupply(_)))
-                .max_by_key(|(_, p)| {
-                    if let PowerDataObject::FixedSupply(f) = p {
-                        f.raw_voltage()
-                    } else {
-                        0
-                    }
-                })
-            {
-                if let PowerDataObject::FixedSupply(fixed) = pdo {
-                    let max_current = fixed.raw_max_current();
-                    info!(
-                        "Requesting SPR PDO {} ({}mV) with EPR capable flag",
-                        position,
-                        fixed.raw_voltage() as u32 * 50
-                    );
+/// Update the contract for both `CONTRACT` waiters and `current_contract`.
+///
+/// The cable rating is checked against the current here, so republishing the contract
+/// after reading the e-marker updates `cable_current_ok`.
+fn publish_contract(contract: Contract) {
+    let cable_current_ok = !contract.is_active() || cable::current_ok(contract.current_ma);
+    if !cable_current_ok {
+        error!(
+            "Drawing {}mA through a cable rated for {}mA",
+            contract.current_ma,
+            cable::max_current_ma().unwrap_or(0)
+        );
+    }
+    let contract = Contract {
+        cable_current_ok,
+        ..contract
+    };
+    CURRENT_CONTRACT.lock(|current| current.set(contract));
+    CONTRACT.sender().send(contract);
+}
 
-                    // Create RDO with epr_mode_capable bit set
-                    let rdo = FixedVariableSupply(0)
-                        .with_object_position(position)
-                        .with_usb_communications_capable(true)
-                        .with_no_usb_suspend(true)
-                        .with_epr_mode_capable(true) // Important for EPR mode entry!
-                        .with_raw_operating_current(max_current)
-                        .with_raw_max_operating_current(max_current);
+/// AVS target voltage picked up by the next attachment.
+static TARGET_AVS_MV: AtomicU32 = AtomicU32::new(DEFAULT_TARGET_AVS_MV);
+/// AVS target current picked up by the next attachment.
+static TARGET_AVS_CURRENT_MA: AtomicU32 = AtomicU32::new(DEFAULT_TARGET_AVS_CURRENT_MA);
 
-                    return PowerSource::FixedVariableSupply(rdo);
-                }
+/// PPS target voltage picked up by the next attachment.
+static TARGET_PPS_MV: AtomicU32 = AtomicU32::new(DEFAULT_TARGET_PPS_MV);
+/// PPS target current picked up by the next attachment.
+static TARGET_PPS_CURRENT_MA: AtomicU32 = AtomicU32::new(DEFAULT_TARGET_PPS_CURRENT_MA);
+/// Whether PPS is preferred over the default policy from the next attachment on.
+static PPS_PREFERRED: AtomicBool = AtomicBool::new(DEFAULT_PPS_PREFERRED);
+/// Preference list picked up by the next attachment, empty for the default policy.
+static PREFERENCES: Mutex<CriticalSectionRawMutex, Cell<&'static [Preference]>> =
+    Mutex::new(Cell::new(&[]));
+/// Minimum EPR PDP picked up by the next attachment.
+static MIN_EPR_PDP_WATTS: AtomicU32 = AtomicU32::new(DEFAULT_MIN_EPR_PDP_WATTS);
+/// Current of fixed requests picked up by the next attachment, 0 for the highest offered.
+static REQUESTED_CURRENT_MA: AtomicU32 = AtomicU32::new(0);
+/// PDO position requested regardless of the policy from the next attachment on, 0 for none.
+static FORCED_PDO: AtomicU8 = AtomicU8::new(0);
+/// First preference tried on this attachment, advanced past each rejected one.
+///
+/// Kept outside the policy so it survives the policy engine restarting after a Reject.
+static PREFERENCE_START: AtomicU8 = AtomicU8::new(0);
+/// Index of the preference the last request was made for, `NO_PREFERENCE` for none
+static SELECTED_PREFERENCE: AtomicU8 = AtomicU8::new(NO_PREFERENCE);
+/// `SELECTED_PREFERENCE` when the last request didn't come from the preference list
+const NO_PREFERENCE: u8 = u8::MAX;
+
+/// Set the AVS target used from the next attachment on.
+///
+/// A running negotiation keeps its current target until the cable is detached, or until
+/// `RENEGOTIATE` is raised. This applies to all targets set through the functions below.
+pub fn set_avs_target(voltage_mv: u32, current_ma: u32) {
+    TARGET_AVS_MV.store(voltage_mv, Ordering::Relaxed);
+    TARGET_AVS_CURRENT_MA.store(current_ma, Ordering::Relaxed);
+}
+
+/// Set the AVS and PPS target voltages, keeping their target currents.
+pub fn set_target_voltage(voltage_mv: u32) {
+    TARGET_AVS_MV.store(voltage_mv, Ordering::Relaxed);
+    TARGET_PPS_MV.store(voltage_mv, Ordering::Relaxed);
+}
+
+/// Request the first satisfiable entry of `preferences` from the next attachment on.
+///
+/// An empty list goes back to the default policy.
+pub fn set_preferences(preferences: &'static [Preference]) {
+    PREFERENCES.lock(|p| p.set(preferences));
+}
+
+/// Move on to the preference after the one the source rejected a request for.
+///
+/// Returns `false` if the request wasn't made for a preference, or if it was the last one.
+#[cfg(target_os = "none")]
+fn advance_preference() -> bool {
+    let selected = SELECTED_PREFERENCE.load(Ordering::Relaxed);
+    if selected == NO_PREFERENCE {
+        return false;
+    }
+    let count = PREFERENCES.lock(|p| p.get().len());
+    let next = selected as usize + 1;
+    if next >= count {
+        warn!("Request for the last preference rejected, all preferences exhausted");
+        return false;
+    }
+    info!(
+        "Request for preference {} rejected, trying preference {}",
+        selected, next
+    );
+    PREFERENCE_START.store(next as u8, Ordering::Relaxed);
+    true
+}
+
+/// Start from the first preference again, on attach.
+#[cfg(target_os = "none")]
+fn reset_preferences() {
+    PREFERENCE_START.store(0, Ordering::Relaxed);
+    SELECTED_PREFERENCE.store(NO_PREFERENCE, Ordering::Relaxed);
+}
+
+/// Only stay in EPR mode with sources whose EPR PDP is at least `pdp_watts`.
+pub fn set_min_epr_pdp(pdp_watts: u32) {
+    MIN_EPR_PDP_WATTS.store(pdp_watts, Ordering::Relaxed);
+}
+
+/// Limit the current of fixed requests from the next attachment on.
+///
+/// `None` requests the highest current the PDO offers.
+pub fn set_requested_current(current_ma: Option<u32>) {
+    REQUESTED_CURRENT_MA.store(current_ma.unwrap_or(0), Ordering::Relaxed);
+}
+
+/// Request the PDO at `position` regardless of the policy, for interop debugging.
+///
+/// The PDO is requested at its maximum current, PPS and AVS PDOs at the target voltage
+/// clamped into their range. A position the source doesn't offer is logged and the policy
+/// used instead. `None` goes back to the policy.
+pub fn force_pdo(position: Option<u8>) {
+    FORCED_PDO.store(position.unwrap_or(0), Ordering::Relaxed);
+}
+
+/// Prefer an SPR PPS contract at the given voltage (20mV steps) from the next attachment on.
+///
+/// Passing `preferred = false` goes back to the default policy.
+pub fn set_pps_target(voltage_mv: u32, current_ma: u32, preferred: bool) {
+    TARGET_PPS_MV.store(voltage_mv, Ordering::Relaxed);
+    TARGET_PPS_CURRENT_MA.store(current_ma, Ordering::Relaxed);
+    PPS_PREFERRED.store(preferred, Ordering::Relaxed);
+}
+
+/// Raised to leave EPR mode and fall back to an SPR contract.
+static EPR_EXIT_REQUEST: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Raised by other tasks to re-request power from the source, e.g. after changing targets.
+///
+/// Only acted on while attached, a signal raised while detached is dropped on the next attach.
+pub static RENEGOTIATE: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Leave EPR mode on the current attachment.
+///
+/// EPR mode is not entered again until another profile is selected.
+pub fn request_epr_exit() {
+    EPR_EXIT_REQUEST.signal(());
+}
+
+/// Hard reset the link with the source, e.g. to recover from a source that stopped answering.
+///
+/// Only acted on while attached, a request made while detached is dropped on the next attach.
+pub fn request_hard_reset() {
+    HARD_RESET_REQUEST.signal(());
+}
+
+/// Derive the contract details of a request from the capabilities it was built from.
+fn contract_for(power_source: &PowerSource, caps: &SourceCapabilities) -> Contract {
+    let pdo_position = power_source.object_position();
+    match power_source {
+        PowerSource::EprRequest {
+            rdo,
+            pdo: PowerDataObject::FixedSupply(fixed),
+        } => Contract {
+            voltage_mv: fixed.raw_voltage() as u32 * 50, // 50mV units
+            current_ma: FixedVariableSupply(*rdo).raw_operating_current() as u32 * 10, // 10mA units
+            pdo_position,
+            is_epr: true,
+            ..Contract::NONE
+        },
+        PowerSource::EprRequest { rdo, .. } => {
+            let avs = Avs(*rdo);
+            Contract {
+                voltage_mv: avs.raw_output_voltage() as u32 * 25, // 25mV units
+                current_ma: avs.raw_operating_current() as u32 * 50, // 50mA units
+                pdo_position,
+                is_epr: true,
+                ..Contract::NONE
             }
         }
-
-        // Fall back to standard request (no EPR)
-        match PowerSource::new_fixed(
-            CurrentRequest::Highest,
-            VoltageRequest::Highest,
-            source_capabilities,
-        ) {
-            Ok(ps) => {
-                info!(
-                    "Requesting highest SPR voltage (PDO {})",
-                    ps.object_position()
-                );
-                ps
+        PowerSource::Pps(rdo) => Contract {
+            voltage_mv: rdo.raw_output_voltage() as u32 * 20, // 20mV units
+            current_ma: rdo.raw_operating_current() as u32 * 50, // 50mA units
+            pdo_position,
+            is_epr: false,
+            ..Contract::NONE
+        },
+        PowerSource::FixedVariableSupply(rdo) => {
+            let voltage_mv = match pdo_at(caps, pdo_position) {
+                Some(PowerDataObject::FixedSupply(f)) => f.raw_voltage() as u32 * 50,
+                Some(PowerDataObject::VariableSupply(v)) => v.raw_min_voltage() as u32 * 50,
+                _ => 0,
+            };
+            Contract {
+                voltage_mv,
+                current_ma: rdo.raw_operating_current() as u32 * 10, // 10mA units
+                pdo_position,
+                is_epr: false,
+                ..Contract::NONE
             }
-            Err(_) => {
-                warn!("No suitable PDO found, falling back to 5V");
-                PowerSource::new_fixed(
-                    CurrentRequest::Highest,
-                    VoltageRequest::Safe5V,
-                    source_capabilities,
-                )
-                .unwrap()
+        }
+        PowerSource::Battery(rdo) => {
+            // The source may settle anywhere in the PDO range, assume its minimum until
+            // VBUS is measured
+            let voltage_mv = match pdo_at(caps, pdo_position) {
+                Some(PowerDataObject::Battery(b)) => b.raw_min_voltage() as u32 * 50,
+                _ => 0,
+            };
+            Contract {
+                voltage_mv,
+                current_ma: battery_current_ma(rdo, voltage_mv),
+                pdo_position,
+                is_epr: false,
+                ..Contract::NONE
             }
         }
+        _ => Contract {
+            pdo_position,
+            ..Contract::NONE
+        },
     }
+}
 
-    async fn transition_power(&mut self, accepted: &PowerSource) {
-        info!(
-            "Power transition accepted: PDO position {}",
-            accepted.object_position()
-        );
+/// Current drawn at `voltage_mv` for the operating power of a battery request.
+fn battery_current_ma(rdo: &Battery, voltage_mv: u32) -> u32 {
+    let power_mw = rdo.raw_operating_power() as u32 * 250; // 250mW units
+    (power_mw * 1000).checked_div(voltage_mv).unwrap_or(0)
+}
+
+/// VBUS range in mV a request allows once accepted, as `(min, max)`.
+///
+/// Variable and battery supplies may settle anywhere in the range of their PDO, all others
+/// at the contract voltage.
+fn vbus_range_mv(
+    power_source: &PowerSource,
+    caps: &SourceCapabilities,
+    contract: &Contract,
+) -> (u32, u32) {
+    match (power_source, pdo_at(caps, contract.pdo_position)) {
+        (PowerSource::FixedVariableSupply(_), Some(PowerDataObject::VariableSupply(v))) => (
+            v.raw_min_voltage() as u32 * 50, // 50mV units
+            v.raw_max_voltage() as u32 * 50,
+        ),
+        (PowerSource::Battery(_), Some(PowerDataObject::Battery(b))) => (
+            b.raw_min_voltage() as u32 * 50, // 50mV units
+            b.raw_max_voltage() as u32 * 50,
+        ),
+        _ => (contract.voltage_mv, contract.voltage_mv),
     }
 }
 
-/// Handle USB PD negotiation.
-#[embassy_executor::task]
-pub async fn ucpd_task(mut ucpd_resources: UcpdResources) {
-    loop {
-        let mut ucpd = Ucpd::new(
-            ucpd_resources.ucpd.reborrow(),
-            Irqs {},
-            ucpd_resources.pin_cc1.reborrow(),
-            ucpd_resources.pin_cc2.reborrow(),
-            Default::default(),
-        );
+/// Raised by the policy to have the driver issue a hard reset.
+static HARD_RESET_REQUEST: Signal<CriticalSectionRawMutex, ()> = Signal::new();
 
-        ucpd.cc_phy().set_pull(CcPull::Sink);
+/// Raised by the policy with the measured voltage when VBUS collapses during a contract.
+static BROWNOUT: Signal<CriticalSectionRawMutex, u32> = Signal::new();
 
-        info!("Waiting for USB connection");
-        let cable_orientation = wait_attached(ucpd.cc_phy()).await;
-        info!("USB cable attached, orientation: {}", cable_orientation);
+/// Last VBUS measured by the policy during a contract in mV, 0 before the first one.
+static MEASURED_VBUS_MV: AtomicU32 = AtomicU32::new(0);
 
-        let cc_sel = match cable_orientation {
-            CableOrientation::Normal => {
-                info!("Starting PD communication on CC1 pin");
-                CcSel::CC1
-            }
-            CableOrientation::Flipped => {
-                info!("Starting PD communication on CC2 pin");
-                CcSel::CC2
-            }
-            CableOrientation::DebugAccessoryMode => panic!("No PD communication in DAM"),
-        };
-        let (mut cc_phy, pd_phy) = ucpd.split_pd_phy(
-            ucpd_resources.rx_dma.reborrow(),
-            ucpd_resources.tx_dma.reborrow(),
-            cc_sel,
-        );
+/// Last VBUS measured by the policy on this attachment, `None` before the first contract.
+///
+/// Updated while validating a new contract and then every `BROWNOUT_POLL_MS`.
+pub fn measured_vbus_mv() -> Option<u32> {
+    match MEASURED_VBUS_MV.load(Ordering::Relaxed) {
+        0 => None,
+        measured_mv => Some(measured_mv),
+    }
+}
 
-        let driver = UcpdSinkDriver::new(pd_phy);
-        let mut sink: Sink<UcpdSinkDriver<'_>, EmbassySinkTimer, _> =
-            Sink::new(driver, Device::default());
-        info!("Run sink");
+/// Raised by the policy with the measured voltage when VBUS drops to vSafe0V during a
+/// contract, which means the source is gone even if the CC lines haven't settled yet.
+static VBUS_REMOVED: Signal<CriticalSectionRawMutex, u32> = Signal::new();
 
-        match select(sink.run(), wait_detached(&mut cc_phy)).await {
-            Either::First(result) => warn!("Sink loop broken with result: {}", result),
-            Either::Second(_) => {
-                info!("Detached");
-                continue;
-            }
+/// Alerts seen by the driver, for delivery to the policy.
+///
+/// The policy engine at the pinned usbpd revision handles Alert messages internally and has
+/// no `DevicePolicyManager` hook for them. The driver therefore inspects each received
+/// message and forwards alerts here; the policy picks them up in `get_event`.
+static ALERT_RECEIVED: Signal<CriticalSectionRawMutex, Alert> = Signal::new();
+
+/// Raised by the driver when the source answers a request with Wait.
+///
+/// Like alerts, Wait isn't reported to the `DevicePolicyManager` by the policy engine. With
+/// an explicit contract it returns to the ready state, where the policy re-sends the request.
+static WAIT_RECEIVED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Raised by the driver when CRC errors pile up, to soft reset the link.
+static CRC_ERROR_RESET: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Raised by the driver when the source rejects a request, with whether another preference
+/// is left to try.
+///
+/// Without a contract the policy engine gives up on a Reject and `ucpd_task` restarts it,
+/// with one the policy re-requests for the next preference.
+static REJECT_RECEIVED: Signal<CriticalSectionRawMutex, bool> = Signal::new();
+
+/// Raised by the driver when the source sends GotoMin, which the policy engine ignores.
+static GOTO_MIN: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Raised by the policy once the source confirmed a contract with PS_RDY.
+static CONTRACT_ESTABLISHED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Hard resets sent or received since the last stable contract.
+static HARD_RESETS: AtomicU32 = AtomicU32::new(0);
+
+/// Raised by the driver with the side that started the latest hard reset, for `ucpd_task`
+/// to log it and wait out the matching recovery.
+static HARD_RESET_ORIGIN: Signal<CriticalSectionRawMutex, HardResetOrigin> = Signal::new();
+/// A contract held for this long clears the hard reset count
+const STABLE_CONTRACT_MS: u64 = 5_000;
+
+/// Delay between an accepted transition and the VBUS measurement
+const VBUS_SETTLE_MS: u64 = 50;
+/// Interval for re-sending an active PPS request, well within tPPSTimeout (12-15s)
+const PPS_KEEP_ALIVE_MS: u64 = 8_000;
+/// Interval of the VBUS checks during a contract above vSafe5V
+const BROWNOUT_POLL_MS: u64 = 20;
+/// VBUS below this during a contract above vSafe5V is a collapse, not a transition
+const BROWNOUT_MV: u32 = 3_000;
+/// Upper bound of vSafe0V, VBUS below this during a contract means the source is removed
+const VSAFE0V_MAX_MV: u32 = 800;
+/// tSinkRequest, minimum delay before repeating a request the source answered with Wait
+const SINK_REQUEST_MS: u64 = 100;
+/// Requests repeated after Wait before giving up on them
+const MAX_WAIT_RETRIES: u8 = 3;
+
+/// Sink policy: decides what to request from the source.
+///
+/// Delays go through `T`, so host tests can record them instead of sleeping.
+pub struct Device<'a, T: SinkTimer = EmbassySinkTimer> {
+    /// Operational PDP in W, sent on EPR mode entry and required to avoid a capability mismatch
+    operational_pdp_watts: u32,
+    /// EPR mode entry was confirmed by EPR capabilities from the source
+    entered_epr_mode: bool,
+    /// EPR mode entry was requested and not confirmed yet
+    epr_entry_requested: bool,
+    /// EPR mode entry failed or timed out, don't try again on this attachment
+    epr_entry_failed: bool,
+    /// EPR mode entry was requested, the next EPR request waits `post_epr_entry_delay_ms`
+    epr_entry_pending: bool,
+    /// Settle delay between EPR mode entry and the first EPR request in ms
+    post_epr_entry_delay_ms: u64,
+    /// EPR mode may be used at all, otherwise only SPR fixed selection is done
+    epr_enabled: bool,
+    /// Lowest voltage the load works with in mV, nothing below it is requested as a fallback
+    min_voltage_mv: u32,
+    /// EPR mode was left on request, don't enter it again until the profile changes
+    epr_exit_requested: bool,
+    /// Minimum EPR PDP in W, sources below it are used in SPR mode
+    min_epr_pdp_watts: u32,
+    /// The source's EPR PDP is below the minimum, stay in SPR mode on this attachment
+    epr_pdp_insufficient: bool,
+    /// Contract that will be in place once the last request is accepted
+    requested_contract: Contract,
+    /// VBUS range in mV the requested contract allows, see `vbus_range_mv`
+    requested_vbus_mv: (u32, u32),
+    /// Target voltage for AVS request in mV
+    target_avs_mv: u32,
+    /// Target current for AVS request in mA
+    target_avs_current_ma: u32,
+    /// Used to confirm the voltage delivered after each transition
+    vbus: &'a mut VbusMonitor,
+    /// Power levels we can consume, advertised on Get_Sink_Cap
+    sink_capabilities: &'static [SinkPdo],
+    /// Request an SPR PPS contract before trying the default policy
+    pps_preferred: bool,
+    /// Target voltage for PPS request in mV
+    target_pps_mv: u32,
+    /// Target current for PPS request in mA
+    target_pps_current_ma: u32,
+    /// Last accepted PPS request, refreshed periodically while the contract is active
+    active_pps: Option<Pps>,
+    /// The source sent GotoMin, the load is off until the next contract
+    power_reduced: bool,
+    /// Progress of the vSafe5V contract requested before the target
+    safe_5v_first: SafeFirst,
+    /// `inform` delivered the source capabilities
+    caps_informed: bool,
+    /// Last request sent, repeated if the source answers with Wait
+    last_request: Option<PowerSource>,
+    /// Repetitions of `last_request` after Wait
+    wait_retries: u8,
+    /// Current of fixed requests in mA, the highest offered if not set
+    requested_current_ma: Option<u32>,
+    /// Contracts to request in order of preference, the default policy if empty
+    preferences: &'static [Preference],
+    /// PDO position to request instead of running the policy, see `force_pdo`
+    forced_pdo: Option<u8>,
+    /// Contract of the last session, requested first if the profile matches
+    restored: Option<SavedContract>,
+    /// Get_Source_Cap_Extended was already sent in this session
+    source_info_requested: bool,
+    /// No USB Suspend flag of every request, see `with_no_usb_suspend`
+    no_usb_suspend: bool,
+    /// USB Communications Capable flag of every request and the sink capabilities
+    usb_communications_capable: bool,
+    /// Negotiate and log as usual, but never connect the load
+    dry_run: bool,
+    /// In a dry run, only log the selection and request vSafe5V instead
+    dry_run_safe_5v: bool,
+    /// Renegotiate when new capabilities offer more power than those of the contract
+    upgrade_on_caps_change: bool,
+    /// Maximum power of the capabilities the last request was made against in mW
+    request_caps_mw: u32,
+    /// New capabilities offer more power, renegotiate unless a request follows anyway
+    upgrade_pending: bool,
+    /// Request the first PDO at its highest current, bypassing the policy
+    accept_first: bool,
+    /// Ceiling on the current of every request in mA, whatever the PDO offers
+    global_max_current_ma: u32,
+    /// Try a fixed EPR PDO at the AVS target before AVS, rather than after it
+    prefer_epr_fixed: bool,
+    /// Fixed voltages in mV requested in order before the target, see `with_ramp`
+    ramp: &'static [u32],
+    /// Index of the next step of `ramp`
+    ramp_step: usize,
+    /// Time an intermediate contract holds before the next request in ms
+    ramp_settle_ms: u64,
+    /// An intermediate contract was requested, the next step follows once it settled
+    ramp_pending: bool,
+    /// Called with each contract the source confirms
+    transition_handler: Option<&'a mut dyn PowerTransitionHandler>,
+    timer: PhantomData<T>,
+}
+
+impl<'a, T: SinkTimer> Device<'a, T> {
+    pub fn new(
+        target_avs_mv: u32,
+        target_avs_current_ma: u32,
+        operational_pdp_watts: u32,
+        vbus: &'a mut VbusMonitor,
+        sink_capabilities: &'static [SinkPdo],
+    ) -> Self {
+        Self {
+            operational_pdp_watts,
+            entered_epr_mode: false,
+            epr_entry_requested: false,
+            epr_entry_failed: false,
+            epr_entry_pending: false,
+            post_epr_entry_delay_ms: Timing::default().post_epr_entry_delay_ms,
+            epr_enabled: true,
+            min_voltage_mv: 0,
+            epr_exit_requested: false,
+            min_epr_pdp_watts: DEFAULT_MIN_EPR_PDP_WATTS,
+            epr_pdp_insufficient: false,
+            requested_contract: Contract::NONE,
+            requested_vbus_mv: (0, 0),
+            target_avs_mv,
+            target_avs_current_ma,
+            vbus,
+            sink_capabilities,
+            pps_preferred: DEFAULT_PPS_PREFERRED,
+            target_pps_mv: DEFAULT_TARGET_PPS_MV,
+            target_pps_current_ma: DEFAULT_TARGET_PPS_CURRENT_MA,
+            active_pps: None,
+            power_reduced: false,
+            safe_5v_first: SafeFirst::Off,
+            caps_informed: false,
+            last_request: None,
+            wait_retries: 0,
+            requested_current_ma: None,
+            preferences: &[],
+            forced_pdo: None,
+            restored: persist::take_restored(),
+            source_info_requested: false,
+            no_usb_suspend: true,
+            usb_communications_capable: true,
+            dry_run: false,
+            dry_run_safe_5v: false,
+            upgrade_on_caps_change: false,
+            request_caps_mw: 0,
+            upgrade_pending: false,
+            accept_first: false,
+            global_max_current_ma: DEFAULT_GLOBAL_MAX_CURRENT_MA,
+            prefer_epr_fixed: true,
+            ramp: &[],
+            ramp_step: 0,
+            ramp_settle_ms: Timing::default().ramp_settle_ms,
+            ramp_pending: false,
+            transition_handler: None,
+            timer: PhantomData,
+        }
+    }
+
+    /// Prefer an SPR PPS contract at the given target.
+    pub fn with_pps_target(mut self, voltage_mv: u32, current_ma: u32) -> Self {
+        self.pps_preferred = true;
+        self.target_pps_mv = voltage_mv;
+        self.target_pps_current_ma = current_ma;
+        self
+    }
+
+    /// Request at most `current_ma` from fixed PDOs instead of their highest current.
+    pub fn with_requested_current(mut self, current_ma: u32) -> Self {
+        self.requested_current_ma = Some(current_ma);
+        self
+    }
+
+    /// Called for every Alert message received from the source.
+    ///
+    /// Logs the reported alert types and passes the alert on through `ALERTS`.
+    pub fn on_alert(&mut self, alert: Alert) {
+        if alert.otp() {
+            warn!("Source alert: over-temperature");
+        }
+        if alert.ocp() {
+            warn!("Source alert: over-current");
+        }
+        if alert.ovp() {
+            warn!("Source alert: over-voltage");
+        }
+        if alert.operating_condition_change() {
+            info!("Source alert: operating condition change");
         }
+        if alert.source_input_change() {
+            info!("Source alert: source input change");
+        }
+        if alert.battery_status_change() {
+            info!("Source alert: battery status change");
+        }
+        if alert.extended() {
+            info!("Source alert: extended alert event");
+        }
+        ALERTS.signal(alert);
+    }
+
+    /// Pick up the targets set through `set_avs_target` and friends.
+    fn load_targets(&mut self) {
+        self.target_avs_mv = TARGET_AVS_MV.load(Ordering::Relaxed);
+        self.target_avs_current_ma = TARGET_AVS_CURRENT_MA.load(Ordering::Relaxed);
+        self.pps_preferred = PPS_PREFERRED.load(Ordering::Relaxed);
+        self.target_pps_mv = TARGET_PPS_MV.load(Ordering::Relaxed);
+        self.target_pps_current_ma = TARGET_PPS_CURRENT_MA.load(Ordering::Relaxed);
+        self.preferences = PREFERENCES.lock(|p| p.get());
+        self.min_epr_pdp_watts = MIN_EPR_PDP_WATTS.load(Ordering::Relaxed);
+        self.requested_current_ma = match REQUESTED_CURRENT_MA.load(Ordering::Relaxed) {
+            0 => None,
+            current_ma => Some(current_ma),
+        };
+        self.forced_pdo = match FORCED_PDO.load(Ordering::Relaxed) {
+            0 => None,
+            position => Some(position),
+        };
+    }
+
+    /// Never fall back below `voltage_mv`, and keep the load off on contracts below it.
+    pub fn with_min_voltage(mut self, voltage_mv: u32) -> Self {
+        self.min_voltage_mv = voltage_mv;
+        self
+    }
+
+    /// Wait `delay_ms` after EPR mode entry before the first EPR request.
+    pub fn with_post_epr_entry_delay(mut self, delay_ms: u64) -> Self {
+        self.post_epr_entry_delay_ms = delay_ms;
+        self
+    }
+
+    /// Request vSafe5V on attach, then renegotiate for the target.
+    ///
+    /// For slow chargers: critical circuitry is powered by the first contract, before the
+    /// capabilities are evaluated for the target.
+    pub fn with_safe_5v_first(mut self, enabled: bool) -> Self {
+        self.safe_5v_first = if enabled {
+            SafeFirst::Request
+        } else {
+            SafeFirst::Off
+        };
+        self
+    }
+
+    /// Set the No USB Suspend flag of requests, on by default.
+    ///
+    /// Clear it on USB-communicating devices that honour USB suspend, allowing the source
+    /// to expect the sink's draw to drop to the suspend current.
+    pub fn with_no_usb_suspend(mut self, no_usb_suspend: bool) -> Self {
+        self.no_usb_suspend = no_usb_suspend;
+        self
+    }
+
+    /// Set the USB Communications Capable flag of requests and sink capabilities, on by
+    /// default.
+    ///
+    /// Clear it on pure power sinks without USB data, so the source doesn't expect USB
+    /// communication.
+    pub fn with_usb_communications_capable(mut self, capable: bool) -> Self {
+        self.usb_communications_capable = capable;
+        self
+    }
+
+    /// Negotiate without ever connecting the load, to validate the selection on real chargers.
+    ///
+    /// With `request_safe_5v` the selection is only logged and vSafe5V is requested in its
+    /// place, so the source never raises VBUS either.
+    pub fn with_dry_run(mut self, enabled: bool, request_safe_5v: bool) -> Self {
+        self.dry_run = enabled;
+        self.dry_run_safe_5v = request_safe_5v;
+        self
+    }
+
+    /// Renegotiate when the source sends capabilities with more power mid-session, e.g.
+    /// after another of its ports was unplugged.
+    pub fn with_upgrade_on_caps_change(mut self, enabled: bool) -> Self {
+        self.upgrade_on_caps_change = enabled;
+        self
+    }
+
+    /// Always request the first fixed PDO (vSafe5V) at its highest current, without EPR
+    /// mode, PPS or AVS.
+    ///
+    /// A known-good fallback for minimal sources that misbehave with anything else.
+    pub fn with_accept_first(mut self, enabled: bool) -> Self {
+        self.accept_first = enabled;
+        self
+    }
+
+    /// Never request more than `max_current_ma`, on any PDO, e.g. for the rating of the
+    /// connector.
+    pub fn with_global_max_current(mut self, max_current_ma: u32) -> Self {
+        self.global_max_current_ma = max_current_ma;
+        self
+    }
+
+    /// Request a fixed EPR PDO matching the AVS target exactly, e.g. 28V, before AVS.
+    ///
+    /// A fixed supply holds its voltage more steadily than AVS. Disabled, fixed EPR PDOs are
+    /// only requested when no AVS PDO covers the target.
+    pub fn with_prefer_epr_fixed(mut self, enabled: bool) -> Self {
+        self.prefer_epr_fixed = enabled;
+        self
+    }
+
+    /// Step up to the target through the fixed voltages of `ladder`, e.g. `[5_000, 20_000]`
+    /// for 5V, 20V, then 28V EPR, holding each for `settle_ms` to limit inrush.
+    ///
+    /// Steps at or above the target, at or below the current contract, or not offered as
+    /// fixed PDOs are skipped. The ladder is walked once per attachment; a detach mid-ramp
+    /// drops the pending step along with the policy engine, the next attach starts over.
+    pub fn with_ramp(mut self, ladder: &'static [u32], settle_ms: u64) -> Self {
+        self.ramp = ladder;
+        self.ramp_settle_ms = settle_ms;
+        self
+    }
+
+    /// Call `handler` with each contract once the source confirmed it.
+    pub fn with_transition_handler(mut self, handler: &'a mut dyn PowerTransitionHandler) -> Self {
+        self.transition_handler = Some(handler);
+        self
+    }
+
+    /// Allow EPR mode, or stay in SPR mode even with EPR capable sources.
+    pub fn with_epr_enabled(mut self, epr_enabled: bool) -> Self {
+        self.epr_enabled = epr_enabled;
+        self
+    }
+
+    /// Only use EPR mode with sources whose EPR PDP is at least `pdp_watts`.
+    pub fn with_min_epr_pdp(mut self, pdp_watts: u32) -> Self {
+        self.min_epr_pdp_watts = pdp_watts;
+        self
+    }
+
+    /// Request the first entry of `preferences` the source can satisfy, in the Auto profile.
+    pub fn with_preferences(mut self, preferences: &'static [Preference]) -> Self {
+        self.preferences = preferences;
+        self
+    }
+
+    /// Data objects of the Sink_Capabilities message sent in response to Get_Sink_Cap.
+    ///
+    /// The policy engine at the pinned usbpd revision answers Get_Sink_Cap on its own and
+    /// has no `DevicePolicyManager` hook for it yet; this is what such a hook should return.
+    pub fn sink_capabilities(&self) -> heapless::Vec<u32, MAX_SINK_PDOS> {
+        SinkCapabilitiesBuilder::new(self.sink_capabilities)
+            .higher_capability(true)
+            .usb_communications_capable(self.usb_communications_capable)
+            .build()
+    }
+
+    /// Record the voltage a variable or battery supply settled at.
+    ///
+    /// A battery supply delivers its operating power, so the current follows the voltage.
+    fn settle_contract(&mut self, voltage_mv: u32) {
+        self.requested_contract.voltage_mv = voltage_mv;
+        if let Some(PowerSource::Battery(rdo)) = &self.last_request {
+            self.requested_contract.current_ma = battery_current_ma(rdo, voltage_mv);
+        }
+        publish_contract(self.requested_contract);
+    }
+
+    /// Check that the source delivers the voltage of the new contract.
+    ///
+    /// Returns whether VBUS is within tolerance of the range the contract allows.
+    async fn validate_vbus(&mut self) -> bool {
+        let (min_mv, max_mv) = self.requested_vbus_mv;
+        if max_mv == 0 {
+            return false;
+        }
+
+        T::after_millis(VBUS_SETTLE_MS).await;
+        let measured_mv = self.vbus.read_mv();
+        MEASURED_VBUS_MV.store(measured_mv, Ordering::Relaxed);
+        let tolerance_mv = self.vbus.tolerance_mv;
+        if (min_mv.saturating_sub(tolerance_mv)..=max_mv + tolerance_mv).contains(&measured_mv) {
+            info!("VBUS {}mV (expected {}-{}mV)", measured_mv, min_mv, max_mv);
+            if min_mv != max_mv {
+                self.settle_contract(measured_mv.clamp(min_mv, max_mv));
+            }
+            return true;
+        }
+
+        warn!(
+            "VBUS {}mV out of tolerance, expected {}-{}mV ± {}mV",
+            measured_mv, min_mv, max_mv, tolerance_mv
+        );
+        if self.vbus.hard_reset_on_mismatch {
+            HARD_RESET_REQUEST.signal(());
+        }
+        false
+    }
+}
+
+impl<T: SinkTimer> DevicePolicyManager for Device<'_, T> {
+    async fn inform(&mut self, source_capabilities: &SourceCapabilities) {
+        log_event(PdEvent::CapsReceived {
+            pdo_count: source_capabilities.pdos().len() as u8,
+            epr: source_capabilities.is_epr_capabilities(),
+        });
+        caps::publish(source_capabilities);
+        self.caps_informed = true;
+        if let Some(elapsed_ms) = stats::record_caps() {
+            info!("First Source_Capabilities {}ms after attach", elapsed_ms);
+        }
+
+        // EPR capabilities only follow a successful EPR_Mode handshake
+        if self.epr_entry_requested && source_capabilities.is_epr_capabilities() {
+            info!("EPR mode entered");
+            self.epr_entry_requested = false;
+            self.entered_epr_mode = true;
+        }
+
+        // The EPR PDP is only known once EPR capabilities arrive
+        if source_capabilities.is_epr_capabilities() {
+            let pdp_watts = source_max_power(source_capabilities).get::<watt>();
+            if pdp_watts < self.min_epr_pdp_watts {
+                warn!(
+                    "Source EPR PDP {}W below minimum {}W, staying in SPR mode",
+                    pdp_watts, self.min_epr_pdp_watts
+                );
+                self.epr_pdp_insufficient = true;
+            } else {
+                info!("Source EPR PDP {}W", pdp_watts);
+            }
+        }
+
+        if !self.epr_enabled && source_epr_capable(source_capabilities) {
+            info!("Source is EPR capable, EPR suppressed, staying in SPR mode");
+        }
+
+        // Another port of the source may have freed power since the contract
+        let offered_mw = source_max_power(source_capabilities).get::<milliwatt>();
+        if self.upgrade_on_caps_change
+            && self.requested_contract.is_active()
+            && offered_mw > self.request_caps_mw
+        {
+            info!(
+                "Source now offers {}mW, up from {}mW, upgrading the contract",
+                offered_mw, self.request_caps_mw
+            );
+            self.upgrade_pending = true;
+        }
+
+        // Print capabilities in detail when we receive them, otherwise as one dense line
+        #[cfg(feature = "pd-verbose")]
+        print_capabilities(source_capabilities);
+        #[cfg(not(feature = "pd-verbose"))]
+        info!(
+            "Source capabilities: {}",
+            CapsSummary::from(source_capabilities)
+        );
+    }
+
+    async fn get_event(&mut self, source_capabilities: &SourceCapabilities) -> Event {
+        // Capabilities with more power arrived without a request following them
+        if self.upgrade_pending {
+            self.upgrade_pending = false;
+            info!("Renegotiating for the upgraded capabilities");
+            return Event::RequestSourceCapabilities;
+        }
+
+        // Upgrade from the vSafe5V contract once the capabilities are known
+        if self.safe_5v_first == SafeFirst::Upgrade && self.caps_informed {
+            info!("vSafe5V contract in place, renegotiating for the target");
+            self.safe_5v_first = SafeFirst::Done;
+            return Event::RequestSourceCapabilities;
+        }
+
+        // Back in the ready state without EPR capabilities, the source rejected EPR mode
+        // entry or the handshake timed out
+        if self.epr_entry_requested {
+            warn!("EPR mode entry not confirmed, staying in SPR mode");
+            self.epr_entry_requested = false;
+            self.epr_entry_pending = false;
+            self.entered_epr_mode = false;
+            self.epr_entry_failed = true;
+        }
+
+        // Leave EPR mode again if the source turned out too weak for it
+        if self.entered_epr_mode && self.epr_pdp_insufficient {
+            info!("Leaving EPR mode, source PDP below minimum");
+            self.entered_epr_mode = false;
+            return Event::ExitEprMode;
+        }
+
+        // After initial SPR negotiation, enter EPR mode if source is EPR capable
+        if self.epr_enabled
+            && !self.accept_first
+            && !self.entered_epr_mode
+            && !self.epr_entry_failed
+            && !self.epr_pdp_insufficient
+            && !self.epr_exit_requested
+            && !self.pps_preferred
+            && !self.ramp_pending
+            && self.wants_epr()
+            && Profile::current() == Profile::Auto
+        {
+            if let Some(PowerDataObject::FixedSupply(fixed)) = source_capabilities.pdos().first() {
+                if fixed.epr_mode_capable() {
+                    info!("Source is EPR capable, entering EPR mode");
+                    self.epr_entry_requested = true;
+                    self.epr_entry_pending = true;
+                    return Event::EnterEprMode(Power::new::<watt>(self.operational_pdp_watts));
+                }
+            }
+        }
+
+        // Re-send an active PPS request before the source's tPPSTimeout expires
+        let active_pps = self.active_pps;
+        let pps_keep_alive = async {
+            match active_pps {
+                Some(_) => T::after_millis(PPS_KEEP_ALIVE_MS).await,
+                None => core::future::pending().await,
+            }
+        };
+
+        // Request the next step of the ramp once the intermediate contract settled
+        let ramp_settle_ms = self.ramp_pending.then_some(self.ramp_settle_ms);
+        let ramp_settled = async {
+            match ramp_settle_ms {
+                Some(settle_ms) => T::after_millis(settle_ms).await,
+                None => core::future::pending().await,
+            }
+        };
+
+        // A contract that holds for a while ends a series of hard resets
+        let contract_stable = async {
+            T::after_millis(STABLE_CONTRACT_MS).await;
+            HARD_RESETS.store(0, Ordering::Relaxed);
+            HARD_RESET_ORIGIN.reset();
+            core::future::pending::<()>().await
+        };
+
+        // Watch for VBUS collapsing under a contract above vSafe5V, and for its removal
+        // under any contract
+        let contract_mv = self.requested_contract.voltage_mv;
+        let threshold_mv = if contract_mv > 5_000 {
+            BROWNOUT_MV
+        } else {
+            VSAFE0V_MAX_MV
+        };
+        let vbus = &mut *self.vbus;
+        let brownout = async {
+            if contract_mv == 0 {
+                core::future::pending::<()>().await;
+            }
+            loop {
+                T::after_millis(BROWNOUT_POLL_MS).await;
+                let measured_mv = vbus.read_mv();
+                MEASURED_VBUS_MV.store(measured_mv, Ordering::Relaxed);
+                if measured_mv < threshold_mv {
+                    return measured_mv;
+                }
+            }
+        };
+
+        // Repeat a request the source was too busy for
+        let wait_retry = WAIT_RECEIVED.wait();
+
+        // Renegotiate when another profile is selected or on demand
+        let ready = ReadyGuard::new();
+        let outcome = select4(
+            select3(PROFILE_CHANGED.wait(), RENEGOTIATE.wait(), GOTO_MIN.wait()),
+            select(pps_keep_alive, ramp_settled),
+            select3(contract_stable, brownout, wait_retry),
+            select3(
+                EPR_EXIT_REQUEST.wait(),
+                ALERT_RECEIVED.wait(),
+                REJECT_RECEIVED.wait(),
+            ),
+        )
+        .await;
+        drop(ready);
+        match outcome {
+            Either4::First(Either3::Third(())) => self.goto_min(),
+            Either4::First(Either3::Second(())) => {
+                info!("Renegotiation requested");
+                self.load_targets();
+                Event::RequestSourceCapabilities
+            }
+            Either4::First(Either3::First(())) => {
+                let profile = Profile::current();
+                self.epr_exit_requested = false;
+                if self.entered_epr_mode && profile != Profile::Auto {
+                    // Fixed and PPS profiles are SPR only, the exit brings SPR capabilities
+                    info!("Profile changed to {}, leaving EPR mode", profile);
+                    self.entered_epr_mode = false;
+                    return Event::ExitEprMode;
+                }
+                info!("Profile changed to {}, renegotiating", profile);
+                Event::RequestSourceCapabilities
+            }
+            Either4::Third(Either3::First(())) => Event::None,
+            Either4::Third(Either3::Third(())) => self.retry_after_wait().await,
+            Either4::Third(Either3::Second(measured_mv)) if measured_mv < VSAFE0V_MAX_MV => {
+                // Detached by `ucpd_task`, possibly before the CC lines tell
+                info!("VBUS removed ({}mV)", measured_mv);
+                load::disable();
+                VBUS_REMOVED.signal(measured_mv);
+                Event::None
+            }
+            Either4::Third(Either3::Second(measured_mv)) => {
+                // Torn down by `ucpd_task`
+                warn!(
+                    "VBUS collapsed to {}mV under a {}mV contract",
+                    measured_mv, contract_mv
+                );
+                load::disable();
+                BROWNOUT.signal(measured_mv);
+                Event::None
+            }
+            Either4::Second(Either::Second(())) => {
+                debug!("Ramp step settled");
+                self.ramp_pending = false;
+                Event::RequestSourceCapabilities
+            }
+            Either4::Second(Either::First(())) => match active_pps {
+                Some(rdo) => {
+                    debug!("Refreshing PPS contract");
+                    Event::RequestPower(PowerSource::Pps(rdo))
+                }
+                None => Event::None,
+            },
+            Either4::Fourth(Either3::Third(true)) => Event::RequestSourceCapabilities,
+            Either4::Fourth(Either3::Third(false)) => {
+                // Every preference was rejected, start over with a hard reset
+                if SELECTED_PREFERENCE.load(Ordering::Relaxed) != NO_PREFERENCE {
+                    HARD_RESET_REQUEST.signal(());
+                }
+                Event::None
+            }
+            Either4::Fourth(Either3::Second(alert)) => {
+                self.on_alert(alert);
+                if alert.operating_condition_change() {
+                    // The source may offer less now, renegotiate against its current capabilities
+                    Event::RequestSourceCapabilities
+                } else {
+                    Event::None
+                }
+            }
+            Either4::Fourth(Either3::First(())) => {
+                if !self.entered_epr_mode {
+                    return Event::None;
+                }
+                info!("Leaving EPR mode on request");
+                self.entered_epr_mode = false;
+                self.epr_exit_requested = true;
+                Event::ExitEprMode
+            }
+        }
+    }
+
+    async fn request(&mut self, source_capabilities: &SourceCapabilities) -> PowerSource {
+        // Give the source time to settle after EPR mode entry
+        if self.epr_entry_pending && source_capabilities.is_epr_capabilities() {
+            self.epr_entry_pending = false;
+            debug!(
+                "Waiting {}ms after EPR mode entry",
+                self.post_epr_entry_delay_ms
+            );
+            T::after_millis(self.post_epr_entry_delay_ms).await;
+        }
+
+        // Evaluated against the newest capabilities, an upgrade is covered by this request
+        self.upgrade_pending = false;
+        SELECTED_PREFERENCE.store(NO_PREFERENCE, Ordering::Relaxed);
+        self.request_caps_mw = source_max_power(source_capabilities).get::<milliwatt>();
+
+        let power_source = if self.accept_first {
+            info!("Accept-first mode, requesting the first PDO");
+            safe_5v(source_capabilities)
+        } else if self.safe_5v_first == SafeFirst::Request {
+            // Power critical circuitry right away, the target follows in `get_event`
+            info!("Requesting vSafe5V first");
+            self.safe_5v_first = SafeFirst::Upgrade;
+            safe_5v(source_capabilities)
+        } else {
+            let max_power_mw = source_max_power(source_capabilities).get::<milliwatt>();
+            debug!("Source maximum power {}mW", max_power_mw);
+            let selected = match self
+                .forced_pdo
+                .and_then(|position| self.select_forced(source_capabilities, position))
+            {
+                Some(forced) => forced,
+                None => {
+                    let target = self.select_power_source(source_capabilities);
+                    let target_mv = contract_for(&target, source_capabilities).voltage_mv;
+                    self.select_ramp_step(source_capabilities, target_mv)
+                        .unwrap_or(target)
+                }
+            };
+            let power_source = limit_to_source_power(selected, max_power_mw);
+            let power_source = derate(power_source, temp::derating_percent());
+            match cable::max_current_ma() {
+                Some(max_current_ma) => limit_to_cable_current(power_source, max_current_ma),
+                None => power_source,
+            }
+        };
+        let power_source = if self.dry_run && self.dry_run_safe_5v {
+            let selected = contract_for(&power_source, source_capabilities);
+            info!(
+                "Dry run: selected {}mV @ {}mA (PDO {}), requesting vSafe5V",
+                selected.voltage_mv, selected.current_ma, selected.pdo_position
+            );
+            safe_5v(source_capabilities)
+        } else {
+            power_source
+        };
+        let power_source = match cap_current(power_source, self.global_max_current_ma) {
+            Some(limited) => {
+                info!(
+                    "Limiting the request to the global maximum of {}mA",
+                    self.global_max_current_ma
+                );
+                limited
+            }
+            None => power_source,
+        };
+        let power_source = self.with_usb_flags(checked_position(power_source, source_capabilities));
+        self.requested_contract = contract_for(&power_source, source_capabilities);
+        self.requested_vbus_mv =
+            vbus_range_mv(&power_source, source_capabilities, &self.requested_contract);
+        log_event(PdEvent::Requested(self.requested_contract));
+        self.last_request = Some(power_source);
+        self.wait_retries = 0;
+        power_source
+    }
+
+    async fn transition_power(&mut self, accepted: &PowerSource) {
+        log_event(PdEvent::PsRdy);
+        log_event(PdEvent::Accepted(self.requested_contract));
+        WAIT_RECEIVED.reset();
+        self.wait_retries = 0;
+        if self.power_reduced {
+            info!("Source restored full power after GotoMin");
+            self.power_reduced = false;
+        }
+        self.active_pps = match accepted {
+            PowerSource::Pps(rdo) => Some(*rdo),
+            _ => None,
+        };
+        publish_contract(self.requested_contract);
+        if let Some(handler) = self.transition_handler.as_mut() {
+            handler.on_transition(&self.requested_contract);
+        }
+        print_contract(
+            self.requested_contract.voltage_mv,
+            self.requested_contract.current_ma,
+            self.requested_contract.pdo_position,
+            self.requested_contract.is_epr,
+        );
+        CONTRACT_ESTABLISHED.signal(());
+        stats::record_contract(&self.requested_contract);
+
+        // Identify the source once per session, the driver sends the queries once the
+        // policy engine is back in PE_SNK_Ready
+        if !self.source_info_requested {
+            self.source_info_requested = true;
+            let _ = DRIVER_REQUEST.try_send(DriverRequest::SourceCapExtended);
+            let _ = DRIVER_REQUEST.try_send(DriverRequest::DiscoverCableIdentity);
+        }
+
+        // Only connect the load once the new voltage is confirmed
+        if self.requested_contract.voltage_mv < self.min_voltage_mv {
+            error!(
+                "Contract at {}mV below the {}mV minimum, keeping the load off",
+                self.requested_contract.voltage_mv, self.min_voltage_mv
+            );
+            set_pd_state(PdState::Fault);
+            load::disable();
+        } else if self.validate_vbus().await {
+            set_pd_state(PdState::Contract);
+            if self.dry_run {
+                // Not saved either, a dry run contract is no session to return to
+                info!("Dry run: contract in place, keeping the load off");
+                load::disable();
+                return;
+            }
+            load::enable();
+            persist::save(SavedContract {
+                profile: Profile::current(),
+                pdo_position: self.requested_contract.pdo_position,
+                voltage_mv: self.requested_contract.voltage_mv,
+            });
+        } else {
+            set_pd_state(PdState::Fault);
+            load::disable();
+        }
+    }
+}
+
+impl<T: SinkTimer> Device<'_, T> {
+    /// Whether the source is EPR capable and EPR mode is enabled.
+    fn epr_capable(&self, source_capabilities: &SourceCapabilities) -> bool {
+        self.epr_enabled && source_epr_capable(source_capabilities)
+    }
+
+    /// Whether these are EPR capabilities that may be used.
+    fn epr_caps(&self, source_capabilities: &SourceCapabilities) -> bool {
+        self.epr_enabled && source_capabilities.is_epr_capabilities()
+    }
+
+    /// Pick the power source to request from the offered capabilities.
+    fn select_power_source(&mut self, source_capabilities: &SourceCapabilities) -> PowerSource {
+        // Go straight back to the voltage of the last session, when it is a fixed one
+        if let Some(restored) = self.restored.take() {
+            if restored.profile == Profile::current() {
+                if let Some(power_source) =
+                    self.select_fixed(source_capabilities, restored.voltage_mv)
+                {
+                    info!("Requesting {}mV of the last session", restored.voltage_mv);
+                    return with_epr_flag(power_source, self.epr_capable(source_capabilities));
+                }
+            }
+        }
+
+        match Profile::current() {
+            Profile::Auto => {
+                let power_source = if self.preferences.is_empty() {
+                    self.select_auto(source_capabilities)
+                } else {
+                    self.select_preferred(source_capabilities)
+                        .unwrap_or_else(|| {
+                            warn!("No preference satisfied, falling back");
+                            self.fallback(source_capabilities)
+                        })
+                };
+                flag_capability_mismatch(
+                    power_source,
+                    source_capabilities,
+                    self.operational_pdp_watts,
+                    self.epr_enabled,
+                )
+            }
+            Profile::Pps => self
+                .select_pps(
+                    source_capabilities,
+                    PPS_PROFILE_MV,
+                    DEFAULT_TARGET_PPS_CURRENT_MA,
+                )
+                .unwrap_or_else(|| {
+                    warn!("No PPS PDO found, falling back");
+                    self.fallback(source_capabilities)
+                }),
+            profile => {
+                let voltage_mv = profile.fixed_voltage_mv().unwrap();
+                match PowerSource::new_fixed(
+                    CurrentRequest::Highest,
+                    VoltageRequest::Specific(ElectricPotential::new::<millivolt>(voltage_mv)),
+                    source_capabilities,
+                ) {
+                    Ok(ps) => {
+                        info!(
+                            "Requesting fixed {}mV (PDO {})",
+                            voltage_mv,
+                            ps.object_position()
+                        );
+                        self.limit_fixed_current(ps)
+                    }
+                    Err(_) => self
+                        .select_variable(source_capabilities, voltage_mv)
+                        .unwrap_or_else(|| {
+                            warn!("No fixed {}mV PDO found, falling back", voltage_mv);
+                            self.fallback(source_capabilities)
+                        }),
+                }
+            }
+        }
+    }
+
+    /// Request used when nothing better is offered.
+    ///
+    /// This is vSafe5V, unless it is below the minimum voltage. Then the lowest fixed voltage
+    /// at or above the minimum is requested, or vSafe5V if there is none, which keeps the load
+    /// off. Both are flagged as a capability mismatch.
+    fn fallback(&self, source_capabilities: &SourceCapabilities) -> PowerSource {
+        if self.min_voltage_mv <= 5_000 {
+            return safe_5v(source_capabilities);
+        }
+
+        let lowest_sufficient = offered_spr_pdos(source_capabilities)
+            .filter_map(|(_, pdo)| match pdo {
+                PowerDataObject::FixedSupply(fixed) => Some(fixed.raw_voltage() as u32 * 50),
+                _ => None,
+            })
+            .filter(|&voltage_mv| voltage_mv >= self.min_voltage_mv)
+            .min();
+        if let Some(power_source) = lowest_sufficient
+            .and_then(|voltage_mv| self.select_fixed(source_capabilities, voltage_mv))
+        {
+            warn!(
+                "Falling back to PDO {}, the lowest at or above {}mV",
+                power_source.object_position(),
+                self.min_voltage_mv
+            );
+            return with_capability_mismatch(power_source);
+        }
+
+        error!(
+            "No PDO at or above the {}mV minimum, requesting 5V with the load off",
+            self.min_voltage_mv
+        );
+        with_capability_mismatch(safe_5v(source_capabilities))
+    }
+
+    /// Apply the USB flags of this sink to a request, whichever selection path built it.
+    fn with_usb_flags(&self, power_source: PowerSource) -> PowerSource {
+        let usb = self.usb_communications_capable;
+        let no_suspend = self.no_usb_suspend;
+        match power_source {
+            PowerSource::FixedVariableSupply(rdo) => PowerSource::FixedVariableSupply(
+                rdo.with_usb_communications_capable(usb)
+                    .with_no_usb_suspend(no_suspend),
+            ),
+            PowerSource::Battery(rdo) => PowerSource::Battery(
+                rdo.with_usb_communications_capable(usb)
+                    .with_no_usb_suspend(no_suspend),
+            ),
+            PowerSource::Pps(rdo) => PowerSource::Pps(
+                rdo.with_usb_communications_capable(usb)
+                    .with_no_usb_suspend(no_suspend),
+            ),
+            // Same bits in fixed and AVS RDOs
+            PowerSource::EprRequest { rdo, pdo } => PowerSource::EprRequest {
+                rdo: Avs(rdo)
+                    .with_usb_communications_capable(usb)
+                    .with_no_usb_suspend(no_suspend)
+                    .0,
+                pdo,
+            },
+            power_source => power_source,
+        }
+    }
+
+    /// Reduce to the minimum on GotoMin from the source.
+    ///
+    /// The minimum of this sink is its own supply with the load disconnected. The source
+    /// restores full power with a new contract, which reconnects the load.
+    fn goto_min(&mut self) -> Event {
+        warn!(
+            "GotoMin from source, disconnecting the load from the {}mV contract",
+            self.requested_contract.voltage_mv
+        );
+        self.power_reduced = true;
+        load::disable();
+        log_event(PdEvent::GotoMin);
+        Event::None
+    }
+
+    /// Repeat the last request after the source answered it with Wait, after tSinkRequest.
+    ///
+    /// Gives up after `MAX_WAIT_RETRIES`, leaving the current contract in place. Without a
+    /// contract the policy engine waits for new capabilities instead, and the negotiation
+    /// watchdog tears the session down if none arrive.
+    async fn retry_after_wait(&mut self) -> Event {
+        let Some(power_source) = self.last_request else {
+            return Event::None;
+        };
+        if self.wait_retries >= MAX_WAIT_RETRIES {
+            warn!(
+                "Source still busy after {} retries, giving up on the request",
+                MAX_WAIT_RETRIES
+            );
+            return Event::None;
+        }
+        self.wait_retries += 1;
+        T::after_millis(SINK_REQUEST_MS).await;
+        info!(
+            "Source sent Wait, repeating request for PDO {} ({}/{})",
+            power_source.object_position(),
+            self.wait_retries,
+            MAX_WAIT_RETRIES
+        );
+        Event::RequestPower(power_source)
+    }
+
+    /// Request a fixed PDO at `voltage_mv`, at the requested current.
+    fn select_fixed(
+        &self,
+        source_capabilities: &SourceCapabilities,
+        voltage_mv: u32,
+    ) -> Option<PowerSource> {
+        PowerSource::new_fixed(
+            CurrentRequest::Highest,
+            VoltageRequest::Specific(ElectricPotential::new::<millivolt>(voltage_mv)),
+            source_capabilities,
+        )
+        .ok()
+        .map(|power_source| self.limit_fixed_current(power_source))
+    }
+
+    /// Whether the policy has any use for EPR mode.
+    fn wants_epr(&self) -> bool {
+        self.preferences.is_empty()
+            || self
+                .preferences
+                .iter()
+                .any(|preference| matches!(preference, Preference::Avs { .. }))
+    }
+
+    /// Request the first entry of the preference list the source can satisfy.
+    ///
+    /// Entries before a preference the source rejected on this attachment are skipped.
+    fn select_preferred(&self, source_capabilities: &SourceCapabilities) -> Option<PowerSource> {
+        let source_epr_capable = self.epr_capable(source_capabilities);
+        let start = PREFERENCE_START.load(Ordering::Relaxed) as usize;
+        for (index, preference) in self.preferences.iter().enumerate().skip(start) {
+            let power_source = match *preference {
+                Preference::Avs { voltage_mv } if self.epr_caps(source_capabilities) => {
+                    self.select_avs(source_capabilities, voltage_mv)
+                }
+                Preference::Avs { .. } => None,
+                Preference::Pps { voltage_mv } if pps_covers(source_capabilities, voltage_mv) => {
+                    self.select_pps(source_capabilities, voltage_mv, self.target_pps_current_ma)
+                }
+                Preference::Pps { .. } => None,
+                Preference::Fixed { voltage_mv } => self
+                    .select_fixed(source_capabilities, voltage_mv)
+                    .map(|power_source| with_epr_flag(power_source, source_epr_capable)),
+                Preference::Variable { voltage_mv } => {
+                    self.select_variable(source_capabilities, voltage_mv)
+                }
+                Preference::Battery {
+                    voltage_mv,
+                    power_mw,
+                } => select_battery(source_capabilities, voltage_mv, power_mw),
+                Preference::LowestSufficient { power_mw } => self
+                    .select_lowest_sufficient(source_capabilities, power_mw)
+                    .map(|power_source| with_epr_flag(power_source, source_epr_capable)),
+            };
+
+            if let Some(power_source) = power_source {
+                info!(
+                    "Preference {} satisfied by PDO {}",
+                    preference,
+                    power_source.object_position()
+                );
+                SELECTED_PREFERENCE.store(index as u8, Ordering::Relaxed);
+                return Some(power_source);
+            }
+            debug!("Preference {} not offered", preference);
+        }
+        None
+    }
+
+    /// Request the lowest fixed voltage whose PDO provides at least `power_mw`.
+    ///
+    /// Falls back to the fixed PDO with the most power, with Capability Mismatch set.
+    fn select_lowest_sufficient(
+        &self,
+        source_capabilities: &SourceCapabilities,
+        power_mw: u32,
+    ) -> Option<PowerSource> {
+        let available_mw = |fixed: &FixedSupply| {
+            let voltage_mv = fixed.raw_voltage() as u32 * 50; // 50mV units
+            let current_ma = fixed.raw_max_current() as u32 * 10; // 10mA units
+            voltage_mv * current_ma / 1000
+        };
+
+        // Sufficient PDOs first, the lowest voltage among them
+        let (position, fixed) = best_fixed_pdo(source_capabilities, |fixed| {
+            (
+                available_mw(fixed) >= power_mw,
+                Reverse(fixed.raw_voltage()),
+            )
+        })?;
+        if available_mw(fixed) >= power_mw {
+            return Some(self.select_fixed_position(position, fixed, false));
+        }
+
+        let (position, fixed) = best_fixed_pdo(source_capabilities, available_mw)?;
+        warn!(
+            "No fixed PDO provides {}mW, requesting {}mV with {}mW and capability mismatch",
+            power_mw,
+            fixed.raw_voltage() as u32 * 50,
+            available_mw(fixed)
+        );
+        Some(with_capability_mismatch(
+            self.select_fixed_position(position, fixed, false),
+        ))
+    }
+
+    /// Request the fixed PDO at `position` at the requested current, its maximum if not set.
+    fn select_fixed_position(
+        &self,
+        position: u8,
+        fixed: &FixedSupply,
+        epr_mode_capable: bool,
+    ) -> PowerSource {
+        let max_current = fixed.raw_max_current();
+        let rdo = FixedVariableSupply(0)
+            .with_object_position(position)
+            .with_epr_mode_capable(epr_mode_capable)
+            .with_raw_operating_current(max_current)
+            .with_raw_max_operating_current(max_current);
+        self.limit_fixed_current(PowerSource::FixedVariableSupply(rdo))
+    }
+
+    /// Request the PDO at `position` at its maximum, bypassing the policy.
+    ///
+    /// Returns `None` if the source doesn't offer the position.
+    fn select_forced(
+        &self,
+        source_capabilities: &SourceCapabilities,
+        position: u8,
+    ) -> Option<PowerSource> {
+        let Some((_, pdo)) = offered_spr_pdos(source_capabilities)
+            .chain(offered_epr_pdos(source_capabilities))
+            .find(|&(offered, _)| offered == position)
+        else {
+            warn!("Forced PDO {} not offered, using the policy", position);
+            return None;
+        };
+
+        let power_source =
+            self.forced_request(position, pdo, self.epr_capable(source_capabilities))?;
+        info!("Forcing PDO {} at its maximum", position);
+        Some(power_source)
+    }
+
+    /// Request for the forced PDO `pdo` at `position`.
+    ///
+    /// Returns `None` for PDOs of unknown type.
+    fn forced_request(
+        &self,
+        position: u8,
+        pdo: &PowerDataObject,
+        epr_capable: bool,
+    ) -> Option<PowerSource> {
+        // EPR PDOs, and every PDO while in EPR mode, take an EPR_Request
+        let epr_request = position >= 8 || self.entered_epr_mode;
+        let power_source = match pdo {
+            PowerDataObject::FixedSupply(fixed) => {
+                let max_current = fixed.raw_max_current();
+                let rdo = FixedVariableSupply(0)
+                    .with_object_position(position)
+                    .with_epr_mode_capable(epr_capable || epr_request)
+                    .with_raw_operating_current(max_current)
+                    .with_raw_max_operating_current(max_current);
+                if epr_request {
+                    PowerSource::EprRequest {
+                        rdo: rdo.0,
+                        pdo: *pdo,
+                    }
+                } else {
+                    PowerSource::FixedVariableSupply(rdo)
+                }
+            }
+            PowerDataObject::VariableSupply(variable) => {
+                let max_current = variable.raw_max_current();
+                PowerSource::FixedVariableSupply(
+                    FixedVariableSupply(0)
+                        .with_object_position(position)
+                        .with_raw_operating_current(max_current)
+                        .with_raw_max_operating_current(max_current),
+                )
+            }
+            PowerDataObject::Battery(battery) => {
+                let max_power = battery.raw_max_power();
+                PowerSource::Battery(
+                    Battery(0)
+                        .with_object_position(position)
+                        .with_raw_operating_power(max_power)
+                        .with_raw_max_operating_power(max_power),
+                )
+            }
+            PowerDataObject::Augmented(Augmented::Spr(pps)) => {
+                let voltage_mv = self.target_pps_mv.clamp(
+                    pps.raw_min_voltage() as u32 * 100,
+                    pps.raw_max_voltage() as u32 * 100,
+                );
+                PowerSource::Pps(
+                    Pps(0)
+                        .with_object_position(position)
+                        .with_raw_output_voltage((voltage_mv / 20) as u16)
+                        .with_raw_operating_current(pps.raw_max_current()),
+                )
+            }
+            PowerDataObject::Augmented(Augmented::Epr(avs)) => {
+                let voltage_mv = self.target_avs_mv.clamp(
+                    avs.raw_min_voltage() as u32 * 100,
+                    avs.raw_max_voltage() as u32 * 100,
+                );
+                let pdp_mw = avs.raw_pd_power() as u32 * 1000;
+                let rdo = Avs(0)
+                    .with_object_position(position)
+                    .with_epr_mode_capable(true)
+                    .with_raw_output_voltage(encode_avs_voltage(voltage_mv))
+                    .with_raw_operating_current(avs_max_current_raw(pdp_mw, voltage_mv));
+                PowerSource::EprRequest {
+                    rdo: rdo.0,
+                    pdo: *pdo,
+                }
+            }
+            _ => {
+                warn!(
+                    "Forced PDO {} has an unknown type, using the policy",
+                    position
+                );
+                return None;
+            }
+        };
+        Some(power_source)
+    }
+
+    /// Apply the requested current to a fixed request made for the highest current.
+    ///
+    /// A requested current above the PDO's maximum is clamped to the maximum.
+    fn limit_fixed_current(&self, power_source: PowerSource) -> PowerSource {
+        let Some(current_ma) = self.requested_current_ma else {
+            return power_source;
+        };
+        let PowerSource::FixedVariableSupply(rdo) = power_source else {
+            return power_source;
+        };
+
+        let max_current = rdo.raw_max_operating_current();
+        let mut current = (current_ma / 10) as u16; // 10mA units
+        if current > max_current {
+            warn!(
+                "Requested {}mA exceeds PDO maximum of {}mA, clamping",
+                current_ma,
+                max_current as u32 * 10
+            );
+            current = max_current;
+        }
+        PowerSource::FixedVariableSupply(
+            rdo.with_raw_operating_current(current)
+                .with_raw_max_operating_current(current),
+        )
+    }
+
+    /// Request an SPR PPS PDO at `target_mv`.
+    ///
+    /// Prefers a PPS PDO whose range covers the target. Otherwise the target is clamped into
+    /// the range of the first PPS PDO. Returns `None` if the source offers no PPS.
+    fn select_pps(
+        &self,
+        source_capabilities: &SourceCapabilities,
+        target_mv: u32,
+        target_current_ma: u32,
+    ) -> Option<PowerSource> {
+        let mut covering = None;
+        let mut fallback = None;
+        for (position, pdo) in offered_spr_pdos(source_capabilities) {
+            if let PowerDataObject::Augmented(Augmented::Spr(pps)) = pdo {
+                let min_mv = pps.raw_min_voltage() as u32 * 100;
+                let max_mv = pps.raw_max_voltage() as u32 * 100;
+                if min_mv <= target_mv && target_mv <= max_mv {
+                    covering = Some((position, pps));
+                    break;
+                }
+                if fallback.is_none() {
+                    fallback = Some((position, pps));
+                }
+            }
+        }
+        let (position, pps) = covering.or(fallback)?;
+
+        let min_mv = pps.raw_min_voltage() as u32 * 100; // 100mV units
+        let max_mv = pps.raw_max_voltage() as u32 * 100;
+        let voltage_mv = target_mv.clamp(min_mv, max_mv);
+        if voltage_mv != target_mv {
+            warn!(
+                "PPS target {}mV outside PDO {} range {}-{}mV, clamping to {}mV",
+                target_mv, position, min_mv, max_mv, voltage_mv
+            );
+        }
+
+        let max_current_ma = pps.raw_max_current() as u32 * 50; // 50mA units
+        let current_ma = target_current_ma.min(max_current_ma);
+
+        info!(
+            "Requesting PPS {}mV @ {}mA at position {}",
+            voltage_mv, current_ma, position
+        );
+
+        let rdo = Pps(0)
+            .with_object_position(position)
+            .with_raw_output_voltage((voltage_mv / 20) as u16) // 20mV units
+            .with_raw_operating_current((current_ma / 50) as u16); // 50mA units
+
+        Some(PowerSource::Pps(rdo))
+    }
+
+    /// Request a variable supply PDO whose range covers `target_mv`.
+    ///
+    /// Draws the requested current if set, otherwise the PDO's maximum, clamped to the
+    /// maximum. Returns `None` if no variable PDO covers the target.
+    fn select_variable(
+        &self,
+        source_capabilities: &SourceCapabilities,
+        target_mv: u32,
+    ) -> Option<PowerSource> {
+        let (position, variable) =
+            offered_spr_pdos(source_capabilities).find_map(|(position, pdo)| match pdo {
+                PowerDataObject::VariableSupply(v)
+                    if v.raw_min_voltage() as u32 * 50 <= target_mv
+                        && target_mv <= v.raw_max_voltage() as u32 * 50 =>
+                {
+                    Some((position, *v))
+                }
+                _ => None,
+            })?;
+
+        let max_current_ma = variable.raw_max_current() as u32 * 10; // 10mA units
+        let current_ma = match self.requested_current_ma {
+            Some(current_ma) if current_ma > max_current_ma => {
+                warn!(
+                    "Requested {}mA exceeds variable PDO maximum of {}mA, clamping",
+                    current_ma, max_current_ma
+                );
+                max_current_ma
+            }
+            Some(current_ma) => current_ma,
+            None => max_current_ma,
+        };
+
+        info!(
+            "Requesting variable PDO {} ({}-{}mV) with {}mA",
+            position,
+            variable.raw_min_voltage() as u32 * 50,
+            variable.raw_max_voltage() as u32 * 50,
+            current_ma
+        );
+
+        let current = (current_ma / 10) as u16;
+        let rdo = FixedVariableSupply(0)
+            .with_object_position(position)
+            .with_raw_operating_current(current)
+            .with_raw_max_operating_current(current);
+        Some(PowerSource::FixedVariableSupply(rdo))
+    }
+
+    /// Request an EPR AVS PDO at `target_mv`, at up to the AVS target current.
+    ///
+    /// Returns `None` if no AVS PDO of the EPR capabilities covers the target.
+    fn select_avs(
+        &self,
+        source_capabilities: &SourceCapabilities,
+        target_mv: u32,
+    ) -> Option<PowerSource> {
+        for (position, pdo) in offered_epr_pdos(source_capabilities) {
+            if let PowerDataObject::Augmented(Augmented::Epr(avs)) = pdo {
+                let min_mv = avs.raw_min_voltage() as u32 * 100;
+                let max_mv = avs.raw_max_voltage() as u32 * 100;
+
+                // Check if this AVS PDO supports our target voltage
+                if min_mv <= target_mv && target_mv <= max_mv {
+                    let pdp_mw = avs.raw_pd_power() as u32 * 1000;
+                    let max_current_raw = avs_max_current_raw(pdp_mw, target_mv);
+                    let target_current_raw = (self.target_avs_current_ma / 50) as u16;
+
+                    let current = if target_current_raw > max_current_raw {
+                        warn!(
+                            "Source max {}mA < target {}mA at {}mV, using source max",
+                            max_current_raw as u32 * 50,
+                            self.target_avs_current_ma,
+                            target_mv
+                        );
+                        max_current_raw
+                    } else {
+                        target_current_raw
+                    };
+
+                    let voltage_raw = encode_avs_voltage(target_mv);
+
+                    info!(
+                        "Requesting {}mV AVS at position {} with {}mA (voltage_raw={})",
+                        target_mv,
+                        position,
+                        current as u32 * 50,
+                        voltage_raw
+                    );
+
+                    let rdo = Avs(0)
+                        .with_object_position(position)
+                        .with_epr_mode_capable(true)
+                        .with_raw_output_voltage(voltage_raw)
+                        .with_raw_operating_current(current);
+
+                    return Some(PowerSource::EprRequest {
+                        rdo: rdo.0,
+                        pdo: *pdo,
+                    });
+                }
+            }
+        }
+        None
+    }
+
+    /// Next step of the ramp ladder towards `target_mv`, requested instead of the target.
+    ///
+    /// Returns `None` once the remaining steps reach the target, leaving them for a higher
+    /// target such as one in EPR mode.
+    fn select_ramp_step(
+        &mut self,
+        source_capabilities: &SourceCapabilities,
+        target_mv: u32,
+    ) -> Option<PowerSource> {
+        let contract_mv = self.requested_contract.voltage_mv;
+        while let Some(&step_mv) = self.ramp.get(self.ramp_step) {
+            if step_mv >= target_mv {
+                return None;
+            }
+            self.ramp_step += 1;
+            if step_mv <= contract_mv {
+                continue;
+            }
+
+            let power_source = self
+                .select_fixed(source_capabilities, step_mv)
+                .map(|power_source| {
+                    with_epr_flag(power_source, self.epr_capable(source_capabilities))
+                })
+                .or_else(|| self.select_epr_fixed(source_capabilities, step_mv));
+            match power_source {
+                Some(power_source) => {
+                    info!("Ramping through {}mV towards {}mV", step_mv, target_mv);
+                    self.ramp_pending = true;
+                    return Some(power_source);
+                }
+                None => debug!("Ramp step {}mV not offered, skipping", step_mv),
+            }
+        }
+        None
+    }
+
+    /// Request a fixed EPR PDO of exactly `target_mv` at its maximum current.
+    ///
+    /// Returns `None` if no fixed PDO of the EPR capabilities has that voltage.
+    fn select_epr_fixed(
+        &self,
+        source_capabilities: &SourceCapabilities,
+        target_mv: u32,
+    ) -> Option<PowerSource> {
+        let (position, pdo, fixed) =
+            offered_epr_pdos(source_capabilities).find_map(|(position, pdo)| match pdo {
+                PowerDataObject::FixedSupply(fixed)
+                    if fixed.raw_voltage() as u32 * 50 == target_mv =>
+                {
+                    Some((position, pdo, fixed))
+                }
+                _ => None,
+            })?;
+        let max_current = fixed.raw_max_current();
+        info!(
+            "Requesting fixed EPR PDO {} ({}mV) with {}mA",
+            position,
+            target_mv,
+            max_current as u32 * 10
+        );
+        let rdo = FixedVariableSupply(0)
+            .with_object_position(position)
+            .with_epr_mode_capable(true)
+            .with_raw_operating_current(max_current)
+            .with_raw_max_operating_current(max_current);
+        Some(PowerSource::EprRequest {
+            rdo: rdo.0,
+            pdo: *pdo,
+        })
+    }
+
+    /// Default policy: EPR fixed or AVS at the target voltage, otherwise the highest SPR
+    /// voltage.
+    fn select_auto(&mut self, source_capabilities: &SourceCapabilities) -> PowerSource {
+        if self.pps_preferred {
+            if let Some(power_source) = self.select_pps(
+                source_capabilities,
+                self.target_pps_mv,
+                self.target_pps_current_ma,
+            ) {
+                return power_source;
+            }
+            warn!("No PPS PDO found, using default policy");
+        }
+
+        let source_epr_capable = self.epr_capable(source_capabilities);
+
+        // If we have EPR capabilities, look for AVS PDO that supports our target voltage
+        if self.epr_caps(source_capabilities) && !self.epr_pdp_insufficient {
+            let target_mv = self.target_avs_mv;
+            let power_source = if self.prefer_epr_fixed {
+                self.select_epr_fixed(source_capabilities, target_mv)
+                    .or_else(|| self.select_avs(source_capabilities, target_mv))
+            } else {
+                self.select_avs(source_capabilities, target_mv)
+                    .or_else(|| self.select_epr_fixed(source_capabilities, target_mv))
+            };
+            if let Some(power_source) = power_source {
+                return power_source;
+            }
+
+            warn!(
+                "No EPR PDO supporting {}mV found, falling back to SPR",
+                target_mv
+            );
+        }
+
+        // For SPR request: manually construct RDO with epr_mode_capable bit if source supports EPR
+        // This is required before EPR mode entry - the source checks this bit
+        // Highest SPR fixed voltage, unconstrained power winning ties
+        match best_fixed_pdo(source_capabilities, |fixed| fixed.raw_voltage()) {
+            Some((position, fixed)) if source_epr_capable => {
+                info!(
+                    "Requesting SPR PDO {} ({}mV) with EPR capable flag",
+                    position,
+                    fixed.raw_voltage() as u32 * 50
+                );
+                // Important for EPR mode entry!
+                self.select_fixed_position(position, fixed, true)
+            }
+            Some((position, fixed)) => {
+                info!("Requesting highest SPR voltage (PDO {})", position);
+                self.select_fixed_position(position, fixed, false)
+            }
+            None => {
+                warn!("No suitable PDO found, falling back");
+                self.fallback(source_capabilities)
+            }
+        }
+    }
+}
+
+/// Request a battery supply PDO whose range covers `target_mv`, drawing `power_mw`.
+///
+/// Power above the PDO's maximum is clamped. Returns `None` if no battery PDO covers the target.
+fn select_battery(
+    source_capabilities: &SourceCapabilities,
+    target_mv: u32,
+    power_mw: u32,
+) -> Option<PowerSource> {
+    let (position, battery) =
+        offered_spr_pdos(source_capabilities).find_map(|(position, pdo)| match pdo {
+            PowerDataObject::Battery(b)
+                if b.raw_min_voltage() as u32 * 50 <= target_mv
+                    && target_mv <= b.raw_max_voltage() as u32 * 50 =>
+            {
+                Some((position, *b))
+            }
+            _ => None,
+        })?;
+
+    let max_power_mw = battery.raw_max_power() as u32 * 250; // 250mW units
+    let power_mw = if power_mw > max_power_mw {
+        warn!(
+            "Requested {}mW exceeds battery PDO maximum of {}mW, clamping",
+            power_mw, max_power_mw
+        );
+        max_power_mw
+    } else {
+        power_mw
+    };
+
+    info!(
+        "Requesting battery PDO {} ({}-{}mV) with {}mW",
+        position,
+        battery.raw_min_voltage() as u32 * 50,
+        battery.raw_max_voltage() as u32 * 50,
+        power_mw
+    );
+
+    let power = (power_mw / 250) as u16;
+    let rdo = Battery(0)
+        .with_object_position(position)
+        .with_raw_operating_power(power)
+        .with_raw_max_operating_power(power);
+    Some(PowerSource::Battery(rdo))
+}
+
+/// Set the EPR Mode Capable bit of a fixed request, keeping EPR entry possible from it.
+fn with_epr_flag(power_source: PowerSource, epr_mode_capable: bool) -> PowerSource {
+    match power_source {
+        PowerSource::FixedVariableSupply(rdo) => {
+            PowerSource::FixedVariableSupply(rdo.with_epr_mode_capable(epr_mode_capable))
+        }
+        power_source => power_source,
+    }
+}
+
+/// The fixed SPR PDO ranked highest by `key`, unconstrained power winning ties.
+///
+/// An unconstrained source doesn't share its power budget with other ports or run off a
+/// battery, so it holds high power more reliably.
+fn best_fixed_pdo<K: Ord>(
+    source_capabilities: &SourceCapabilities,
+    key: impl Fn(&FixedSupply) -> K,
+) -> Option<(u8, &FixedSupply)> {
+    offered_spr_pdos(source_capabilities)
+        .filter_map(|(position, pdo)| match pdo {
+            PowerDataObject::FixedSupply(fixed) => Some((position, fixed)),
+            _ => None,
+        })
+        .max_by_key(|&(_, fixed)| (key(fixed), fixed.unconstrained_power()))
+}
+
+/// SPR PDOs the source offers, without separators and zero padding.
+///
+/// All selection goes through this, so a null PDO or position 0 is never requested.
+fn offered_spr_pdos(
+    source_capabilities: &SourceCapabilities,
+) -> impl Iterator<Item = (u8, &PowerDataObject)> {
+    source_capabilities
+        .spr_pdos()
+        .filter(|(position, pdo)| *position != 0 && !pdo.is_zero_padding())
+}
+
+/// EPR PDOs the source offers, without separators and zero padding.
+fn offered_epr_pdos(
+    source_capabilities: &SourceCapabilities,
+) -> impl Iterator<Item = (u8, &PowerDataObject)> {
+    source_capabilities
+        .epr_pdos()
+        .filter(|(position, pdo)| *position != 0 && !pdo.is_zero_padding())
+}
+
+/// Whether the source is EPR capable, from the flag in its first PDO.
+fn source_epr_capable(source_capabilities: &SourceCapabilities) -> bool {
+    matches!(
+        source_capabilities.pdos().first(),
+        Some(PowerDataObject::FixedSupply(fixed)) if fixed.epr_mode_capable()
+    )
+}
+
+/// Whether an SPR PPS PDO covers `voltage_mv`.
+fn pps_covers(source_capabilities: &SourceCapabilities, voltage_mv: u32) -> bool {
+    offered_spr_pdos(source_capabilities).any(|(_, pdo)| match pdo {
+        PowerDataObject::Augmented(Augmented::Spr(pps)) => {
+            pps.raw_min_voltage() as u32 * 100 <= voltage_mv
+                && voltage_mv <= pps.raw_max_voltage() as u32 * 100
+        }
+        _ => false,
+    })
+}
+
+/// Maximum continuous power the source can deliver, over both its SPR and EPR PDOs.
+///
+/// For EPR capabilities this is the PDP advertised in the AVS PDO.
+pub fn source_max_power(source_capabilities: &SourceCapabilities) -> Power {
+    let max_mw = source_capabilities
+        .pdos()
+        .iter()
+        .map(|pdo| match pdo {
+            PowerDataObject::FixedSupply(f) => {
+                f.raw_voltage() as u32 * 50 * f.raw_max_current() as u32 * 10 / 1000
+            }
+            PowerDataObject::Battery(b) => b.raw_max_power() as u32 * 250,
+            PowerDataObject::VariableSupply(v) => {
+                v.raw_max_voltage() as u32 * 50 * v.raw_max_current() as u32 * 10 / 1000
+            }
+            PowerDataObject::Augmented(Augmented::Spr(pps)) => {
+                pps.raw_max_voltage() as u32 * 100 * pps.raw_max_current() as u32 * 50 / 1000
+            }
+            PowerDataObject::Augmented(Augmented::Epr(avs)) => avs.raw_pd_power() as u32 * 1000,
+            _ => 0,
+        })
+        .max()
+        .unwrap_or(0);
+    Power::new::<milliwatt>(max_mw)
+}
+
+/// Reduce the current of a PPS or AVS request so it stays within the source's maximum power.
+///
+/// Fixed requests are bounded by their PDO already.
+fn limit_to_source_power(power_source: PowerSource, max_power_mw: u32) -> PowerSource {
+    let max_current_raw = |voltage_mv: u32| avs_max_current_raw(max_power_mw, voltage_mv);
+    match power_source {
+        PowerSource::EprRequest {
+            pdo: PowerDataObject::FixedSupply(_),
+            ..
+        } => power_source,
+        PowerSource::EprRequest { rdo, pdo } => {
+            let avs = Avs(rdo);
+            let voltage_mv = avs.raw_output_voltage() as u32 * 25;
+            let max_current = max_current_raw(voltage_mv);
+            if avs.raw_operating_current() <= max_current {
+                return power_source;
+            }
+            warn!(
+                "AVS request above source maximum, limiting to {}mA",
+                max_current as u32 * 50
+            );
+            PowerSource::EprRequest {
+                rdo: avs.with_raw_operating_current(max_current).0,
+                pdo,
+            }
+        }
+        PowerSource::Pps(rdo) => {
+            let voltage_mv = rdo.raw_output_voltage() as u32 * 20;
+            let max_current = max_current_raw(voltage_mv);
+            if rdo.raw_operating_current() <= max_current {
+                return power_source;
+            }
+            warn!(
+                "PPS request above source maximum, limiting to {}mA",
+                max_current as u32 * 50
+            );
+            PowerSource::Pps(rdo.with_raw_operating_current(max_current))
+        }
+        _ => power_source,
+    }
+}
+
+/// Scale the requested current of a power source down to `percent`.
+fn derate(power_source: PowerSource, percent: u32) -> PowerSource {
+    if percent >= 100 {
+        return power_source;
+    }
+    let scale = |raw: u16| (raw as u32 * percent / 100) as u16;
+    match power_source {
+        PowerSource::FixedVariableSupply(rdo) => {
+            let current = scale(rdo.raw_operating_current());
+            let max_current = scale(rdo.raw_max_operating_current());
+            PowerSource::FixedVariableSupply(
+                rdo.with_raw_operating_current(current)
+                    .with_raw_max_operating_current(max_current),
+            )
+        }
+        PowerSource::Pps(rdo) => {
+            let current = scale(rdo.raw_operating_current());
+            PowerSource::Pps(rdo.with_raw_operating_current(current))
+        }
+        PowerSource::EprRequest {
+            rdo,
+            pdo: pdo @ PowerDataObject::FixedSupply(_),
+        } => with_epr_pdo(
+            derate(
+                PowerSource::FixedVariableSupply(FixedVariableSupply(rdo)),
+                percent,
+            ),
+            pdo,
+        ),
+        PowerSource::EprRequest { rdo, pdo } => {
+            let avs = Avs(rdo);
+            let current = scale(avs.raw_operating_current());
+            PowerSource::EprRequest {
+                rdo: avs.with_raw_operating_current(current).0,
+                pdo,
+            }
+        }
+        PowerSource::Battery(rdo) => {
+            let power = scale(rdo.raw_operating_power());
+            let max_power = scale(rdo.raw_max_operating_power());
+            PowerSource::Battery(
+                rdo.with_raw_operating_power(power)
+                    .with_raw_max_operating_power(max_power),
+            )
+        }
+        _ => power_source,
+    }
+}
+
+/// Wrap a fixed RDO into an EPR request for `pdo`, the fixed EPR PDO it was built from.
+fn with_epr_pdo(power_source: PowerSource, pdo: PowerDataObject) -> PowerSource {
+    match power_source {
+        PowerSource::FixedVariableSupply(rdo) => PowerSource::EprRequest { rdo: rdo.0, pdo },
+        power_source => power_source,
+    }
+}
+
+/// Cap the requested current to what the cable carries.
+///
+/// Battery requests are in power and left alone.
+fn limit_to_cable_current(power_source: PowerSource, max_current_ma: u32) -> PowerSource {
+    match cap_current(power_source, max_current_ma) {
+        Some(limited) => {
+            info!("Limiting the request to the cable's {}mA", max_current_ma);
+            limited
+        }
+        None => power_source,
+    }
+}
+
+/// Cap the requested current of fixed, variable, PPS and AVS requests to `max_current_ma`.
+///
+/// Returns `None` if the request is within the cap already.
+fn cap_current(power_source: PowerSource, max_current_ma: u32) -> Option<PowerSource> {
+    let max_raw_10ma = (max_current_ma / 10).min(u16::MAX as u32) as u16;
+    let max_raw_50ma = (max_current_ma / 50).min(u16::MAX as u32) as u16;
+    let limited = match power_source {
+        PowerSource::FixedVariableSupply(rdo)
+            if rdo.raw_operating_current() > max_raw_10ma
+                || rdo.raw_max_operating_current() > max_raw_10ma =>
+        {
+            let current = rdo.raw_operating_current().min(max_raw_10ma);
+            let max_current = rdo.raw_max_operating_current().min(max_raw_10ma);
+            PowerSource::FixedVariableSupply(
+                rdo.with_raw_operating_current(current)
+                    .with_raw_max_operating_current(max_current),
+            )
+        }
+        PowerSource::Pps(rdo) if rdo.raw_operating_current() > max_raw_50ma => {
+            PowerSource::Pps(rdo.with_raw_operating_current(max_raw_50ma))
+        }
+        PowerSource::EprRequest {
+            rdo,
+            pdo: pdo @ PowerDataObject::FixedSupply(_),
+        } => {
+            let fixed = PowerSource::FixedVariableSupply(FixedVariableSupply(rdo));
+            return cap_current(fixed, max_current_ma).map(|limited| with_epr_pdo(limited, pdo));
+        }
+        PowerSource::EprRequest { rdo, pdo } if Avs(rdo).raw_operating_current() > max_raw_50ma => {
+            PowerSource::EprRequest {
+                rdo: Avs(rdo).with_raw_operating_current(max_raw_50ma).0,
+                pdo,
+            }
+        }
+        _ => return None,
+    };
+    Some(limited)
+}
+
+/// Set the Capability Mismatch bit if the source can't provide the operational PDP.
+///
+/// Skipped while EPR entry is still pending, as the SPR capabilities of an EPR source
+/// don't show its full power.
+fn flag_capability_mismatch(
+    power_source: PowerSource,
+    source_capabilities: &SourceCapabilities,
+    operational_pdp_watts: u32,
+    epr_enabled: bool,
+) -> PowerSource {
+    let epr_entry_pending = epr_enabled
+        && !source_capabilities.is_epr_capabilities()
+        && source_epr_capable(source_capabilities);
+    let available_mw = source_max_power(source_capabilities).get::<milliwatt>();
+    let required_mw = operational_pdp_watts * 1000;
+    if epr_entry_pending || available_mw >= required_mw {
+        return power_source;
+    }
+
+    warn!(
+        "Source offers {}W, {}W short of {}W, setting capability mismatch",
+        available_mw / 1000,
+        (required_mw - available_mw) / 1000,
+        operational_pdp_watts
+    );
+    with_capability_mismatch(power_source)
+}
+
+/// Set the Capability Mismatch bit of a request.
+fn with_capability_mismatch(power_source: PowerSource) -> PowerSource {
+    match power_source {
+        PowerSource::FixedVariableSupply(rdo) => {
+            PowerSource::FixedVariableSupply(rdo.with_capability_mismatch(true))
+        }
+        PowerSource::Pps(rdo) => PowerSource::Pps(rdo.with_capability_mismatch(true)),
+        // Same bit in fixed and AVS RDOs
+        PowerSource::EprRequest { rdo, pdo } => PowerSource::EprRequest {
+            rdo: Avs(rdo).with_capability_mismatch(true).0,
+            pdo,
+        },
+        other => other,
+    }
+}
+
+/// Encode an AVS output voltage for an EPR AVS RDO.
+///
+/// The voltage is in 25mV units with the two least significant bits zero, i.e. effective
+/// 100mV steps. Per USB PD 3.2 Table 6.26: "Output voltage in 25 mV units, the least two
+/// significant bits Shall be set to zero". Voltages in between are rounded down.
+fn encode_avs_voltage(mv: u32) -> u16 {
+    ((mv / 25) & !0x3) as u16
+}
+
+/// Highest current in 50mA units that stays within `pdp_mw` at `voltage_mv`.
+///
+/// Rounded down to the 50mA step, so the request never exceeds the PDP. The voltage is in
+/// mV rather than V, as AVS targets fall on 100mV steps.
+fn avs_max_current_raw(pdp_mw: u32, voltage_mv: u32) -> u16 {
+    let max_current_ma = pdp_mw as u64 * 1000 / voltage_mv.max(1) as u64;
+    (max_current_ma / 50).min(u16::MAX as u64) as u16
+}
+
+/// Whether `position` refers to a PDO the source offers, rather than padding or a position
+/// past the end of the capabilities.
+fn validate_position(source_capabilities: &SourceCapabilities, position: u8) -> bool {
+    pdo_at(source_capabilities, position).is_some_and(|pdo| !pdo.is_zero_padding())
+}
+
+/// The PDO at the 1-based `position`, `None` for position 0 or past the last PDO.
+///
+/// Contracts are derived before `checked_position` catches a bad selection, so this must
+/// not assume a valid position.
+fn pdo_at(source_capabilities: &SourceCapabilities, position: u8) -> Option<&PowerDataObject> {
+    (position as usize)
+        .checked_sub(1)
+        .and_then(|index| source_capabilities.pdos().get(index))
+}
+
+/// Last check of a selection before it is requested, replacing an invalid position with
+/// vSafe5V.
+///
+/// Selection only builds requests from offered PDOs, so this catches bugs rather than
+/// source behavior.
+fn checked_position(
+    power_source: PowerSource,
+    source_capabilities: &SourceCapabilities,
+) -> PowerSource {
+    let position = power_source.object_position();
+    if validate_position(source_capabilities, position) {
+        return power_source;
+    }
+    error!(
+        "Selected PDO {} not offered by the source, requesting vSafe5V",
+        position
+    );
+    with_capability_mismatch(safe_5v(source_capabilities))
+}
+
+/// Request vSafe5V, which every source has to offer.
+fn safe_5v(source_capabilities: &SourceCapabilities) -> PowerSource {
+    PowerSource::new_fixed(
+        CurrentRequest::Highest,
+        VoltageRequest::Safe5V,
+        source_capabilities,
+    )
+    .unwrap()
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use crate::sim::{
+        ACCEPT, FIXED_DUAL_ROLE_DATA, FIXED_EPR_MODE_CAPABLE, FIXED_UNCONSTRAINED_POWER,
+        FIXED_USB_COMMUNICATIONS_CAPABLE, MockDriver, MockSinkTimer, NOT_SUPPORTED, PS_RDY,
+        fixed_pdo, pps_pdo,
+    };
+    use embassy_futures::block_on;
+    use embassy_time::{Duration, with_timeout};
+    use usbpd::sink::policy_engine::Sink;
+    use usbpd_traits::Driver;
+
+    /// Run the sink against the scripted source and return the RDOs it requested.
+    fn negotiate(driver: &mut MockDriver, contract_mv: u32) -> std::vec::Vec<u32> {
+        negotiate_with(driver, contract_mv, |device| device)
+    }
+
+    /// Like `negotiate`, with the policy adjusted by `configure`.
+    fn negotiate_with(
+        driver: &mut MockDriver,
+        contract_mv: u32,
+        configure: impl FnOnce(Device<'_>) -> Device<'_>,
+    ) -> std::vec::Vec<u32> {
+        let mut vbus = VbusMonitor::new(contract_mv, 500);
+        let device = configure(Device::new(
+            DEFAULT_TARGET_AVS_MV,
+            DEFAULT_TARGET_AVS_CURRENT_MA,
+            DEFAULT_OPERATIONAL_PDP_WATTS,
+            &mut vbus,
+            &SINK_PDOS,
+        ));
+        let mut sink: Sink<&mut MockDriver, EmbassySinkTimer, _> = Sink::new(&mut *driver, device);
+        let _ = block_on(with_timeout(Duration::from_millis(500), sink.run()));
+        drop(sink);
+        driver.requests()
+    }
+
+    #[test]
+    fn epr_charger_140w_requests_highest_spr_with_epr_flag() {
+        // SPR capabilities of a typical 140W EPR charger
+        let mut driver = MockDriver::new()
+            .source_capabilities(&[
+                fixed_pdo(5_000, 3_000)
+                    | FIXED_EPR_MODE_CAPABLE
+                    | FIXED_USB_COMMUNICATIONS_CAPABLE
+                    | FIXED_DUAL_ROLE_DATA,
+                fixed_pdo(9_000, 3_000),
+                fixed_pdo(12_000, 3_000),
+                fixed_pdo(15_000, 3_000),
+                fixed_pdo(20_000, 5_000),
+                pps_pdo(5_000, 21_000, 5_000),
+            ])
+            .control(ACCEPT)
+            .control(PS_RDY);
+
+        let requests = negotiate(&mut driver, 20_000);
+        let rdo = FixedVariableSupply(requests[0]);
+        assert_eq!(rdo.object_position(), 5);
+        assert_eq!(rdo.raw_operating_current(), 500);
+        assert_eq!(rdo.raw_max_operating_current(), 500);
+        assert!(rdo.epr_mode_capable());
+    }
+
+    #[test]
+    fn spr_charger_requests_highest_fixed_voltage() {
+        let mut driver = MockDriver::new()
+            .source_capabilities(&[
+                fixed_pdo(5_000, 3_000) | FIXED_USB_COMMUNICATIONS_CAPABLE,
+                fixed_pdo(9_000, 3_000),
+                fixed_pdo(15_000, 2_000),
+            ])
+            .control(ACCEPT)
+            .control(PS_RDY);
+
+        let requests = negotiate(&mut driver, 15_000);
+        let rdo = FixedVariableSupply(requests[0]);
+        assert_eq!(rdo.object_position(), 3);
+        assert!(!rdo.epr_mode_capable());
+    }
+
+    const PREFERENCES: [Preference; 5] = [
+        Preference::Avs { voltage_mv: 24_000 },
+        Preference::Pps { voltage_mv: 20_000 },
+        Preference::Fixed { voltage_mv: 20_000 },
+        Preference::Fixed { voltage_mv: 9_000 },
+        Preference::Fixed { voltage_mv: 5_000 },
+    ];
+
+    #[test]
+    fn preferences_skip_contracts_the_source_cannot_satisfy() {
+        // PPS that doesn't reach 20V, no fixed 20V
+        let mut driver = MockDriver::new()
+            .source_capabilities(&[
+                fixed_pdo(5_000, 3_000),
+                fixed_pdo(9_000, 3_000),
+                fixed_pdo(15_000, 3_000),
+                pps_pdo(3_300, 11_000, 3_000),
+            ])
+            .control(ACCEPT)
+            .control(PS_RDY);
+
+        let requests = negotiate_with(&mut driver, 9_000, |device| {
+            device.with_preferences(&PREFERENCES)
+        });
+        let rdo = FixedVariableSupply(requests[0]);
+        assert_eq!(rdo.object_position(), 2);
+    }
+
+    #[test]
+    fn preferences_request_pps_when_covered() {
+        let mut driver = MockDriver::new()
+            .source_capabilities(&[
+                fixed_pdo(5_000, 3_000),
+                fixed_pdo(20_000, 3_000),
+                pps_pdo(3_300, 21_000, 3_000),
+            ])
+            .control(ACCEPT)
+            .control(PS_RDY);
+
+        let requests = negotiate_with(&mut driver, 20_000, |device| {
+            device.with_preferences(&PREFERENCES)
+        });
+        let rdo = Pps(requests[0]);
+        assert_eq!(rdo.object_position(), 3);
+        assert_eq!(rdo.raw_output_voltage(), 1_000);
+    }
+
+    #[test]
+    fn avs_voltage_is_encoded_in_100mv_steps() {
+        assert_eq!(encode_avs_voltage(15_000), 600);
+        assert_eq!(encode_avs_voltage(20_000), 800);
+        assert_eq!(encode_avs_voltage(24_000), 960);
+        assert_eq!(encode_avs_voltage(28_000), 1_120);
+        assert_eq!(encode_avs_voltage(24_100), 964);
+    }
+
+    #[test]
+    fn avs_voltage_between_steps_rounds_down() {
+        // 24.15V is 966 in 25mV units, the two LSBs must be cleared
+        assert_eq!(encode_avs_voltage(24_150), 964);
+        assert_eq!(encode_avs_voltage(24_175), 964);
+        for mv in (15_000..=28_000).step_by(25) {
+            assert_eq!(encode_avs_voltage(mv) & 0x3, 0, "{mv}mV");
+        }
+    }
+
+    #[test]
+    fn avs_max_current_from_pdp() {
+        // 100W at 20V and 140W at 28V are exactly 5A
+        assert_eq!(avs_max_current_raw(100_000, 20_000), 100);
+        assert_eq!(avs_max_current_raw(140_000, 28_000), 100);
+        // 100W at 28V is 3571mA, rounded down to 3550mA rather than up to 3600mA
+        assert_eq!(avs_max_current_raw(100_000, 28_000), 71);
+        // 140W at 24.9V is 5622mA, not the 5833mA of a voltage truncated to 24V
+        assert_eq!(avs_max_current_raw(140_000, 24_900), 112);
+    }
+
+    #[test]
+    fn separators_are_never_selected() {
+        let source = || {
+            MockDriver::new()
+                .source_capabilities(&[fixed_pdo(5_000, 3_000), 0, fixed_pdo(20_000, 3_000), 0])
+                .control(ACCEPT)
+                .control(PS_RDY)
+        };
+
+        // Sufficient power at 20V only
+        let mut driver = source();
+        let requests = negotiate_with(&mut driver, 20_000, |device| {
+            device.with_preferences(&[Preference::LowestSufficient { power_mw: 45_000 }])
+        });
+        assert_eq!(FixedVariableSupply(requests[0]).object_position(), 3);
+
+        // No PDO is sufficient, the most powerful one is requested rather than a separator
+        let mut driver = source();
+        let requests = negotiate_with(&mut driver, 20_000, |device| {
+            device.with_preferences(&[Preference::LowestSufficient { power_mw: 100_000 }])
+        });
+        let rdo = FixedVariableSupply(requests[0]);
+        assert_eq!(rdo.object_position(), 3);
+        assert!(rdo.capability_mismatch());
+    }
+
+    #[test]
+    fn unconstrained_pdo_wins_over_equal_constrained_one() {
+        // Two ports' worth of 20V, only the second is unconstrained
+        let source = || {
+            MockDriver::new()
+                .source_capabilities(&[
+                    fixed_pdo(5_000, 3_000),
+                    fixed_pdo(9_000, 3_000),
+                    fixed_pdo(20_000, 3_000),
+                    fixed_pdo(20_000, 3_000) | FIXED_UNCONSTRAINED_POWER,
+                    fixed_pdo(15_000, 3_000),
+                ])
+                .control(ACCEPT)
+                .control(PS_RDY)
+        };
+
+        let mut driver = source();
+        let requests = negotiate(&mut driver, 20_000);
+        assert_eq!(FixedVariableSupply(requests[0]).object_position(), 4);
+
+        let mut driver = source();
+        let requests = negotiate_with(&mut driver, 20_000, |device| {
+            device.with_preferences(&[Preference::LowestSufficient { power_mw: 60_000 }])
+        });
+        assert_eq!(FixedVariableSupply(requests[0]).object_position(), 4);
+    }
+
+    #[test]
+    fn higher_voltage_wins_over_unconstrained_power() {
+        let mut driver = MockDriver::new()
+            .source_capabilities(&[
+                fixed_pdo(5_000, 3_000),
+                fixed_pdo(15_000, 3_000) | FIXED_UNCONSTRAINED_POWER,
+                fixed_pdo(20_000, 3_000),
+            ])
+            .control(ACCEPT)
+            .control(PS_RDY);
+
+        let requests = negotiate(&mut driver, 20_000);
+        assert_eq!(FixedVariableSupply(requests[0]).object_position(), 3);
+    }
+
+    #[test]
+    fn usb_communications_capable_flag_follows_config() {
+        let source = || {
+            MockDriver::new()
+                .source_capabilities(&[fixed_pdo(5_000, 3_000), pps_pdo(3_300, 11_000, 3_000)])
+                .control(ACCEPT)
+                .control(PS_RDY)
+        };
+
+        let mut driver = source();
+        let requests = negotiate(&mut driver, 5_000);
+        let rdo = FixedVariableSupply(requests[0]);
+        assert!(rdo.usb_communications_capable());
+        assert!(rdo.no_usb_suspend());
+
+        let mut driver = source();
+        let requests = negotiate_with(&mut driver, 5_000, |device| {
+            device.with_usb_communications_capable(false)
+        });
+        let rdo = FixedVariableSupply(requests[0]);
+        assert!(!rdo.usb_communications_capable());
+        assert!(rdo.no_usb_suspend());
+        assert_eq!(requests[0] & (1 << 25), 0);
+
+        // PPS requests carry the flag too
+        let mut driver = source();
+        let requests = negotiate_with(&mut driver, 9_000, |device| {
+            device
+                .with_pps_target(9_000, 2_000)
+                .with_usb_communications_capable(false)
+        });
+        let rdo = Pps(requests[0]);
+        assert_eq!(rdo.object_position(), 2);
+        assert!(!rdo.usb_communications_capable());
+    }
+
+    #[test]
+    fn accept_first_requests_the_first_pdo_at_highest_current() {
+        let mut driver = MockDriver::new()
+            .source_capabilities(&[
+                fixed_pdo(5_000, 3_000) | FIXED_EPR_MODE_CAPABLE,
+                fixed_pdo(9_000, 3_000),
+                fixed_pdo(20_000, 5_000),
+                pps_pdo(5_000, 21_000, 5_000),
+            ])
+            .control(ACCEPT)
+            .control(PS_RDY);
+
+        let requests = negotiate_with(&mut driver, 5_000, |device| device.with_accept_first(true));
+        let rdo = FixedVariableSupply(requests[0]);
+        assert_eq!(rdo.object_position(), 1);
+        assert_eq!(rdo.raw_operating_current(), 300);
+        assert!(!rdo.epr_mode_capable());
+    }
+
+    #[test]
+    fn global_max_current_caps_every_request() {
+        let source = || {
+            MockDriver::new()
+                .source_capabilities(&[
+                    fixed_pdo(5_000, 3_000),
+                    fixed_pdo(20_000, 5_000),
+                    pps_pdo(3_300, 11_000, 5_000),
+                ])
+                .control(ACCEPT)
+                .control(PS_RDY)
+        };
+
+        let mut driver = source();
+        let requests = negotiate_with(&mut driver, 20_000, |device| {
+            device.with_global_max_current(2_000)
+        });
+        let rdo = FixedVariableSupply(requests[0]);
+        assert_eq!(rdo.object_position(), 2);
+        assert_eq!(rdo.raw_operating_current(), 200);
+        assert_eq!(rdo.raw_max_operating_current(), 200);
+
+        let mut driver = source();
+        let requests = negotiate_with(&mut driver, 9_000, |device| {
+            device
+                .with_pps_target(9_000, 4_000)
+                .with_global_max_current(2_000)
+        });
+        let rdo = Pps(requests[0]);
+        assert_eq!(rdo.object_position(), 3);
+        assert_eq!(rdo.raw_operating_current(), 40);
+    }
+
+    #[test]
+    fn forced_epr_fixed_pdo_takes_an_epr_request() {
+        let mut vbus = VbusMonitor::new(28_000, 500);
+        let mut device: Device<'_> = Device::new(
+            DEFAULT_TARGET_AVS_MV,
+            DEFAULT_TARGET_AVS_CURRENT_MA,
+            DEFAULT_OPERATIONAL_PDP_WATTS,
+            &mut vbus,
+            &SINK_PDOS,
+        );
+        // 28V 5A, the first EPR PDO of a 140W charger
+        let pdo = PowerDataObject::FixedSupply(FixedSupply(fixed_pdo(28_000, 5_000)));
+
+        let Some(PowerSource::EprRequest { rdo, pdo: copied }) =
+            device.forced_request(8, &pdo, true)
+        else {
+            panic!("forcing PDO 8 must take an EPR_Request");
+        };
+        let rdo = FixedVariableSupply(rdo);
+        assert_eq!(rdo.object_position(), 8);
+        assert!(rdo.epr_mode_capable());
+        assert_eq!(rdo.raw_operating_current(), 500);
+        assert!(
+            matches!(copied, PowerDataObject::FixedSupply(fixed) if fixed.raw_voltage() == 560)
+        );
+
+        // SPR PDOs take a plain Request outside EPR mode, an EPR_Request in it
+        let spr = PowerDataObject::FixedSupply(FixedSupply(fixed_pdo(20_000, 5_000)));
+        assert!(matches!(
+            device.forced_request(4, &spr, true),
+            Some(PowerSource::FixedVariableSupply(_))
+        ));
+        device.entered_epr_mode = true;
+        assert!(matches!(
+            device.forced_request(4, &spr, true),
+            Some(PowerSource::EprRequest { .. })
+        ));
+    }
+
+    #[test]
+    fn variable_supply_may_settle_anywhere_in_its_range() {
+        let mut vbus = VbusMonitor::new(12_000, 500);
+        let mut device: Device<'_> = Device::new(
+            DEFAULT_TARGET_AVS_MV,
+            DEFAULT_TARGET_AVS_CURRENT_MA,
+            DEFAULT_OPERATIONAL_PDP_WATTS,
+            &mut vbus,
+            &SINK_PDOS,
+        );
+        // 9-15V variable supply, recorded at its minimum
+        device.requested_contract = Contract {
+            voltage_mv: 9_000,
+            current_ma: 2_000,
+            pdo_position: 2,
+            ..Contract::NONE
+        };
+        device.requested_vbus_mv = (9_000, 15_000);
+        assert!(block_on(device.validate_vbus()));
+
+        // Outside the range by more than the tolerance
+        device.vbus.voltage_mv = 15_600;
+        assert!(!block_on(device.validate_vbus()));
+    }
+
+    #[test]
+    fn battery_contract_draws_its_power_at_the_settled_voltage() {
+        let mut vbus = VbusMonitor::new(12_000, 500);
+        let mut device: Device<'_> = Device::new(
+            DEFAULT_TARGET_AVS_MV,
+            DEFAULT_TARGET_AVS_CURRENT_MA,
+            DEFAULT_OPERATIONAL_PDP_WATTS,
+            &mut vbus,
+            &SINK_PDOS,
+        );
+        // 30W from a 9-15V battery supply, recorded at its minimum
+        let rdo = Battery(0)
+            .with_object_position(2)
+            .with_raw_operating_power(120)
+            .with_raw_max_operating_power(120);
+        device.last_request = Some(PowerSource::Battery(rdo));
+        device.requested_contract = Contract {
+            voltage_mv: 9_000,
+            current_ma: battery_current_ma(&rdo, 9_000),
+            pdo_position: 2,
+            ..Contract::NONE
+        };
+        device.requested_vbus_mv = (9_000, 15_000);
+
+        assert!(block_on(device.validate_vbus()));
+        assert_eq!(device.requested_contract.voltage_mv, 12_000);
+        assert_eq!(device.requested_contract.current_ma, 2_500);
+    }
+
+    #[test]
+    fn out_of_range_position_falls_back_to_safe_5v() {
+        /// Selects the position past the last PDO, like an off-by-one would.
+        struct OffByOne;
+
+        impl DevicePolicyManager for OffByOne {
+            async fn request(&mut self, source_capabilities: &SourceCapabilities) -> PowerSource {
+                let past_end = source_capabilities.pdos().len() as u8 + 1;
+                assert!(validate_position(source_capabilities, 1));
+                assert!(!validate_position(source_capabilities, 0));
+                assert!(!validate_position(source_capabilities, past_end));
+                // Contracts are derived before the check, position 0 must not underflow
+                let unset = PowerSource::FixedVariableSupply(FixedVariableSupply(0));
+                assert_eq!(contract_for(&unset, source_capabilities).voltage_mv, 0);
+                let selected = PowerSource::FixedVariableSupply(
+                    FixedVariableSupply(0)
+                        .with_object_position(past_end)
+                        .with_raw_operating_current(300)
+                        .with_raw_max_operating_current(300),
+                );
+                checked_position(selected, source_capabilities)
+            }
+        }
+
+        let mut driver = MockDriver::new()
+            .source_capabilities(&[fixed_pdo(5_000, 3_000), fixed_pdo(20_000, 3_000)])
+            .control(ACCEPT)
+            .control(PS_RDY);
+        let mut sink: Sink<&mut MockDriver, EmbassySinkTimer, _> = Sink::new(&mut driver, OffByOne);
+        let _ = block_on(with_timeout(Duration::from_millis(500), sink.run()));
+        drop(sink);
+
+        let rdo = FixedVariableSupply(driver.requests()[0]);
+        assert_eq!(rdo.object_position(), 1);
+        assert!(rdo.capability_mismatch());
+    }
+
+    #[test]
+    fn ramp_steps_through_the_ladder_before_the_target() {
+        let caps = [
+            fixed_pdo(5_000, 3_000),
+            fixed_pdo(9_000, 3_000),
+            fixed_pdo(15_000, 3_000),
+            fixed_pdo(20_000, 3_000),
+        ];
+        let mut driver = MockDriver::new()
+            .source_capabilities(&caps)
+            .control(ACCEPT)
+            .control(PS_RDY)
+            .source_capabilities(&caps)
+            .control(ACCEPT)
+            .control(PS_RDY);
+
+        // The 20V step is the target itself, 9V is the only intermediate contract
+        let requests = negotiate_with(&mut driver, 20_000, |device| {
+            device.with_ramp(&[9_000, 20_000], 10)
+        });
+        assert_eq!(requests.len(), 2);
+        assert_eq!(FixedVariableSupply(requests[0]).object_position(), 2);
+        assert_eq!(FixedVariableSupply(requests[1]).object_position(), 4);
+    }
+
+    #[test]
+    fn wait_retries_after_sink_request_time() {
+        let mut vbus = VbusMonitor::new(5_000, 500);
+        let mut device: Device<'_, MockSinkTimer> = Device::new(
+            DEFAULT_TARGET_AVS_MV,
+            DEFAULT_TARGET_AVS_CURRENT_MA,
+            DEFAULT_OPERATIONAL_PDP_WATTS,
+            &mut vbus,
+            &SINK_PDOS,
+        );
+        device.last_request = Some(PowerSource::FixedVariableSupply(
+            FixedVariableSupply(0).with_object_position(2),
+        ));
+        MockSinkTimer::take_delays();
+
+        for _ in 0..MAX_WAIT_RETRIES {
+            let event = block_on(device.retry_after_wait());
+            assert!(matches!(
+                event,
+                Event::RequestPower(PowerSource::FixedVariableSupply(rdo)) if rdo.object_position() == 2
+            ));
+        }
+        // Given up on without waiting again
+        assert!(matches!(block_on(device.retry_after_wait()), Event::None));
+        assert_eq!(
+            MockSinkTimer::take_delays(),
+            [SINK_REQUEST_MS; MAX_WAIT_RETRIES as usize]
+        );
+    }
+
+    #[test]
+    fn data_answers_need_data_objects() {
+        // Ping and Battery_Status share type 5, only the latter carries a data object
+        let ping: u16 = (1 << 8) | (0b10 << 6) | 0b0_0101;
+        let battery_status = ping | (1 << 12);
+        assert_eq!(
+            QueryReply::classify(ping, MessageKind::Data, 0b0_0101),
+            QueryReply::Unrelated
+        );
+        assert_eq!(
+            QueryReply::classify(battery_status, MessageKind::Data, 0b0_0101),
+            QueryReply::Answer
+        );
+    }
+
+    /// The simulated source as the transport of the driver's own exchanges.
+    impl query::PdPort for MockDriver {
+        async fn transmit(&mut self, message: &[u8]) -> Result<(), ()> {
+            let mut source = self;
+            Driver::transmit(&mut source, message).await.map_err(|_| ())
+        }
+
+        async fn receive(&mut self, buffer: &mut [u8]) -> Result<usize, ()> {
+            let mut source = self;
+            Driver::receive(&mut source, buffer).await.map_err(|_| ())
+        }
+    }
+
+    /// The driver's handling of its own requests on top of the simulated source, as
+    /// `receive` and `transmit` of the UCPD driver do it: `request` is carried out the
+    /// first time the policy engine waits for a message after PS_RDY.
+    struct QueryingDriver<'a> {
+        source: &'a mut MockDriver,
+        link: query::QueryLink,
+        request: Option<DriverRequest>,
+        contract_ready: bool,
+        result: Option<Result<usize, QueryError>>,
+    }
+
+    impl Driver for &mut QueryingDriver<'_> {
+        async fn wait_for_vbus(&self) {}
+
+        async fn receive(
+            &mut self,
+            buffer: &mut [u8],
+        ) -> Result<usize, usbpd_traits::DriverRxError> {
+            let request = if self.contract_ready {
+                self.request.take()
+            } else {
+                None
+            };
+            if let Some(request) = request {
+                let mut response = [0u8; query::MAX_MESSAGE_LEN];
+                let result = self
+                    .link
+                    .request(&mut *self.source, request, &mut response)
+                    .await;
+                self.result = Some(result);
+            }
+            loop {
+                let len = match self.link.take_stashed(buffer) {
+                    Some(len) => len,
+                    None => {
+                        let mut source = &mut *self.source;
+                        Driver::receive(&mut source, buffer).await?
+                    }
+                };
+                if self
+                    .link
+                    .drop_late_answer(&mut *self.source, &buffer[..len])
+                    .await
+                {
+                    continue;
+                }
+                let header = u16::from_le_bytes([buffer[0], buffer[1]]);
+                if MessageKind::of(header) == MessageKind::Control && header & 0x1F == PS_RDY as u16
+                {
+                    self.contract_ready = true;
+                }
+                self.link.unshift_good_crc(&mut buffer[..len]);
+                return Ok(len);
+            }
+        }
+
+        async fn transmit(&mut self, data: &[u8]) -> Result<(), usbpd_traits::DriverTxError> {
+            let mut message = data.to_vec();
+            let header = self
+                .link
+                .outgoing_header(u16::from_le_bytes([data[0], data[1]]));
+            message[..2].copy_from_slice(&header.to_le_bytes());
+            let mut source = &mut *self.source;
+            Driver::transmit(&mut source, &message).await
+        }
+
+        async fn transmit_hard_reset(&mut self) -> Result<(), usbpd_traits::DriverTxError> {
+            self.link.reset();
+            let mut source = &mut *self.source;
+            Driver::transmit_hard_reset(&mut source).await
+        }
+    }
+
+    #[test]
+    fn not_supported_refuses_a_query_with_the_contract_intact() {
+        // The source doesn't implement Get_Source_Cap_Extended
+        let mut source = MockDriver::new()
+            .source_capabilities(&[fixed_pdo(5_000, 3_000), fixed_pdo(9_000, 3_000)])
+            .control(ACCEPT)
+            .control(PS_RDY)
+            .control(NOT_SUPPORTED);
+        let mut driver = QueryingDriver {
+            source: &mut source,
+            link: query::QueryLink::new(),
+            request: Some(DriverRequest::SourceCapExtended),
+            contract_ready: false,
+            result: None,
+        };
+
+        // Contracts are recorded per test, `CONTRACT` is shared by the tests running in
+        // parallel
+        let mut contracts = std::vec::Vec::new();
+        let mut record = |contract: &Contract| contracts.push(*contract);
+        let mut vbus = VbusMonitor::new(9_000, 500);
+        let device = Device::new(
+            DEFAULT_TARGET_AVS_MV,
+            DEFAULT_TARGET_AVS_CURRENT_MA,
+            DEFAULT_OPERATIONAL_PDP_WATTS,
+            &mut vbus,
+            &SINK_PDOS,
+        )
+        .with_transition_handler(&mut record);
+        let mut sink: Sink<&mut QueryingDriver<'_>, EmbassySinkTimer, _> =
+            Sink::new(&mut driver, device);
+        let _ = block_on(with_timeout(Duration::from_millis(500), sink.run()));
+        drop(sink);
+
+        assert_eq!(driver.result, Some(Err(QueryError::NotSupported)));
+        // Nothing left over for the policy engine
+        let mut buffer = [0u8; query::MAX_MESSAGE_LEN];
+        assert_eq!(driver.link.take_stashed(&mut buffer), None);
+
+        // No Soft_Reset or Hard_Reset, the 9V contract stays the only one
+        let soft_reset = |message: &std::vec::Vec<u8>| {
+            let header = u16::from_le_bytes([message[0], message[1]]);
+            MessageKind::of(header) == MessageKind::Control && header & 0x1F == 0b0_1101
+        };
+        assert!(!source.transmitted.iter().any(soft_reset));
+        assert_eq!(source.hard_resets, 0);
+        assert_eq!(source.requests().len(), 1);
+        assert_eq!(contracts.len(), 1);
+        assert_eq!(contracts[0].voltage_mv, 9_000);
     }
 }
@@ -0,0 +1,1254 @@
+//! UCPD hardware side of the PD negotiation: CC attach detection, the PD phy driver and
+//! the task running the policy engine. Only built for the target, the policy in the parent
+//! module has no dependency on the UCPD types.
+use core::sync::atomic::Ordering;
+use embassy_futures::select::{Either, Either3, Either4, select, select3, select4};
+use embassy_stm32::gpio::Output;
+use embassy_stm32::ucpd::{self, CcPhy, CcPull, CcSel, CcVState, PdPhy, Sop, Ucpd};
+use embassy_stm32::{Peri, bind_interrupts, peripherals};
+use embassy_time::{Duration, Instant, Timer, with_timeout};
+use usbpd::sink::policy_engine::Sink;
+use usbpd_traits::Driver as SinkDriver;
+
+use super::query::{MAX_MESSAGE_LEN, PdPort, QueryLink, SENDER_RESPONSE_TIMEOUT, is_good_crc};
+use super::{
+    ALERT_RECEIVED, BROWNOUT, CONTRACT_ESTABLISHED, CONTROL_GOOD_CRC, CONTROL_REJECT,
+    CRC_ERROR_RESET, CURRENT_ORIENTATION, CableOrientation, CcTermination, Contract, DATA_ROLE_DFP,
+    DEFAULT_TARGET_AVS_CURRENT_MA, DEFAULT_TARGET_AVS_MV, DRIVER_REQUEST, DataRole, Device,
+    DriverRequest, EPR_EXIT_REQUEST, EmbassySinkTimer, GOTO_MIN, HARD_RESET_ORIGIN,
+    HARD_RESET_REQUEST, HARD_RESETS, HardResetOrigin, MEASURED_VBUS_MV, MessageKind, ORIENTATION,
+    QueryError, REJECT_RECEIVED, RENEGOTIATE, RpCurrent, SINK_PDOS, Timing, UcpdConfig,
+    VBUS_REMOVED, VSAFE0V_MAX_MV, WAIT_RECEIVED, advance_preference, current_contract, data_role,
+    publish_contract, ready_driver_request, reset_preferences,
+};
+use crate::alert::Alert;
+use crate::battery::{self, BatteryCapabilities, BatteryStatus, MAX_BATTERIES};
+use crate::cable;
+use crate::caps::{SOURCE_CAPS_EXTENDED, SourceCapsExtended};
+use crate::event::{PdEvent, log_event};
+#[cfg(feature = "pd-trace")]
+use crate::fmt::Bytes;
+#[cfg(any(feature = "cc-trace", feature = "pd-trace"))]
+use crate::fmt::trace;
+use crate::fmt::{debug, info, warn};
+use crate::load;
+use crate::profile::PROFILE_CHANGED;
+use crate::source_status::{self, SourceStatus};
+use crate::stats;
+use crate::status::{PdState, set_pd_state};
+use crate::vbus::VbusMonitor;
+use crate::vconn;
+
+bind_interrupts!(pub(crate) struct Irqs {
+    UCPD1 => ucpd::InterruptHandler<peripherals::UCPD1>;
+});
+
+pub struct UcpdResources {
+    pub ucpd: Peri<'static, peripherals::UCPD1>,
+    pub pin_cc1: Peri<'static, peripherals::PB6>,
+    pub pin_cc2: Peri<'static, peripherals::PB4>,
+    pub rx_dma: Peri<'static, peripherals::DMA1_CH1>,
+    pub tx_dma: Peri<'static, peripherals::DMA1_CH2>,
+}
+
+/// Update the orientation for both `ORIENTATION` waiters and `cable_orientation`.
+fn publish_orientation(orientation: Option<CableOrientation>) {
+    CURRENT_ORIENTATION.lock(|current| current.set(orientation));
+    ORIENTATION.sender().send(orientation);
+}
+
+fn set_data_role(role: DataRole) {
+    DATA_ROLE_DFP.store(role == DataRole::Dfp, Ordering::Relaxed);
+}
+
+/// The UCPD PD phy, retrying discarded transmissions.
+struct UcpdPort<'d> {
+    /// The UCPD PD phy instance.
+    pd_phy: PdPhy<'d, peripherals::UCPD1>,
+    /// Retries of a discarded transmission
+    tx_retries: u8,
+}
+
+impl UcpdPort<'_> {
+    /// Transmit, retrying up to `tx_retries` times while the phy discards the message.
+    ///
+    /// A discarded transmission collided with an incoming message, so the line is busy;
+    /// the retry waits `TX_RETRY_DELAY` for it to clear.
+    async fn transmit_with_retry(&mut self, data: &[u8]) -> Result<(), ucpd::TxError> {
+        trace_message("TX", data);
+        let mut attempt = 0;
+        loop {
+            match self.pd_phy.transmit(data).await {
+                Err(ucpd::TxError::Discarded) if attempt < self.tx_retries => {
+                    attempt += 1;
+                    info!("TX discarded, retry {}/{}", attempt, self.tx_retries);
+                    Timer::after(TX_RETRY_DELAY).await;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+impl PdPort for UcpdPort<'_> {
+    async fn transmit(&mut self, message: &[u8]) -> Result<(), ()> {
+        self.transmit_with_retry(message).await.map_err(|_| ())
+    }
+
+    async fn receive(&mut self, buffer: &mut [u8]) -> Result<usize, ()> {
+        self.pd_phy.receive(buffer).await.map_err(|_| ())
+    }
+}
+
+struct UcpdSinkDriver<'d> {
+    /// The UCPD PD phy
+    port: UcpdPort<'d>,
+    /// MessageIDs of the driver's own messages to the source
+    link: QueryLink,
+    /// MessageID of the next message sent to the cable plug, counted separately for SOP'
+    next_cable_message_id: u8,
+    /// Accept PR_Swap instead of rejecting it
+    dual_role: bool,
+    /// Accept DR_Swap instead of rejecting it
+    accept_dr_swap: bool,
+    /// Orientation of the attachment if VCONN is sourced for SOP' exchanges
+    vconn: Option<CableOrientation>,
+    /// Last full charge capacity of each battery in mWh, for the state of charge
+    last_full_charge_mwh: [Option<u32>; MAX_BATTERIES as usize],
+    /// RX overruns since the last message received intact
+    consecutive_overruns: u8,
+    /// CRC errors since `crc_window_start`
+    crc_errors: u8,
+    /// Start of the window CRC errors are counted in
+    crc_window_start: Instant,
+    /// GotoMin was received, the PS_RDY that follows it is handled by the driver too
+    goto_min_pending: bool,
+}
+
+impl<'d> UcpdSinkDriver<'d> {
+    fn new(
+        pd_phy: PdPhy<'d, peripherals::UCPD1>,
+        config: &UcpdConfig,
+        orientation: CableOrientation,
+    ) -> Self {
+        Self {
+            port: UcpdPort {
+                pd_phy,
+                tx_retries: config.tx_retries,
+            },
+            link: QueryLink::new(),
+            next_cable_message_id: 0,
+            dual_role: config.dual_role,
+            accept_dr_swap: config.accept_dr_swap,
+            vconn: config.vconn_source.then_some(orientation),
+            last_full_charge_mwh: [None; MAX_BATTERIES as usize],
+            consecutive_overruns: 0,
+            crc_errors: 0,
+            crc_window_start: Instant::now(),
+            goto_min_pending: false,
+        }
+    }
+
+    /// Forget the MessageID shift, after the counters were reset by a soft or hard reset.
+    fn reset_message_ids(&mut self) {
+        self.link.reset();
+        self.next_cable_message_id = 0;
+        self.goto_min_pending = false;
+    }
+
+    /// Count a CRC error, returning whether the errors in the current window reached
+    /// the threshold.
+    fn crc_error_burst(&mut self) -> bool {
+        let now = Instant::now();
+        if now - self.crc_window_start > CRC_ERROR_WINDOW {
+            self.crc_window_start = now;
+            self.crc_errors = 0;
+        }
+        self.crc_errors += 1;
+        if self.crc_errors < CRC_ERROR_THRESHOLD {
+            return false;
+        }
+        self.crc_errors = 0;
+        true
+    }
+
+    /// Perform a Soft_Reset handshake outside of the policy engine.
+    ///
+    /// This resets the message counters on both sides, after which the source re-sends
+    /// its capabilities to a freshly started policy engine.
+    async fn soft_reset(&mut self) -> Result<(), ()> {
+        // MessageID is 0 for Soft_Reset
+        self.reset_message_ids();
+        let mut buffer = [0u8; MAX_MESSAGE_LEN];
+        let result = self
+            .link
+            .exchange(
+                &mut self.port,
+                CONTROL_SOFT_RESET,
+                &[],
+                MessageKind::Control,
+                CONTROL_ACCEPT,
+                &mut buffer,
+            )
+            .await;
+        self.reset_message_ids();
+        result.map(|_| ()).map_err(|_| ())
+    }
+
+    /// Answer a PR_Swap or DR_Swap from the source with Accept or Reject.
+    ///
+    /// The policy engine doesn't handle role swaps, so the driver acknowledges the request
+    /// and responds within tReceiverResponse itself. Returns whether the source
+    /// acknowledged the response.
+    async fn respond_swap(&mut self, header: u16, accept: bool) -> bool {
+        if !self.acknowledge(header).await {
+            return false;
+        }
+
+        let response = if accept {
+            CONTROL_ACCEPT
+        } else {
+            CONTROL_REJECT
+        };
+        let Ok(message_id) = self.link.send(&mut self.port, response, &[]).await else {
+            return false;
+        };
+
+        // Wait for the GoodCRC of the response
+        let mut buffer = [0u8; MAX_MESSAGE_LEN];
+        loop {
+            let Ok(Ok(len)) = with_timeout(
+                SENDER_RESPONSE_TIMEOUT,
+                self.port.pd_phy.receive(&mut buffer),
+            )
+            .await
+            else {
+                return false;
+            };
+            if len < 2 {
+                continue;
+            }
+            let header = u16::from_le_bytes([buffer[0], buffer[1]]);
+            if is_good_crc(header) && (header >> 9) & 0x7 == message_id as u16 {
+                return true;
+            }
+            self.link.stash(&buffer[..len]);
+        }
+    }
+
+    /// Ask the cable plug for its identity over SOP', returning its VBUS current rating.
+    ///
+    /// Strictly only the VCONN source talks to the cable plug, but sources keep VCONN on
+    /// for e-marked cables, so the e-marker answers a sink as well. With
+    /// `UcpdConfig::vconn_source` the caller sources VCONN itself for the exchange. `None` if no e-marker
+    /// answered within tSenderResponse.
+    async fn discover_cable_identity(&mut self) -> Option<u32> {
+        let message_id = self.next_cable_message_id;
+        // SOP' headers carry no roles: Cable Plug 0 for messages from a port
+        let header = (1 << 12) | ((message_id as u16) << 9) | (0b10 << 6) | DATA_VENDOR_DEFINED;
+        let mut message = [0u8; 6];
+        message[..2].copy_from_slice(&header.to_le_bytes());
+        message[2..].copy_from_slice(&cable::DISCOVER_IDENTITY_REQUEST.to_le_bytes());
+        self.port
+            .pd_phy
+            .transmit_with_sop(Sop::SopPrime, &message)
+            .await
+            .ok()?;
+
+        let mut buffer = [0u8; MAX_MESSAGE_LEN];
+        let mut acknowledged = false;
+        loop {
+            let (sop, len) = with_timeout(
+                SENDER_RESPONSE_TIMEOUT,
+                self.port.pd_phy.receive_with_sop(&mut buffer),
+            )
+            .await
+            .ok()?
+            .ok()?;
+            if len < 2 {
+                continue;
+            }
+            if sop != Sop::SopPrime {
+                // Not part of this exchange, the policy engine acknowledges and handles it
+                self.link.stash(&buffer[..len]);
+                continue;
+            }
+
+            let header = u16::from_le_bytes([buffer[0], buffer[1]]);
+            if is_good_crc(header) {
+                if (header >> 9) & 0x7 == message_id as u16 {
+                    acknowledged = true;
+                    self.next_cable_message_id = (message_id + 1) & 0x7;
+                }
+                continue;
+            }
+            let object_count = ((header >> 12) & 0x7) as usize;
+            if !acknowledged || header & 0x1F != DATA_VENDOR_DEFINED || object_count == 0 {
+                continue;
+            }
+            let good_crc = (header & (0x7 << 9)) | (0b10 << 6) | CONTROL_GOOD_CRC;
+            let _ = self
+                .port
+                .pd_phy
+                .transmit_with_sop(Sop::SopPrime, &good_crc.to_le_bytes())
+                .await;
+
+            let mut objects = [0u32; 7];
+            for (object, bytes) in objects
+                .iter_mut()
+                .zip(buffer[2..len].chunks_exact(4))
+                .take(object_count)
+            {
+                *object = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+            }
+            return cable::parse_discover_identity(&objects[..object_count]);
+        }
+    }
+
+    /// Send the GoodCRC for a received message the driver handles itself.
+    async fn acknowledge(&mut self, header: u16) -> bool {
+        self.link.acknowledge(&mut self.port, header).await
+    }
+
+    /// Handle GotoMin and the PS_RDY completing it, which the policy engine would ignore.
+    async fn handle_goto_min(&mut self, header: u16) {
+        if !self.acknowledge(header).await {
+            warn!("Failed to acknowledge GotoMin");
+            return;
+        }
+        if header & 0x1F == CONTROL_GOTO_MIN {
+            self.goto_min_pending = true;
+            GOTO_MIN.signal(());
+        } else {
+            self.goto_min_pending = false;
+            info!("Source at reduced power");
+        }
+    }
+
+    /// Handle a role swap request, which the policy engine would otherwise ignore.
+    async fn handle_swap(&mut self, header: u16) {
+        match header & 0x1F {
+            CONTROL_PR_SWAP => {
+                let accepted = self.dual_role;
+                info!(
+                    "PR_Swap requested, {}",
+                    if accepted { "accepting" } else { "rejecting" }
+                );
+                if !self.respond_swap(header, accepted).await {
+                    warn!("Failed to answer PR_Swap");
+                    return;
+                }
+                log_event(PdEvent::PrSwap { accepted });
+            }
+            CONTROL_DR_SWAP => {
+                let accepted = self.accept_dr_swap;
+                info!(
+                    "DR_Swap requested, {}",
+                    if accepted { "accepting" } else { "rejecting" }
+                );
+                if !self.respond_swap(header, accepted).await {
+                    warn!("Failed to answer DR_Swap");
+                    return;
+                }
+                if accepted {
+                    let role = match data_role() {
+                        DataRole::Ufp => DataRole::Dfp,
+                        DataRole::Dfp => DataRole::Ufp,
+                    };
+                    set_data_role(role);
+                    info!("Data role is now {}", role);
+                }
+                log_event(PdEvent::DrSwap { accepted });
+            }
+            _ => {}
+        }
+    }
+
+    /// Carry out a request of the policy that the policy engine has no support for.
+    async fn handle_request(&mut self, request: DriverRequest) {
+        match request {
+            DriverRequest::SourceCapExtended => {
+                let mut buffer = [0u8; MAX_MESSAGE_LEN];
+                let result = self
+                    .link
+                    .request(&mut self.port, request, &mut buffer)
+                    .await;
+                if let Err(error) = result {
+                    log_unanswered("Get_Source_Cap_Extended", error);
+                }
+                let parsed = result.ok().and_then(|len| {
+                    // Extended message header, then the data block
+                    let data_size = (u16::from_le_bytes([buffer[2], buffer[3]]) & 0x1FF) as usize;
+                    let data = buffer.get(4..len)?;
+                    SourceCapsExtended::parse(&data[..data_size.min(data.len())])
+                });
+                match parsed {
+                    Some(info) => {
+                        info!(
+                            "Source {:04x}:{:04x} FW {} HW {}, PDP {}W, EPR PDP {}W",
+                            info.vid,
+                            info.pid,
+                            info.fw_version,
+                            info.hw_version,
+                            info.pdp_watts,
+                            info.epr_pdp_watts
+                        );
+                        SOURCE_CAPS_EXTENDED.signal(info);
+                        // Power banks report their charge
+                        if info.fixed_batteries > 0 {
+                            battery::request_capabilities(0);
+                            battery::request_status(0);
+                        }
+                    }
+                    None if result.is_ok() => {
+                        warn!("Malformed Source_Capabilities_Extended from source, continuing")
+                    }
+                    None => {}
+                }
+            }
+            DriverRequest::BatteryCapabilities { battery } => {
+                let mut buffer = [0u8; MAX_MESSAGE_LEN];
+                let result = self
+                    .link
+                    .request(&mut self.port, request, &mut buffer)
+                    .await;
+                if let Err(error) = result {
+                    log_unanswered("Get_Battery_Cap", error);
+                }
+                let parsed = result.ok().and_then(|len| {
+                    let data_size = (u16::from_le_bytes([buffer[2], buffer[3]]) & 0x1FF) as usize;
+                    let data = buffer.get(4..len)?;
+                    BatteryCapabilities::parse(battery, &data[..data_size.min(data.len())])
+                });
+                match parsed {
+                    Some(capabilities) => {
+                        info!(
+                            "Battery {}: design {}mWh, last full charge {}mWh",
+                            battery,
+                            capabilities.design_capacity_mwh.unwrap_or(0),
+                            capabilities.last_full_charge_mwh.unwrap_or(0)
+                        );
+                        self.last_full_charge_mwh[battery as usize] =
+                            capabilities.last_full_charge_mwh;
+                        log_event(PdEvent::BatteryCapabilities(capabilities));
+                    }
+                    None if result.is_ok() => info!("Battery {} capabilities unsupported", battery),
+                    None => {}
+                }
+            }
+            DriverRequest::BatteryStatus { battery } => {
+                let mut buffer = [0u8; MAX_MESSAGE_LEN];
+                let result = self
+                    .link
+                    .request(&mut self.port, request, &mut buffer)
+                    .await;
+                if let Err(error) = result {
+                    log_unanswered("Get_Battery_Status", error);
+                }
+                let parsed = result.ok().and_then(|len| {
+                    let bsdo = buffer.get(2..6).filter(|_| len >= 6)?;
+                    let bsdo = u32::from_le_bytes([bsdo[0], bsdo[1], bsdo[2], bsdo[3]]);
+                    BatteryStatus::parse(battery, bsdo, self.last_full_charge_mwh[battery as usize])
+                });
+                match parsed {
+                    Some(status) => {
+                        info!(
+                            "Battery {}: {}mWh, {}%, {}",
+                            battery,
+                            status.present_capacity_mwh.unwrap_or(0),
+                            status.soc_percent.unwrap_or(0),
+                            status.charging
+                        );
+                        log_event(PdEvent::BatteryStatus(status));
+                    }
+                    None if result.is_ok() => info!("Battery {} status unsupported", battery),
+                    None => {}
+                }
+            }
+            DriverRequest::Status => {
+                let mut buffer = [0u8; MAX_MESSAGE_LEN];
+                let result = self
+                    .link
+                    .request(&mut self.port, request, &mut buffer)
+                    .await;
+                if let Err(error) = result {
+                    log_unanswered("Get_Status", error);
+                }
+                let parsed = result.ok().and_then(|len| {
+                    let data_size = (u16::from_le_bytes([buffer[2], buffer[3]]) & 0x1FF) as usize;
+                    let data = buffer.get(4..len)?;
+                    SourceStatus::parse(&data[..data_size.min(data.len())])
+                });
+                source_status::set(parsed);
+                match parsed {
+                    Some(status) => {
+                        if status.is_fault() {
+                            warn!("Source reports a fault: {}", status);
+                        }
+                        log_event(PdEvent::SourceStatus(status));
+                    }
+                    None => info!("No source status, not asking again"),
+                }
+            }
+            DriverRequest::DiscoverCableIdentity => {
+                // Power the e-marker for the exchange, it may not answer otherwise, but
+                // never against a VCONN the source drives itself
+                let vconn_off = match self.vconn {
+                    Some(orientation) if opposite_cc_powered(orientation) => {
+                        warn!("CC line of VCONN already powered, not sourcing VCONN");
+                        None
+                    }
+                    Some(orientation) => {
+                        vconn::enable(orientation);
+                        Timer::after(VCONN_STABLE).await;
+                        Some(VconnOff)
+                    }
+                    None => None,
+                };
+                let rating_ma = self.discover_cable_identity().await;
+                drop(vconn_off);
+                let limit_ma = cable::set_max_current_ma(rating_ma);
+                // Re-check the contract against the rating
+                let contract = current_contract();
+                if contract.is_active() {
+                    publish_contract(contract);
+                }
+                match rating_ma {
+                    Some(rating_ma) => info!("E-marked cable rated for {}mA", rating_ma),
+                    None => info!("No e-marker answered, capping to {}mA for safety", limit_ma),
+                }
+                if contract.current_ma > limit_ma {
+                    warn!("Contract exceeds the cable's {}mA, renegotiating", limit_ma);
+                    RENEGOTIATE.signal(());
+                }
+            }
+        }
+    }
+}
+
+/// Control message types handled outside of the policy engine (USB PD 3.2 Table 6.5), see
+/// `power` for GoodCRC, Reject and Not_Supported
+const CONTROL_GOTO_MIN: u16 = 0b0_0010;
+const CONTROL_ACCEPT: u16 = 0b0_0011;
+const CONTROL_PS_RDY: u16 = 0b0_0110;
+const CONTROL_WAIT: u16 = 0b0_1100;
+const CONTROL_DR_SWAP: u16 = 0b0_1001;
+const CONTROL_PR_SWAP: u16 = 0b0_1010;
+const CONTROL_SOFT_RESET: u16 = 0b0_1101;
+/// Data message types handled outside of the policy engine (USB PD 3.2 Table 6.6)
+const DATA_VENDOR_DEFINED: u16 = 0b0_1111;
+/// Pause before retrying a discarded transmission
+const TX_RETRY_DELAY: Duration = Duration::from_micros(500);
+/// tVCONNStable, time for VCONN to settle before talking to the cable plug
+const VCONN_STABLE: Duration = Duration::from_millis(50);
+/// Time the CC pull-downs are removed when restarting attach detection, long enough for
+/// the partner to see a detach (tPDDebounce)
+const ATTACH_RETRY_PULL_OFF: Duration = Duration::from_millis(20);
+/// Time the CC lines must be stable after Rd took over from the dead-battery pull-downs,
+/// instead of tCCDebounce for a source that powered the board at boot
+const DEAD_BATTERY_SETTLE: Duration = Duration::from_millis(10);
+/// RX overruns in a row that are reported to the policy engine instead of re-armed
+const MAX_CONSECUTIVE_OVERRUNS: u8 = 4;
+/// CRC errors within `CRC_ERROR_WINDOW` that have the link soft reset
+const CRC_ERROR_THRESHOLD: u8 = 5;
+/// Window CRC errors are counted in
+const CRC_ERROR_WINDOW: Duration = Duration::from_secs(1);
+
+impl SinkDriver for &mut UcpdSinkDriver<'_> {
+    async fn wait_for_vbus(&self) {
+        // The sink policy engine is only running when attached. Therefore VBus is present.
+    }
+
+    async fn receive(&mut self, buffer: &mut [u8]) -> Result<usize, usbpd_traits::DriverRxError> {
+        loop {
+            // A message stashed during a driver exchange is classified like a fresh one
+            let outcome = match self.link.take_stashed(buffer) {
+                Some(len) => Either3::First(Ok(len)),
+                None => {
+                    select3(
+                        self.port.pd_phy.receive(buffer),
+                        HARD_RESET_REQUEST.wait(),
+                        ready_driver_request(),
+                    )
+                    .await
+                }
+            };
+            let result = match outcome {
+                Either3::First(Ok(len)) if is_swap_request(&buffer[..len]) => {
+                    let header = u16::from_le_bytes([buffer[0], buffer[1]]);
+                    self.handle_swap(header).await;
+                    continue;
+                }
+                Either3::First(Ok(len))
+                    if is_control_message(&buffer[..len], CONTROL_GOTO_MIN)
+                        || (self.goto_min_pending
+                            && is_control_message(&buffer[..len], CONTROL_PS_RDY)) =>
+                {
+                    let header = u16::from_le_bytes([buffer[0], buffer[1]]);
+                    self.handle_goto_min(header).await;
+                    continue;
+                }
+                Either3::First(Err(ucpd::RxError::Overrun)) => {
+                    // The message is lost either way; receive again right away so the
+                    // source's retry finds the phy armed, unless overruns keep coming
+                    self.consecutive_overruns += 1;
+                    stats::record_overrun();
+                    debug!(
+                        "RX overrun, {} in a row, {} total",
+                        self.consecutive_overruns,
+                        stats::Stats::current().overruns
+                    );
+                    if self.consecutive_overruns < MAX_CONSECUTIVE_OVERRUNS {
+                        continue;
+                    }
+                    warn!("Repeated RX overruns, discarding");
+                    self.consecutive_overruns = 0;
+                    return Err(usbpd_traits::DriverRxError::Discarded);
+                }
+                Either3::First(Err(ucpd::RxError::Crc)) => {
+                    // A sign of poor signal integrity, e.g. a long or damaged cable
+                    stats::record_crc_error();
+                    if self.crc_error_burst() {
+                        warn!(
+                            "{} CRC errors within {}ms, soft resetting the link",
+                            CRC_ERROR_THRESHOLD,
+                            CRC_ERROR_WINDOW.as_millis()
+                        );
+                        CRC_ERROR_RESET.signal(());
+                    }
+                    return Err(usbpd_traits::DriverRxError::Discarded);
+                }
+                Either3::First(Ok(len))
+                    if self
+                        .link
+                        .drop_late_answer(&mut self.port, &buffer[..len])
+                        .await =>
+                {
+                    self.consecutive_overruns = 0;
+                    continue;
+                }
+                Either3::First(Ok(len)) => {
+                    self.consecutive_overruns = 0;
+                    forward_alert(&buffer[..len]);
+                    forward_wait(&buffer[..len]);
+                    forward_reject(&buffer[..len]);
+                    self.link.unshift_good_crc(&mut buffer[..len]);
+                    Ok(len)
+                }
+                Either3::First(result) => result,
+                Either3::Second(()) => {
+                    // Report the reset to the policy engine like one sent by the source
+                    warn!("Sending requested hard reset");
+                    note_hard_reset(HardResetOrigin::Sent);
+                    self.reset_message_ids();
+                    let _ = self.port.pd_phy.transmit_hardreset().await;
+                    return Err(usbpd_traits::DriverRxError::HardReset);
+                }
+                Either3::Third(request) => {
+                    self.handle_request(request).await;
+                    continue;
+                }
+            };
+            if let Ok(len) = result {
+                trace_message("RX", &buffer[..len]);
+            }
+            return result.map_err(|err| match err {
+                ucpd::RxError::Crc | ucpd::RxError::Overrun => {
+                    usbpd_traits::DriverRxError::Discarded
+                }
+                ucpd::RxError::HardReset => {
+                    note_hard_reset(HardResetOrigin::Received);
+                    self.reset_message_ids();
+                    usbpd_traits::DriverRxError::HardReset
+                }
+            });
+        }
+    }
+
+    async fn transmit(&mut self, data: &[u8]) -> Result<(), usbpd_traits::DriverTxError> {
+        let map_err = |err| match err {
+            ucpd::TxError::Discarded => usbpd_traits::DriverTxError::Discarded,
+            ucpd::TxError::HardReset => usbpd_traits::DriverTxError::HardReset,
+        };
+        if data.len() < 2 {
+            return self.port.transmit_with_retry(data).await.map_err(map_err);
+        }
+
+        let original = u16::from_le_bytes([data[0], data[1]]);
+        let header = self.link.outgoing_header(original);
+        if header == original || data.len() > MAX_MESSAGE_LEN {
+            return self.port.transmit_with_retry(data).await.map_err(map_err);
+        }
+
+        let mut message = [0u8; MAX_MESSAGE_LEN];
+        message[..data.len()].copy_from_slice(data);
+        message[..2].copy_from_slice(&header.to_le_bytes());
+        self.port
+            .transmit_with_retry(&message[..data.len()])
+            .await
+            .map_err(map_err)
+    }
+
+    async fn transmit_hard_reset(&mut self) -> Result<(), usbpd_traits::DriverTxError> {
+        note_hard_reset(HardResetOrigin::Sent);
+        self.reset_message_ids();
+        self.port
+            .pd_phy
+            .transmit_hardreset()
+            .await
+            .map_err(|err| match err {
+                ucpd::TxError::Discarded => usbpd_traits::DriverTxError::Discarded,
+                ucpd::TxError::HardReset => usbpd_traits::DriverTxError::HardReset,
+            })
+    }
+}
+
+/// Switches VCONN off when dropped, also when the exchange it powers is cancelled.
+struct VconnOff;
+
+impl Drop for VconnOff {
+    fn drop(&mut self) {
+        vconn::disable();
+    }
+}
+
+/// Whether the CC line VCONN would be switched onto with `orientation` carries a
+/// voltage, e.g. the source's own VCONN.
+///
+/// An unpowered line reads as the lowest level, pulled down by our Rd and the Ra of an
+/// e-marked cable. Read from the UCPD directly, the `CcPhy` is busy detecting a detach.
+fn opposite_cc_powered(orientation: CableOrientation) -> bool {
+    let status = embassy_stm32::pac::UCPD1.sr().read();
+    let opposite = match orientation {
+        CableOrientation::Normal => status.typec_vstate_cc2(),
+        CableOrientation::Flipped => status.typec_vstate_cc1(),
+        CableOrientation::DebugAccessoryMode => return true,
+    };
+    opposite != CcVState::LOWEST
+}
+
+/// Whether a message is a PR_Swap or DR_Swap request.
+fn is_swap_request(message: &[u8]) -> bool {
+    if message.len() < 2 {
+        return false;
+    }
+    let header = u16::from_le_bytes([message[0], message[1]]);
+    header & (1 << 15) == 0
+        && (header >> 12) & 0x7 == 0
+        && matches!(header & 0x1F, CONTROL_PR_SWAP | CONTROL_DR_SWAP)
+}
+
+/// Whether a message is the given control message.
+fn is_control_message(message: &[u8], message_type: u16) -> bool {
+    if message.len() < 2 {
+        return false;
+    }
+    let header = u16::from_le_bytes([message[0], message[1]]);
+    header & (1 << 15) == 0 && (header >> 12) & 0x7 == 0 && header & 0x1F == message_type
+}
+
+/// Log why the source didn't answer a query of the driver.
+///
+/// None of the queries are required for the contract, which stays in place either way.
+fn log_unanswered(query: &str, error: QueryError) {
+    match error {
+        QueryError::NotSupported => info!("Source doesn't support {}, continuing", query),
+        QueryError::Rejected => info!("Source rejected {}, continuing", query),
+        QueryError::NoResponse => warn!("No answer to {} from source, continuing", query),
+    }
+}
+
+/// Data message type of Alert (USB PD 3.2 Table 6.6)
+const DATA_ALERT: u16 = 0b0_0110;
+
+/// Forward a received Alert message to the policy.
+fn forward_alert(message: &[u8]) {
+    if message.len() < 6 {
+        return;
+    }
+    let header = u16::from_le_bytes([message[0], message[1]]);
+    let extended = header & (1 << 15) != 0;
+    let object_count = (header >> 12) & 0x7;
+    if !extended && object_count == 1 && header & 0x1F == DATA_ALERT {
+        let ado = u32::from_le_bytes([message[2], message[3], message[4], message[5]]);
+        ALERT_RECEIVED.signal(Alert(ado));
+    }
+}
+
+/// Let the policy know the source answered with Wait, so it repeats its request.
+fn forward_wait(message: &[u8]) {
+    if message.len() < 2 {
+        return;
+    }
+    let header = u16::from_le_bytes([message[0], message[1]]);
+    let is_control = header & (1 << 15) == 0 && (header >> 12) & 0x7 == 0;
+    if is_control && header & 0x1F == CONTROL_WAIT {
+        WAIT_RECEIVED.signal(());
+    }
+}
+
+/// Let the policy fall back to the next preference when the source rejects a request.
+fn forward_reject(message: &[u8]) {
+    if is_control_message(message, CONTROL_REJECT) {
+        log_event(PdEvent::Rejected);
+        REJECT_RECEIVED.signal(advance_preference());
+    }
+}
+
+/// Wait until both CC lines are open, or with `watch_vbus` until the policy reports VBUS
+/// removed, whichever comes first.
+///
+/// VBUS is only watched while the policy holds a contract, as it legitimately drops to
+/// vSafe0V during hard resets and before the first contract of a dead-battery boot.
+async fn wait_detached<T: ucpd::Instance>(cc_phy: &mut CcPhy<'_, T>, watch_vbus: bool) {
+    let cc_open = async {
+        loop {
+            let (cc1, cc2) = cc_phy.vstate();
+            trace_vstate(cc1, cc2);
+            if cc1 == CcVState::LOWEST && cc2 == CcVState::LOWEST {
+                return;
+            }
+            cc_phy.wait_for_vstate_change().await;
+        }
+    };
+    let vbus_removed = async {
+        if !watch_vbus {
+            core::future::pending::<()>().await;
+        }
+        let vbus_mv = VBUS_REMOVED.wait().await;
+        info!(
+            "Detach on VBUS removal ({}mV), ahead of the CC lines",
+            vbus_mv
+        );
+    };
+    select(cc_open, vbus_removed).await;
+
+    // Disconnect downstream before anything else reacts to the detach
+    load::disable();
+    publish_orientation(None);
+}
+
+// Waits for a source to attach and returns the orientation of the cable.
+//
+// Also used for a source that is already attached at boot (dead-battery operation): the
+// current vstate is checked before waiting for any change, so only the debounce period
+// applies, which `ucpd_task` shortens to `DEAD_BATTERY_SETTLE` then.
+async fn wait_attached<T: ucpd::Instance>(
+    cc_phy: &mut CcPhy<'_, T>,
+    timing: &Timing,
+    pull: CcPull,
+) -> CableOrientation {
+    // Start of the current CC activity that hasn't settled into an attach yet
+    let mut activity_since: Option<Instant> = None;
+    loop {
+        let (cc1, cc2) = cc_phy.vstate();
+        trace_vstate(cc1, cc2);
+        if cc1 == CcVState::LOWEST && cc2 == CcVState::LOWEST {
+            // Detached, wait until attached by monitoring the CC lines.
+            activity_since = None;
+            cc_phy.wait_for_vstate_change().await;
+            continue;
+        }
+
+        // Odd adapters can keep the CC lines bouncing forever, start over with fresh Rd
+        let since = *activity_since.get_or_insert_with(Instant::now);
+        if since.elapsed() >= timing.attach_timeout {
+            warn!(
+                "No stable attach within {}ms, resetting the CC pull-downs",
+                timing.attach_timeout.as_millis()
+            );
+            cc_phy.set_pull(CcPull::Disabled);
+            Timer::after(ATTACH_RETRY_PULL_OFF).await;
+            cc_phy.set_pull(pull);
+            activity_since = None;
+            continue;
+        }
+
+        // Attached, wait for CC lines to be stable for tCCDebounce (100..200ms).
+        if with_timeout(timing.cc_debounce, cc_phy.wait_for_vstate_change())
+            .await
+            .is_ok()
+        {
+            // State has changed, restart detection procedure.
+            continue;
+        };
+
+        // State was stable for the complete debounce period, check orientation.
+        let orientation = match (cc1, cc2) {
+            (_, CcVState::LOWEST) => CableOrientation::Normal, // CC1 connected
+            (CcVState::LOWEST, _) => CableOrientation::Flipped, // CC2 connected
+            _ => CableOrientation::DebugAccessoryMode,         // Both connected (special cable)
+        };
+        #[cfg(feature = "cc-trace")]
+        trace!("CC lines stable, orientation {}", orientation);
+        return orientation;
+    }
+}
+
+/// Current advertised by the Rp of an attached source, from the voltage it sets on CC.
+fn rp_current(vstate: CcVState) -> RpCurrent {
+    match vstate {
+        CcVState::HIGHEST => RpCurrent::Current3A0,
+        CcVState::HIGH => RpCurrent::Current1A5,
+        _ => RpCurrent::Default,
+    }
+}
+
+/// Log the CC line states with the `cc-trace` feature.
+fn trace_vstate(cc1: CcVState, cc2: CcVState) {
+    #[cfg(feature = "cc-trace")]
+    trace!("CC vstate: cc1 {}, cc2 {}", cc1, cc2);
+    #[cfg(not(feature = "cc-trace"))]
+    let _ = (cc1, cc2);
+}
+
+/// Hexdump a PD message, header and data objects, with the `pd-trace` feature.
+fn trace_message(direction: &str, message: &[u8]) {
+    #[cfg(feature = "pd-trace")]
+    trace!("PD {}: {}", direction, Bytes(message));
+    #[cfg(not(feature = "pd-trace"))]
+    let _ = (direction, message);
+}
+
+/// Put everything downstream into its detached state before attach detection restarts.
+///
+/// The load is already off from `wait_detached`; it is switched off again here so the
+/// teardown doesn't depend on how the detach was noticed.
+fn enter_detached_state() {
+    load::disable();
+    vconn::disable();
+    publish_orientation(None);
+    publish_contract(Contract::NONE);
+    HARD_RESETS.store(0, Ordering::Relaxed);
+    set_pd_state(PdState::WaitingForAttach);
+    info!("Detached: load off, contract cleared");
+    log_event(PdEvent::Detached);
+    stats::log_summary();
+}
+
+/// Backoff before re-initializing after repeated hard resets, the last entry is the cap
+const HARD_RESET_BACKOFF_MS: [u64; 4] = [100, 500, 2_000, 5_000];
+
+/// tPSHardReset (max), from a hard reset until the source starts the transition to vSafe0V
+const PS_HARD_RESET: Duration = Duration::from_millis(35);
+/// tSafe0V (max) plus tSrcRecover (max) plus tSrcTurnOn (max): VBUS down to vSafe0V, held
+/// there, and back up to vSafe5V
+const SRC_RECOVER: Duration = Duration::from_millis(650 + 1_000 + 275);
+
+/// Time for VBUS to return after a hard reset, before attach detection restarts.
+///
+/// The Rp stays on the CC line throughout, so without waiting the VBUS dip is taken for a
+/// fresh attach. The source's tPSHardReset starts once it sent its own hard reset, which we
+/// only see after the fact, while for ours it starts once the source received it.
+fn hard_reset_recovery(origin: HardResetOrigin) -> Duration {
+    match origin {
+        HardResetOrigin::Sent => PS_HARD_RESET + SRC_RECOVER,
+        HardResetOrigin::Received => SRC_RECOVER,
+    }
+}
+
+/// Record a hard reset sent or received.
+fn note_hard_reset(origin: HardResetOrigin) {
+    // VBUS goes to vSafe0V during a hard reset
+    load::disable();
+    set_pd_state(PdState::Fault);
+    match origin {
+        HardResetOrigin::Sent => warn!("Hard reset sent to the source"),
+        HardResetOrigin::Received => warn!("Hard reset received from the source"),
+    }
+    HARD_RESET_ORIGIN.signal(origin);
+    log_event(PdEvent::HardReset(origin));
+    stats::record_hard_reset();
+    set_data_role(DataRole::Ufp);
+    HARD_RESETS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Handle USB PD negotiation.
+///
+/// `on_transition` is called with every contract the source confirms.
+#[embassy_executor::task]
+pub async fn ucpd_task(
+    mut ucpd_resources: UcpdResources,
+    mut vbus: VbusMonitor,
+    load_enable: Output<'static>,
+    config: UcpdConfig,
+    mut on_transition: Option<fn(&Contract)>,
+) {
+    load::install(load_enable);
+    let config = config.validated();
+    info!("CC termination: {}", config.cc_termination);
+    let cc_pull = match config.cc_termination {
+        CcTermination::Open => CcPull::Disabled,
+        // Rp was replaced by Rd in `validated`
+        CcTermination::Rd | CcTermination::Rp(_) => CcPull::Sink,
+    };
+
+    // With dead-battery support the MCU may have been powered by the source through the
+    // dead-battery Rd pull-downs, so a source can already be attached on the first pass.
+    let mut booted_attached = cfg!(feature = "dead-battery");
+
+    loop {
+        // An exchange with the cable plug may have been cut short by the last session
+        vconn::disable();
+        let mut ucpd = Ucpd::new(
+            ucpd_resources.ucpd.reborrow(),
+            Irqs {},
+            ucpd_resources.pin_cc1.reborrow(),
+            ucpd_resources.pin_cc2.reborrow(),
+            // SOP' for the responses of the cable's e-marker
+            ucpd::Config {
+                sop_prime_enable: true,
+                ..Default::default()
+            },
+        );
+
+        // Taking over from the dead-battery pull-downs with Rd keeps the source attached
+        ucpd.cc_phy().set_pull(cc_pull);
+
+        // A source powering the board since boot has been attached for longer than
+        // tCCDebounce already, the CC lines only need to settle after the handover
+        let timing = if booted_attached && vbus.read_mv() > VSAFE0V_MAX_MV {
+            info!("Dead-battery boot with VBUS present, skipping tCCDebounce");
+            Timing {
+                cc_debounce: DEAD_BATTERY_SETTLE,
+                ..config.timing
+            }
+        } else {
+            info!("Waiting for USB connection");
+            config.timing
+        };
+        booted_attached = false;
+        let cable_orientation = wait_attached(ucpd.cc_phy(), &timing, cc_pull).await;
+        log_event(PdEvent::Attached(cable_orientation));
+        stats::record_attach();
+        publish_orientation(Some(cable_orientation));
+
+        let cc_sel = match cable_orientation {
+            CableOrientation::Normal => {
+                info!("Starting PD communication on CC1 pin");
+                CcSel::CC1
+            }
+            CableOrientation::Flipped => {
+                info!("Starting PD communication on CC2 pin");
+                CcSel::CC2
+            }
+            CableOrientation::DebugAccessoryMode => {
+                // No PD communication in DAM, wait for the accessory to be removed
+                warn!("Debug accessory attached, no PD communication");
+                set_pd_state(PdState::DebugAccessory);
+                wait_detached(ucpd.cc_phy(), false).await;
+                enter_detached_state();
+                continue;
+            }
+        };
+        set_pd_state(PdState::Negotiating);
+        let (mut cc_phy, pd_phy) = ucpd.split_pd_phy(
+            ucpd_resources.rx_dma.reborrow(),
+            ucpd_resources.tx_dma.reborrow(),
+            cc_sel,
+        );
+
+        // Profile changes while detached are picked up by the initial request
+        PROFILE_CHANGED.reset();
+        HARD_RESET_REQUEST.reset();
+        HARD_RESET_ORIGIN.reset();
+        ALERT_RECEIVED.reset();
+        EPR_EXIT_REQUEST.reset();
+        RENEGOTIATE.reset();
+        DRIVER_REQUEST.clear();
+        BROWNOUT.reset();
+        WAIT_RECEIVED.reset();
+        GOTO_MIN.reset();
+        CRC_ERROR_RESET.reset();
+        REJECT_RECEIVED.reset();
+        reset_preferences();
+        VBUS_REMOVED.reset();
+        MEASURED_VBUS_MV.store(0, Ordering::Relaxed);
+        cable::reset();
+        source_status::reset();
+        set_data_role(DataRole::Ufp);
+
+        let mut driver = UcpdSinkDriver::new(pd_phy, &config, cable_orientation);
+        let hard_resets_at_attach = HARD_RESETS.load(Ordering::Relaxed);
+
+        // Policy engine sessions on this attachment, restarted after a soft reset
+        loop {
+            let mut device = attached_device(&mut vbus, &config);
+            if let Some(handler) = on_transition.as_mut() {
+                device = device.with_transition_handler(handler);
+            }
+            let mut sink: Sink<&mut UcpdSinkDriver<'_>, EmbassySinkTimer, _> =
+                Sink::new(&mut driver, device);
+            CONTRACT_ESTABLISHED.reset();
+            info!("Run sink");
+
+            let result = match select4(
+                sink.run(),
+                wait_detached(&mut cc_phy, true),
+                negotiation_watchdog(config.timing.negotiation_timeout),
+                select(BROWNOUT.wait(), CRC_ERROR_RESET.wait()),
+            )
+            .await
+            {
+                Either4::First(result) => result,
+                Either4::Second(_) => {
+                    drop(sink);
+                    enter_detached_state();
+                    break;
+                }
+                Either4::Third(_) if stats::awaiting_caps() => {
+                    // Rp without a single Source_Capabilities, a USB or BC 1.2 style charger
+                    drop(sink);
+                    let (cc1, cc2) = cc_phy.vstate();
+                    let rp = match cable_orientation {
+                        CableOrientation::Flipped => cc2,
+                        _ => cc1,
+                    };
+                    let current_ma = rp_current(rp).current_ma();
+                    info!(
+                        "No PD communication within {}ms, legacy source offering {}mA at 5V",
+                        config.timing.negotiation_timeout.as_millis(),
+                        current_ma
+                    );
+                    log_event(PdEvent::LegacySource { current_ma });
+                    if config.min_voltage_mv > 5_000 {
+                        set_pd_state(PdState::Fault);
+                    } else if current_ma < config.min_current_ma {
+                        warn!(
+                            "Legacy source offers {}mA, below the {}mA the load needs, keeping the load off",
+                            current_ma, config.min_current_ma
+                        );
+                        set_pd_state(PdState::Fault);
+                        load::disable();
+                    } else if config.dry_run {
+                        info!("Dry run: legacy source, keeping the load off");
+                        set_pd_state(PdState::Contract);
+                        load::disable();
+                    } else {
+                        set_pd_state(PdState::Contract);
+                        load::enable();
+                    }
+                    wait_detached(&mut cc_phy, false).await;
+                    enter_detached_state();
+                    break;
+                }
+                Either4::Third(_) => {
+                    warn!(
+                        "Negotiation timeout, no contract within {}ms",
+                        config.timing.negotiation_timeout.as_millis()
+                    );
+                    set_pd_state(PdState::Fault);
+                    load::disable();
+                    break;
+                }
+                Either4::Fourth(Either::Second(())) => {
+                    drop(sink);
+                    if driver.soft_reset().await.is_ok() {
+                        info!("Soft reset accepted, restarting policy engine");
+                        set_pd_state(PdState::Negotiating);
+                        continue;
+                    }
+                    warn!("Soft reset after CRC errors failed");
+                    set_pd_state(PdState::Fault);
+                    load::disable();
+                    break;
+                }
+                Either4::Fourth(Either::First(vbus_mv)) => {
+                    // Stay in the fault state until the source is unplugged
+                    drop(sink);
+                    load::disable();
+                    set_pd_state(PdState::Fault);
+                    log_event(PdEvent::Brownout { vbus_mv });
+                    publish_contract(Contract::NONE);
+                    wait_detached(&mut cc_phy, false).await;
+                    enter_detached_state();
+                    break;
+                }
+            };
+            warn!("Sink loop broken with result: {}", result);
+            drop(sink);
+
+            // A rejected preference is retried with the next one, unless none is left
+            let exhausted = REJECT_RECEIVED.try_take() == Some(false);
+            if exhausted {
+                warn!("Source rejected the request with no preference left, giving up");
+            }
+
+            // Protocol errors without a hard reset are recovered on the same attachment
+            let hard_resets = HARD_RESETS.load(Ordering::Relaxed);
+            if !exhausted
+                && hard_resets == hard_resets_at_attach
+                && driver.soft_reset().await.is_ok()
+            {
+                info!("Soft reset accepted, restarting policy engine");
+                set_pd_state(PdState::Negotiating);
+                continue;
+            }
+            set_pd_state(PdState::Fault);
+            load::disable();
+
+            // Let VBUS come back first, the source's Rp never went away
+            if let Some(origin) = HARD_RESET_ORIGIN.try_take() {
+                let recovery = hard_reset_recovery(origin);
+                info!(
+                    "Hard reset {}, waiting {}ms for VBUS to recover",
+                    match origin {
+                        HardResetOrigin::Sent => "sent by us",
+                        HardResetOrigin::Received => "received from the source",
+                    },
+                    recovery.as_millis()
+                );
+                Timer::after(recovery).await;
+            }
+
+            // Back off before retrying when the source keeps resetting
+            if hard_resets > 0 {
+                let hard_resets = hard_resets as usize;
+                let backoff_ms =
+                    HARD_RESET_BACKOFF_MS[(hard_resets - 1).min(HARD_RESET_BACKOFF_MS.len() - 1)];
+                warn!("{} hard resets, retrying in {}ms", hard_resets, backoff_ms);
+                Timer::after_millis(backoff_ms).await;
+            }
+            break;
+        }
+    }
+}
+
+/// Completes if no contract is established within `timeout`, otherwise never.
+async fn negotiation_watchdog(timeout: Duration) {
+    if with_timeout(timeout, CONTRACT_ESTABLISHED.wait())
+        .await
+        .is_ok()
+    {
+        core::future::pending::<()>().await;
+    }
+}
+
+/// Create the policy for a new attachment from the current targets.
+fn attached_device<'a>(vbus: &'a mut VbusMonitor, config: &UcpdConfig) -> Device<'a> {
+    let mut device = Device::new(
+        DEFAULT_TARGET_AVS_MV,
+        DEFAULT_TARGET_AVS_CURRENT_MA,
+        config.operational_pdp_watts,
+        vbus,
+        &SINK_PDOS,
+    )
+    .with_epr_enabled(config.epr_enabled)
+    .with_post_epr_entry_delay(config.timing.post_epr_entry_delay_ms)
+    .with_min_voltage(config.min_voltage_mv)
+    .with_safe_5v_first(config.safe_5v_first)
+    .with_no_usb_suspend(config.no_usb_suspend)
+    .with_usb_communications_capable(config.usb_communications_capable)
+    .with_dry_run(config.dry_run, config.dry_run_safe_5v)
+    .with_upgrade_on_caps_change(config.upgrade_on_caps_change)
+    .with_accept_first(config.accept_first)
+    .with_global_max_current(config.global_max_current_ma)
+    .with_prefer_epr_fixed(config.prefer_epr_fixed)
+    .with_ramp(config.ramp, config.timing.ramp_settle_ms);
+    device.load_targets();
+    debug!(
+        "Sink capabilities: {:08x}",
+        device.sink_capabilities().as_slice()
+    );
+    device
+}
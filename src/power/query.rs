@@ -0,0 +1,351 @@
+//! Messages the driver exchanges with the source itself, for requests the policy engine has
+//! no support for. Generic over the transport, so the host tests run the exchanges against
+//! the simulated source.
+use embassy_time::{Duration, with_timeout};
+
+use super::{
+    CONTROL_GOOD_CRC, DataRole, DriverRequest, MessageKind, QueryError, QueryReply, data_role,
+};
+use crate::fmt::debug;
+
+/// Longest message handled by the driver: header, extended header and one 26 byte chunk
+pub(crate) const MAX_MESSAGE_LEN: usize = 30;
+
+/// Control message types of the queries (USB PD 3.2 Table 6.5)
+const CONTROL_GET_SOURCE_CAP_EXTENDED: u16 = 0b1_0001;
+const CONTROL_GET_STATUS: u16 = 0b1_0010;
+/// Data message types answering the queries (USB PD 3.2 Table 6.6)
+const DATA_BATTERY_STATUS: u16 = 0b0_0101;
+/// Extended message types of the queries and their answers (USB PD 3.2 Table 6.53)
+const EXTENDED_SOURCE_CAPABILITIES_EXTENDED: u16 = 0b0_0001;
+const EXTENDED_STATUS: u16 = 0b0_0010;
+const EXTENDED_GET_BATTERY_CAP: u16 = 0b0_0011;
+const EXTENDED_GET_BATTERY_STATUS: u16 = 0b0_0100;
+const EXTENDED_BATTERY_CAPABILITIES: u16 = 0b0_0101;
+/// Extended bit of the message header
+const HEADER_EXTENDED: u16 = 1 << 15;
+/// Header bits of messages we send: Sink, UFP, revision 3.0
+pub(crate) const SINK_HEADER_FLAGS: u16 = 0b10 << 6;
+/// Port Data Role bit of the message header
+pub(crate) const HEADER_DATA_ROLE_DFP: u16 = 1 << 5;
+/// tSenderResponse
+pub(crate) const SENDER_RESPONSE_TIMEOUT: Duration = Duration::from_millis(30);
+
+/// Transport of the messages the driver sends and receives itself.
+pub(crate) trait PdPort {
+    /// Transmit a message, `Err` if it wasn't sent.
+    async fn transmit(&mut self, message: &[u8]) -> Result<(), ()>;
+    /// Receive the next message into `buffer`, returning its length.
+    async fn receive(&mut self, buffer: &mut [u8]) -> Result<usize, ()>;
+}
+
+/// Header bits of messages we send, with the current data role.
+pub(crate) fn header_flags() -> u16 {
+    match data_role() {
+        DataRole::Ufp => SINK_HEADER_FLAGS,
+        DataRole::Dfp => SINK_HEADER_FLAGS | HEADER_DATA_ROLE_DFP,
+    }
+}
+
+/// Whether a header is the one of a GoodCRC message.
+pub(crate) fn is_good_crc(header: u16) -> bool {
+    MessageKind::of(header) == MessageKind::Control && header & 0x1F == CONTROL_GOOD_CRC
+}
+
+/// Payload of Get_Battery_Cap and Get_Battery_Status: the extended message header of a
+/// single chunk with one byte of data, the battery reference, padded to a data object.
+fn battery_reference(battery: u8) -> [u8; 4] {
+    let extended_header: u16 = (1 << 15) | 1;
+    let [low, high] = extended_header.to_le_bytes();
+    [low, high, battery, 0]
+}
+
+/// Message sent for a request and the answer it expects.
+struct Query {
+    message_type: u16,
+    payload: [u8; 4],
+    payload_len: usize,
+    answer_kind: MessageKind,
+    answer_type: u16,
+}
+
+impl DriverRequest {
+    /// The query carrying out the request, `None` for the exchange with the cable plug,
+    /// which goes over SOP'.
+    fn query(self) -> Option<Query> {
+        let query = match self {
+            DriverRequest::SourceCapExtended => Query {
+                message_type: CONTROL_GET_SOURCE_CAP_EXTENDED,
+                payload: [0; 4],
+                payload_len: 0,
+                answer_kind: MessageKind::Extended,
+                answer_type: EXTENDED_SOURCE_CAPABILITIES_EXTENDED,
+            },
+            DriverRequest::BatteryCapabilities { battery } => Query {
+                message_type: HEADER_EXTENDED | EXTENDED_GET_BATTERY_CAP,
+                payload: battery_reference(battery),
+                payload_len: 4,
+                answer_kind: MessageKind::Extended,
+                answer_type: EXTENDED_BATTERY_CAPABILITIES,
+            },
+            DriverRequest::BatteryStatus { battery } => Query {
+                message_type: HEADER_EXTENDED | EXTENDED_GET_BATTERY_STATUS,
+                payload: battery_reference(battery),
+                payload_len: 4,
+                answer_kind: MessageKind::Data,
+                answer_type: DATA_BATTERY_STATUS,
+            },
+            DriverRequest::Status => Query {
+                message_type: CONTROL_GET_STATUS,
+                payload: [0; 4],
+                payload_len: 0,
+                answer_kind: MessageKind::Extended,
+                answer_type: EXTENDED_STATUS,
+            },
+            DriverRequest::DiscoverCableIdentity => return None,
+        };
+        Some(query)
+    }
+}
+
+/// A message the driver sent outside of the policy engine, waiting for its answer.
+#[derive(Clone, Copy)]
+struct PendingQuery {
+    message_id: u8,
+    answer_kind: MessageKind,
+    answer_type: u16,
+}
+
+/// MessageID bookkeeping of the driver's own messages, interleaved with the policy
+/// engine's.
+pub(crate) struct QueryLink {
+    /// Messages sent by the driver itself since the message counters were last reset.
+    ///
+    /// The MessageIDs of the policy engine's messages are shifted by this much, so they
+    /// stay unique towards the source, and shifted back in the GoodCRCs it receives.
+    id_offset: u8,
+    /// MessageID of the next message sent to the source
+    next_message_id: u8,
+    /// A message for the policy engine that arrived during a driver exchange
+    stashed: Option<([u8; MAX_MESSAGE_LEN], usize)>,
+    /// The driver exchange waiting for its answer, left set if it was cancelled
+    pending_query: Option<PendingQuery>,
+}
+
+impl QueryLink {
+    pub(crate) const fn new() -> Self {
+        Self {
+            id_offset: 0,
+            next_message_id: 0,
+            stashed: None,
+            pending_query: None,
+        }
+    }
+
+    /// Forget the MessageID shift, after the counters were reset by a soft or hard reset.
+    pub(crate) fn reset(&mut self) {
+        *self = Self::new();
+    }
+
+    /// Carry out `request`, returning the length of the answer in `response`.
+    ///
+    /// `Err(QueryError::NoResponse)` for requests that aren't a query of the source.
+    pub(crate) async fn request<P: PdPort>(
+        &mut self,
+        port: &mut P,
+        request: DriverRequest,
+        response: &mut [u8],
+    ) -> Result<usize, QueryError> {
+        let query = request.query().ok_or(QueryError::NoResponse)?;
+        self.exchange(
+            port,
+            query.message_type,
+            &query.payload[..query.payload_len],
+            query.answer_kind,
+            query.answer_type,
+            response,
+        )
+        .await
+    }
+
+    /// Send a message outside of the policy engine and wait for the response.
+    ///
+    /// The response must be of the given message type and kind. Other messages arriving
+    /// in the meantime are stashed for the policy engine. Returns the length of the
+    /// response in `response`, or why there is none, e.g. Not_Supported from a source that
+    /// doesn't implement the message.
+    ///
+    /// Safe to cancel: the MessageIDs are counted before anything is sent, and an answer
+    /// arriving after the exchange was dropped is acknowledged and dropped by
+    /// `drop_late_answer`.
+    pub(crate) async fn exchange<P: PdPort>(
+        &mut self,
+        port: &mut P,
+        message_type: u16,
+        payload: &[u8],
+        response_kind: MessageKind,
+        response_type: u16,
+        response: &mut [u8],
+    ) -> Result<usize, QueryError> {
+        self.pending_query = Some(PendingQuery {
+            message_id: self.next_message_id,
+            answer_kind: response_kind,
+            answer_type: response_type,
+        });
+        let result = self
+            .await_answer(
+                port,
+                message_type,
+                payload,
+                response_kind,
+                response_type,
+                response,
+            )
+            .await;
+        self.pending_query = None;
+        result
+    }
+
+    /// Send a message and wait for the answer, the body of `exchange`.
+    async fn await_answer<P: PdPort>(
+        &mut self,
+        port: &mut P,
+        message_type: u16,
+        payload: &[u8],
+        response_kind: MessageKind,
+        response_type: u16,
+        response: &mut [u8],
+    ) -> Result<usize, QueryError> {
+        let message_id = self
+            .send(port, message_type, payload)
+            .await
+            .map_err(|_| QueryError::NoResponse)?;
+
+        let mut acknowledged = false;
+        loop {
+            let len = with_timeout(SENDER_RESPONSE_TIMEOUT, port.receive(response))
+                .await
+                .map_err(|_| QueryError::NoResponse)?
+                .map_err(|_| QueryError::NoResponse)?;
+            if len < 2 {
+                continue;
+            }
+
+            let header = u16::from_le_bytes([response[0], response[1]]);
+            let reply = QueryReply::classify(header, response_kind, response_type);
+            match reply {
+                QueryReply::GoodCrc { message_id: acked } => acknowledged |= acked == message_id,
+                QueryReply::Answer | QueryReply::Refused(_) if acknowledged => {
+                    if !self.acknowledge(port, header).await {
+                        return Err(QueryError::NoResponse);
+                    }
+                    return match reply {
+                        QueryReply::Refused(error) => Err(error),
+                        _ => Ok(len),
+                    };
+                }
+                // Not part of this exchange, the policy engine acknowledges and handles it
+                _ => self.stash(&response[..len]),
+            }
+        }
+    }
+
+    /// Transmit a message outside of the policy engine, returning its MessageID.
+    pub(crate) async fn send<P: PdPort>(
+        &mut self,
+        port: &mut P,
+        message_type: u16,
+        payload: &[u8],
+    ) -> Result<u8, ()> {
+        let message_id = self.next_message_id;
+        let object_count = payload.len().div_ceil(4) as u16;
+        let header =
+            header_flags() | (object_count << 12) | ((message_id as u16) << 9) | message_type;
+        let mut message = [0u8; MAX_MESSAGE_LEN];
+        message[..2].copy_from_slice(&header.to_le_bytes());
+        message[2..2 + payload.len()].copy_from_slice(payload);
+        // Counted before transmitting: a MessageID skipped by a failed or cancelled
+        // transmission is harmless, a repeated one has the source drop the policy
+        // engine's next message as a retry
+        self.next_message_id = (message_id + 1) & 0x7;
+        self.id_offset = (self.id_offset + 1) & 0x7;
+        port.transmit(&message[..2 + payload.len()]).await?;
+        Ok(message_id)
+    }
+
+    /// Send the GoodCRC for a received message the driver handles itself.
+    pub(crate) async fn acknowledge<P: PdPort>(&mut self, port: &mut P, header: u16) -> bool {
+        let good_crc = header_flags() | (header & (0x7 << 9)) | CONTROL_GOOD_CRC;
+        port.transmit(&good_crc.to_le_bytes()).await.is_ok()
+    }
+
+    /// Acknowledge and drop the answer to a cancelled exchange, returning whether
+    /// `message` was part of it.
+    pub(crate) async fn drop_late_answer<P: PdPort>(
+        &mut self,
+        port: &mut P,
+        message: &[u8],
+    ) -> bool {
+        let Some(query) = self.pending_query else {
+            return false;
+        };
+        if message.len() < 2 {
+            return false;
+        }
+        let header = u16::from_le_bytes([message[0], message[1]]);
+        match QueryReply::classify(header, query.answer_kind, query.answer_type) {
+            QueryReply::GoodCrc { message_id } => message_id == query.message_id,
+            QueryReply::Answer | QueryReply::Refused(_) => {
+                debug!("Dropping the answer to a cancelled query");
+                self.pending_query = None;
+                let _ = self.acknowledge(port, header).await;
+                true
+            }
+            QueryReply::Unrelated => {
+                self.pending_query = None;
+                false
+            }
+        }
+    }
+
+    /// Keep a message for the policy engine, if the slot is free.
+    pub(crate) fn stash(&mut self, message: &[u8]) {
+        if self.stashed.is_none() && message.len() <= MAX_MESSAGE_LEN {
+            let mut stashed = [0u8; MAX_MESSAGE_LEN];
+            stashed[..message.len()].copy_from_slice(message);
+            self.stashed = Some((stashed, message.len()));
+        }
+    }
+
+    /// Move a stashed message into `buffer`, returning its length.
+    pub(crate) fn take_stashed(&mut self, buffer: &mut [u8]) -> Option<usize> {
+        let (message, len) = self.stashed.take()?;
+        buffer[..len].copy_from_slice(&message[..len]);
+        Some(len)
+    }
+
+    /// Header of a message of the policy engine as sent to the source.
+    ///
+    /// The policy engine always sends as UFP, and its MessageIDs are shifted past the
+    /// messages the driver sent itself.
+    pub(crate) fn outgoing_header(&mut self, original: u16) -> u16 {
+        let header = (original & !HEADER_DATA_ROLE_DFP) | header_flags();
+        if is_good_crc(original) {
+            return header;
+        }
+        let message_id = (((original >> 9) as u8) + self.id_offset) & 0x7;
+        self.next_message_id = (message_id + 1) & 0x7;
+        (header & !(0x7 << 9)) | ((message_id as u16) << 9)
+    }
+
+    /// Undo the MessageID shift in a GoodCRC for the policy engine.
+    pub(crate) fn unshift_good_crc(&self, message: &mut [u8]) {
+        let header = u16::from_le_bytes([message[0], message[1]]);
+        if self.id_offset == 0 || !is_good_crc(header) {
+            return;
+        }
+        let message_id = (((header >> 9) as u8).wrapping_sub(self.id_offset)) & 0x7;
+        let header = (header & !(0x7 << 9)) | ((message_id as u16) << 9);
+        message[..2].copy_from_slice(&header.to_le_bytes());
+    }
+}
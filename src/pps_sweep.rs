@@ -0,0 +1,118 @@
+//! PPS test generator: one voltage, a sequence of current limits.
+//!
+//! Characterizes the CV/CC behaviour of a source by stepping the PPS operating current at a
+//! fixed voltage and logging what was requested against what the source granted and what
+//! was measured on VBUS. Each step goes through the policy as a renegotiation, which keeps
+//! refreshing the PPS contract every 8s during long dwells.
+use embassy_time::{Duration, Timer};
+
+use crate::fmt::{info, warn};
+use crate::power::{self, Contract, RENEGOTIATE};
+
+/// Voltage range of SPR PPS in mV
+pub const PPS_MIN_MV: u32 = 3_300;
+pub const PPS_MAX_MV: u32 = 21_000;
+
+/// Shortest dwell, enough for the renegotiation and PS_RDY before the reading
+pub const MIN_DWELL_MS: u32 = 500;
+
+/// One current limit of a sweep.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct SweepStep {
+    /// Operating current to request in mA, rounded down to 50mA
+    pub current_ma: u32,
+    /// Time to hold the step before reading, at least `MIN_DWELL_MS`
+    pub dwell_ms: u32,
+}
+
+/// What a step got.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct StepResult {
+    /// The step as requested
+    pub step: SweepStep,
+    /// The contract in place at the end of the dwell
+    pub contract: Contract,
+    /// VBUS at the end of the dwell in mV, `None` if not measured
+    pub measured_mv: Option<u32>,
+    /// Current drawn at the end of the dwell in mA, `None` without the `ina226` feature
+    pub measured_ma: Option<i32>,
+}
+
+impl StepResult {
+    /// Whether the source granted the step as requested.
+    pub fn granted(&self, voltage_mv: u32) -> bool {
+        self.contract.voltage_mv == voltage_mv
+            && self.contract.current_ma == self.step.current_ma / 50 * 50
+    }
+}
+
+/// Step through `steps` with SPR PPS at `voltage_mv`, logging each of them.
+///
+/// `voltage_mv` must be within the SPR PPS range, the policy clamps it to the PDO's. The PPS
+/// target stays at the last step when done, `power::set_pps_target` with
+/// `preferred = false` goes back to the default policy. Steps taken while detached are
+/// logged as not granted.
+pub async fn pps_sweep(voltage_mv: u32, steps: &[SweepStep]) {
+    if !(PPS_MIN_MV..=PPS_MAX_MV).contains(&voltage_mv) {
+        warn!(
+            "PPS sweep at {}mV outside {}-{}mV, not started",
+            voltage_mv, PPS_MIN_MV, PPS_MAX_MV
+        );
+        return;
+    }
+    info!("PPS sweep at {}mV, {} steps", voltage_mv, steps.len());
+    for (index, &step) in steps.iter().enumerate() {
+        power::set_pps_target(voltage_mv, step.current_ma, true);
+        RENEGOTIATE.signal(());
+        Timer::after(Duration::from_millis(step.dwell_ms.max(MIN_DWELL_MS) as u64)).await;
+
+        let result = measure(step);
+        if !result.granted(voltage_mv) {
+            warn!(
+                "Step {}: requested {}mV @ {}mA, source granted {}mV @ {}mA",
+                index,
+                voltage_mv,
+                step.current_ma,
+                result.contract.voltage_mv,
+                result.contract.current_ma
+            );
+        }
+        match (result.measured_mv, result.measured_ma) {
+            (Some(measured_mv), Some(measured_ma)) => info!(
+                "Step {}: requested {}mV @ {}mA, measured {}mV @ {}mA",
+                index, voltage_mv, step.current_ma, measured_mv, measured_ma
+            ),
+            (Some(measured_mv), None) => info!(
+                "Step {}: requested {}mV @ {}mA, measured {}mV",
+                index, voltage_mv, step.current_ma, measured_mv
+            ),
+            _ => info!(
+                "Step {}: requested {}mV @ {}mA, not measured",
+                index, voltage_mv, step.current_ma
+            ),
+        }
+    }
+    info!("PPS sweep done");
+}
+
+/// Read the contract and VBUS for `step`, from the INA226 if available.
+fn measure(step: SweepStep) -> StepResult {
+    let contract = power::current_contract();
+    #[cfg(feature = "ina226")]
+    if let Some(telemetry) = crate::telemetry::latest() {
+        return StepResult {
+            step,
+            contract,
+            measured_mv: Some(telemetry.vbus_mv),
+            measured_ma: Some(telemetry.current_ma),
+        };
+    }
+    StepResult {
+        step,
+        contract,
+        measured_mv: power::measured_vbus_mv(),
+        measured_ma: None,
+    }
+}
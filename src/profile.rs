@@ -0,0 +1,113 @@
+//! Button driven selection of the requested voltage profile.
+use core::sync::atomic::{AtomicU8, Ordering};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
+
+#[cfg(target_os = "none")]
+pub use button::profile_task;
+
+/// Voltage profile requested from the source.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Profile {
+    /// Default policy: EPR AVS if available, otherwise highest SPR voltage
+    Auto,
+    Fixed5V,
+    Fixed9V,
+    Fixed15V,
+    Fixed20V,
+    /// SPR PPS at `PPS_PROFILE_MV`
+    Pps,
+}
+
+/// Voltage requested in the PPS profile
+pub const PPS_PROFILE_MV: u32 = 12_000;
+
+impl Profile {
+    const ALL: [Profile; 6] = [
+        Profile::Auto,
+        Profile::Fixed5V,
+        Profile::Fixed9V,
+        Profile::Fixed15V,
+        Profile::Fixed20V,
+        Profile::Pps,
+    ];
+
+    /// The profile selected after this one when the button is pressed.
+    pub fn next(self) -> Self {
+        Self::ALL[(self as usize + 1) % Self::ALL.len()]
+    }
+
+    /// Fixed voltage of this profile in mV, if it is a fixed profile.
+    pub fn fixed_voltage_mv(self) -> Option<u32> {
+        match self {
+            Profile::Fixed5V => Some(5_000),
+            Profile::Fixed9V => Some(9_000),
+            Profile::Fixed15V => Some(15_000),
+            Profile::Fixed20V => Some(20_000),
+            Profile::Auto | Profile::Pps => None,
+        }
+    }
+
+    /// Profile with the given index into the button cycle.
+    pub fn from_index(index: u8) -> Option<Self> {
+        Self::ALL.get(index as usize).copied()
+    }
+
+    /// Currently selected profile.
+    pub fn current() -> Self {
+        Self::ALL[PROFILE.load(Ordering::Relaxed) as usize]
+    }
+
+    /// Select a new profile and notify the policy so it renegotiates.
+    pub fn select(self) {
+        PROFILE.store(self as u8, Ordering::Relaxed);
+        PROFILE_CHANGED.signal(());
+    }
+}
+
+static PROFILE: AtomicU8 = AtomicU8::new(Profile::Auto as u8);
+
+/// Raised whenever the selected profile changes.
+pub(crate) static PROFILE_CHANGED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Button handling, only available on the target.
+#[cfg(target_os = "none")]
+mod button {
+    use embassy_stm32::gpio::Input;
+    use embassy_time::{Duration, Timer};
+
+    use super::Profile;
+    use crate::fmt::info;
+
+    /// Button must read the same level for this long to count as a press.
+    const DEBOUNCE: Duration = Duration::from_millis(30);
+    /// Button polling interval.
+    const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+    /// Cycle through the voltage profiles on every press of an active-low button.
+    #[embassy_executor::task]
+    pub async fn profile_task(button: Input<'static>) {
+        loop {
+            wait_level(&button, true).await;
+            wait_level(&button, false).await;
+
+            let profile = Profile::current().next();
+            info!("Profile selected: {}", profile);
+            profile.select();
+        }
+    }
+
+    /// Wait until the button is stable at the given pressed state for `DEBOUNCE`.
+    async fn wait_level(button: &Input<'static>, pressed: bool) {
+        let mut stable = Duration::from_ticks(0);
+        while stable < DEBOUNCE {
+            Timer::after(POLL_INTERVAL).await;
+            if button.is_low() == pressed {
+                stable += POLL_INTERVAL;
+            } else {
+                stable = Duration::from_ticks(0);
+            }
+        }
+    }
+}
@@ -0,0 +1,162 @@
+//! Host-side simulation of a PD source, used to exercise the sink policy without hardware.
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::vec::Vec;
+
+use usbpd::timers::Timer;
+use usbpd_traits::{Driver, DriverRxError, DriverTxError};
+
+/// Control message types (USB PD 3.2 Table 6.5)
+pub const GOOD_CRC: u8 = 0b0_0001;
+pub const ACCEPT: u8 = 0b0_0011;
+pub const REJECT: u8 = 0b0_0100;
+pub const PS_RDY: u8 = 0b0_0110;
+pub const NOT_SUPPORTED: u8 = 0b1_0000;
+
+/// Data message types (USB PD 3.2 Table 6.6)
+pub const SOURCE_CAPABILITIES: u8 = 0b0_0001;
+pub const REQUEST: u8 = 0b0_0010;
+
+/// Fixed supply PDO flags (USB PD 3.2 Table 6.9)
+pub const FIXED_UNCONSTRAINED_POWER: u32 = 1 << 27;
+pub const FIXED_USB_COMMUNICATIONS_CAPABLE: u32 = 1 << 26;
+pub const FIXED_DUAL_ROLE_DATA: u32 = 1 << 25;
+pub const FIXED_EPR_MODE_CAPABLE: u32 = 1 << 23;
+
+/// Header bits of messages from the simulated source: Source, DFP, revision 3.0
+const SOURCE_HEADER_FLAGS: u16 = (1 << 8) | (0b10 << 6) | (1 << 5);
+
+/// Fixed supply PDO without flags.
+pub fn fixed_pdo(voltage_mv: u32, current_ma: u32) -> u32 {
+    ((voltage_mv / 50) << 10) | (current_ma / 10)
+}
+
+/// SPR PPS APDO (USB PD 3.2 Table 6.13).
+pub fn pps_pdo(min_voltage_mv: u32, max_voltage_mv: u32, current_ma: u32) -> u32 {
+    (0b11 << 30)
+        | ((max_voltage_mv / 100) << 17)
+        | ((min_voltage_mv / 100) << 8)
+        | (current_ma / 50)
+}
+
+/// Message header as sent by the simulated source.
+fn header(message_type: u8, message_id: u8, object_count: usize) -> u16 {
+    SOURCE_HEADER_FLAGS
+        | ((object_count as u16 & 0x7) << 12)
+        | ((message_id as u16 & 0x7) << 9)
+        | message_type as u16
+}
+
+/// Driver that plays back a scripted source and records what the sink transmits.
+///
+/// Every transmitted message is acknowledged with GoodCRC before the next scripted message
+/// is delivered. Once the script is exhausted, `receive` never completes.
+#[derive(Default)]
+pub struct MockDriver {
+    /// Messages from the simulated source, in order
+    script: VecDeque<Vec<u8>>,
+    /// Message ID of the next scripted message
+    next_message_id: u8,
+    /// Message ID of a transmitted message that still needs a GoodCRC
+    pending_good_crc: Option<u8>,
+    /// Messages transmitted by the sink, GoodCRC excluded
+    pub transmitted: Vec<Vec<u8>>,
+    /// Number of hard resets transmitted by the sink
+    pub hard_resets: usize,
+}
+
+impl MockDriver {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queue a Source_Capabilities message with the given PDOs.
+    pub fn source_capabilities(self, pdos: &[u32]) -> Self {
+        self.queue_message(SOURCE_CAPABILITIES, pdos)
+    }
+
+    /// Queue a control message, e.g. `ACCEPT` or `PS_RDY`.
+    pub fn control(self, message_type: u8) -> Self {
+        self.queue_message(message_type, &[])
+    }
+
+    fn queue_message(mut self, message_type: u8, objects: &[u32]) -> Self {
+        let mut message = Vec::new();
+        message.extend_from_slice(
+            &header(message_type, self.next_message_id, objects.len()).to_le_bytes(),
+        );
+        for object in objects {
+            message.extend_from_slice(&object.to_le_bytes());
+        }
+        self.next_message_id = (self.next_message_id + 1) % 8;
+        self.script.push_back(message);
+        self
+    }
+
+    /// Request data objects transmitted by the sink, in order.
+    pub fn requests(&self) -> Vec<u32> {
+        self.transmitted
+            .iter()
+            .filter(|message| {
+                let header = u16::from_le_bytes([message[0], message[1]]);
+                header & 0x1F == REQUEST as u16 && (header >> 12) & 0x7 == 1
+            })
+            .map(|message| u32::from_le_bytes([message[2], message[3], message[4], message[5]]))
+            .collect()
+    }
+}
+
+impl Driver for &mut MockDriver {
+    async fn wait_for_vbus(&self) {}
+
+    async fn receive(&mut self, buffer: &mut [u8]) -> Result<usize, DriverRxError> {
+        let message = match self.pending_good_crc.take() {
+            Some(message_id) => header(GOOD_CRC, message_id, 0).to_le_bytes().to_vec(),
+            None => match self.script.pop_front() {
+                Some(message) => message,
+                None => core::future::pending().await,
+            },
+        };
+        buffer[..message.len()].copy_from_slice(&message);
+        Ok(message.len())
+    }
+
+    async fn transmit(&mut self, data: &[u8]) -> Result<(), DriverTxError> {
+        let header = u16::from_le_bytes([data[0], data[1]]);
+        let is_good_crc = header & 0x1F == GOOD_CRC as u16 && (header >> 12) & 0x7 == 0;
+        if !is_good_crc {
+            self.pending_good_crc = Some(((header >> 9) & 0x7) as u8);
+            self.transmitted.push(data.to_vec());
+        }
+        Ok(())
+    }
+
+    async fn transmit_hard_reset(&mut self) -> Result<(), DriverTxError> {
+        self.hard_resets += 1;
+        Ok(())
+    }
+}
+
+std::thread_local! {
+    /// Delays requested from `MockSinkTimer` on this thread, in order
+    static DELAYS: RefCell<Vec<u64>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Timer that records the requested delays and completes right away.
+///
+/// Virtual time advances by each delay without sleeping, so timing of the policy is
+/// deterministic. Delays are recorded per thread, tests running in parallel don't mix.
+pub struct MockSinkTimer {}
+
+impl MockSinkTimer {
+    /// Delays requested on this thread since the last call, in ms.
+    pub fn take_delays() -> Vec<u64> {
+        DELAYS.with(|delays| delays.take())
+    }
+}
+
+impl Timer for MockSinkTimer {
+    async fn after_millis(milliseconds: u64) {
+        DELAYS.with(|delays| delays.borrow_mut().push(milliseconds));
+    }
+}
@@ -0,0 +1,115 @@
+//! Sink capabilities advertised in response to Get_Sink_Cap.
+use heapless::Vec;
+
+/// Maximum number of data objects in a Sink_Capabilities message
+pub const MAX_SINK_PDOS: usize = 7;
+
+/// A power level the sink is able to consume.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SinkPdo {
+    /// Fixed supply at `voltage_mv`, drawing up to `current_ma`
+    Fixed { voltage_mv: u32, current_ma: u32 },
+    /// SPR PPS between `min_voltage_mv` and `max_voltage_mv`, drawing up to `current_ma`
+    Pps {
+        min_voltage_mv: u32,
+        max_voltage_mv: u32,
+        current_ma: u32,
+    },
+}
+
+/// vSafe5V at the default USB Type-C current.
+pub const VSAFE_5V: SinkPdo = SinkPdo::Fixed {
+    voltage_mv: 5_000,
+    current_ma: 3_000,
+};
+
+/// Sink capabilities used when none are configured.
+pub const DEFAULT_SINK_PDOS: [SinkPdo; 1] = [VSAFE_5V];
+
+impl SinkPdo {
+    /// Encode as a sink power data object (USB PD 3.2 Tables 6.17 and 6.19).
+    fn to_raw(self) -> u32 {
+        match self {
+            SinkPdo::Fixed {
+                voltage_mv,
+                current_ma,
+            } => {
+                let voltage = (voltage_mv / 50) & 0x3FF; // 50mV units
+                let current = (current_ma / 10) & 0x3FF; // 10mA units
+                (voltage << 10) | current
+            }
+            SinkPdo::Pps {
+                min_voltage_mv,
+                max_voltage_mv,
+                current_ma,
+            } => {
+                let max_voltage = (max_voltage_mv / 100) & 0xFF; // 100mV units
+                let min_voltage = (min_voltage_mv / 100) & 0xFF;
+                let current = (current_ma / 50) & 0x7F; // 50mA units
+                (0b11 << 30) | (max_voltage << 17) | (min_voltage << 8) | current
+            }
+        }
+    }
+}
+
+/// Builds the data objects of a Sink_Capabilities message.
+pub struct SinkCapabilitiesBuilder<'a> {
+    pdos: &'a [SinkPdo],
+    higher_capability: bool,
+    usb_communications_capable: bool,
+}
+
+impl<'a> SinkCapabilitiesBuilder<'a> {
+    pub fn new(pdos: &'a [SinkPdo]) -> Self {
+        Self {
+            pdos,
+            higher_capability: false,
+            usb_communications_capable: false,
+        }
+    }
+
+    /// The sink needs more than vSafe5V for full functionality.
+    pub fn higher_capability(mut self, higher_capability: bool) -> Self {
+        self.higher_capability = higher_capability;
+        self
+    }
+
+    /// The sink is capable of USB communication.
+    pub fn usb_communications_capable(mut self, usb_communications_capable: bool) -> Self {
+        self.usb_communications_capable = usb_communications_capable;
+        self
+    }
+
+    /// Encode the data objects.
+    ///
+    /// The first object is always vSafe5V, as required by the spec, and carries the sink flags.
+    /// Objects beyond `MAX_SINK_PDOS` are dropped.
+    pub fn build(&self) -> Vec<u32, MAX_SINK_PDOS> {
+        let mut pdos = Vec::new();
+        if !matches!(
+            self.pdos.first(),
+            Some(SinkPdo::Fixed {
+                voltage_mv: 5_000,
+                ..
+            })
+        ) {
+            let _ = pdos.push(VSAFE_5V.to_raw());
+        }
+        for pdo in self.pdos {
+            if pdos.push(pdo.to_raw()).is_err() {
+                break;
+            }
+        }
+
+        if let Some(first) = pdos.first_mut() {
+            if self.higher_capability {
+                *first |= 1 << 28;
+            }
+            if self.usb_communications_capable {
+                *first |= 1 << 26;
+            }
+        }
+        pdos
+    }
+}
@@ -0,0 +1,454 @@
+//! Provider (source) demo mode, selected with the `source` feature instead of the sink.
+//!
+//! Advertises fixed 5V, 9V and 15V supplies, answers requests and sends PS_RDY. The
+//! usbpd crate only has a sink policy engine, so the source side is a small policy engine
+//! of its own on top of the UCPD PD phy. Only vSafe5V is switched by the board itself;
+//! the higher voltages need a programmable supply driven from
+//! `SourcePolicyManager::transition_power`.
+use heapless::Vec;
+
+use crate::fmt::info;
+
+#[cfg(target_os = "none")]
+pub use ucpd::ucpd_source_task;
+
+/// A fixed supply offered by the source.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct FixedOffer {
+    pub voltage_mv: u32,
+    pub current_ma: u32,
+}
+
+impl FixedOffer {
+    /// Fixed Supply PDO (USB PD 3.2 Table 6.9), without flags.
+    pub fn pdo(self) -> u32 {
+        ((self.voltage_mv / 50) << 10) | (self.current_ma / 10)
+    }
+}
+
+/// Supplies advertised by the demo source, vSafe5V first as required
+pub const DEMO_OFFERS: [FixedOffer; 3] = [
+    FixedOffer {
+        voltage_mv: 5_000,
+        current_ma: 3_000,
+    },
+    FixedOffer {
+        voltage_mv: 9_000,
+        current_ma: 3_000,
+    },
+    FixedOffer {
+        voltage_mv: 15_000,
+        current_ma: 3_000,
+    },
+];
+
+/// Maximum number of PDOs in SPR Source_Capabilities
+pub const MAX_OFFERS: usize = 7;
+
+/// Source side counterpart of `power::Device`: what to offer and which requests to grant.
+pub struct SourcePolicyManager {
+    offers: &'static [FixedOffer],
+    /// Supply granted by the last accepted request
+    contract: Option<FixedOffer>,
+}
+
+impl SourcePolicyManager {
+    pub fn new(offers: &'static [FixedOffer]) -> Self {
+        Self {
+            offers: &offers[..offers.len().min(MAX_OFFERS)],
+            contract: None,
+        }
+    }
+
+    /// Data objects of the Source_Capabilities message.
+    pub fn source_capabilities(&self) -> Vec<u32, MAX_OFFERS> {
+        self.offers.iter().map(|offer| offer.pdo()).collect()
+    }
+
+    /// Supply granted for a fixed Request Data Object, `None` to reject it.
+    ///
+    /// The object position must be offered and the operating current within its maximum.
+    pub fn evaluate_request(&self, rdo: u32) -> Option<FixedOffer> {
+        let position = (rdo >> 28) as usize;
+        let offer = *self.offers.get(position.checked_sub(1)?)?;
+        let operating_current_ma = ((rdo >> 10) & 0x3FF) * 10;
+        (operating_current_ma <= offer.current_ma).then_some(offer)
+    }
+
+    /// Switch to the granted supply, between Accept and PS_RDY.
+    pub async fn transition_power(&mut self, offer: FixedOffer) {
+        info!(
+            "Sourcing {}mV, up to {}mA",
+            offer.voltage_mv, offer.current_ma
+        );
+        self.contract = Some(offer);
+    }
+
+    /// Supply granted by the last accepted request.
+    pub fn contract(&self) -> Option<FixedOffer> {
+        self.contract
+    }
+
+    /// Forget the contract, after a reset or detach.
+    pub fn reset(&mut self) {
+        self.contract = None;
+    }
+}
+
+/// UCPD handling, only available on the target.
+#[cfg(target_os = "none")]
+mod ucpd {
+    use embassy_futures::select::{Either, select};
+    use embassy_stm32::gpio::Output;
+    use embassy_stm32::peripherals;
+    use embassy_stm32::ucpd::{self, CcPhy, CcPull, CcSel, CcVState, PdPhy, Ucpd};
+    use embassy_time::{Duration, Timer, with_timeout};
+
+    use super::{DEMO_OFFERS, SourcePolicyManager};
+    use crate::fmt::{debug, info, warn};
+    use crate::power::{Irqs, UcpdResources};
+    use crate::status::{PdState, set_pd_state};
+
+    /// Control message types (USB PD 3.2 Table 6.5)
+    const CONTROL_GOOD_CRC: u16 = 0b0_0001;
+    const CONTROL_ACCEPT: u16 = 0b0_0011;
+    const CONTROL_REJECT: u16 = 0b0_0100;
+    const CONTROL_PS_RDY: u16 = 0b0_0110;
+    const CONTROL_GET_SOURCE_CAP: u16 = 0b0_0111;
+    const CONTROL_SOFT_RESET: u16 = 0b0_1101;
+    const CONTROL_NOT_SUPPORTED: u16 = 0b1_0000;
+    /// Data message types (USB PD 3.2 Table 6.6)
+    const DATA_SOURCE_CAPABILITIES: u16 = 0b0_0001;
+    const DATA_REQUEST: u16 = 0b0_0010;
+    /// Header bits of messages we send: Source, DFP, revision 3.0
+    const SOURCE_HEADER_FLAGS: u16 = (1 << 8) | (0b10 << 6) | (1 << 5);
+    /// Longest message handled: header and seven data objects
+    const MAX_MESSAGE_LEN: usize = 30;
+
+    /// tCCDebounce
+    const CC_DEBOUNCE: Duration = Duration::from_millis(150);
+    /// tTypeCSendSourceCap, between unanswered Source_Capabilities
+    const SEND_SOURCE_CAP_INTERVAL: Duration = Duration::from_millis(150);
+    /// nCapsCount, Source_Capabilities sent before giving up on a non-PD sink
+    const CAPS_COUNT: usize = 50;
+    /// tSenderResponse, for the Request after Source_Capabilities
+    const SENDER_RESPONSE_TIMEOUT: Duration = Duration::from_millis(30);
+    /// tReceive, for the GoodCRC of a transmitted message
+    const RECEIVE_TIMEOUT: Duration = Duration::from_millis(2);
+    /// nRetryCount
+    const RETRY_COUNT: usize = 2;
+    /// tSrcTransition, between Accept and the voltage change
+    const SOURCE_TRANSITION: Duration = Duration::from_millis(30);
+    /// tSrcRecover, VBUS stays off this long after a hard reset
+    const SOURCE_RECOVER: Duration = Duration::from_millis(700);
+
+    /// PD phy driver of the source, with MessageID and GoodCRC handling.
+    struct UcpdSourceDriver<'d> {
+        pd_phy: PdPhy<'d, peripherals::UCPD1>,
+        /// MessageID of the next message sent to the sink
+        next_message_id: u8,
+    }
+
+    /// Why a source session ended.
+    enum SessionError {
+        /// Hard reset received from the sink
+        HardReset,
+        /// The sink didn't acknowledge or answer in time, needs a hard reset
+        ProtocolError,
+        /// Soft_Reset received and accepted, capabilities must be re-sent
+        SoftReset,
+        /// No PD communication, the sink doesn't respond
+        Unresponsive,
+    }
+
+    impl<'d> UcpdSourceDriver<'d> {
+        fn new(pd_phy: PdPhy<'d, peripherals::UCPD1>) -> Self {
+            Self {
+                pd_phy,
+                next_message_id: 0,
+            }
+        }
+
+        /// Send a message and wait for its GoodCRC, retrying up to nRetryCount times.
+        ///
+        /// Returns whether the sink acknowledged it.
+        async fn send(&mut self, message_type: u16, objects: &[u32]) -> Result<bool, SessionError> {
+            let message_id = self.next_message_id;
+            let header = SOURCE_HEADER_FLAGS
+                | ((objects.len() as u16 & 0x7) << 12)
+                | ((message_id as u16) << 9)
+                | message_type;
+            let mut message = [0u8; MAX_MESSAGE_LEN];
+            message[..2].copy_from_slice(&header.to_le_bytes());
+            for (chunk, object) in message[2..].chunks_exact_mut(4).zip(objects) {
+                chunk.copy_from_slice(&object.to_le_bytes());
+            }
+            let len = 2 + 4 * objects.len();
+
+            for _ in 0..=RETRY_COUNT {
+                match self.pd_phy.transmit(&message[..len]).await {
+                    Ok(()) => {}
+                    Err(ucpd::TxError::HardReset) => return Err(SessionError::HardReset),
+                    Err(ucpd::TxError::Discarded) => continue,
+                }
+                let mut buffer = [0u8; MAX_MESSAGE_LEN];
+                match with_timeout(RECEIVE_TIMEOUT, self.pd_phy.receive(&mut buffer)).await {
+                    Ok(Ok(rx_len)) if rx_len >= 2 => {
+                        let header = u16::from_le_bytes([buffer[0], buffer[1]]);
+                        if is_control(header, CONTROL_GOOD_CRC)
+                            && (header >> 9) & 0x7 == message_id as u16
+                        {
+                            self.next_message_id = (message_id + 1) & 0x7;
+                            return Ok(true);
+                        }
+                    }
+                    Ok(Err(ucpd::RxError::HardReset)) => return Err(SessionError::HardReset),
+                    _ => {}
+                }
+            }
+            Ok(false)
+        }
+
+        /// Receive the next message other than GoodCRC and acknowledge it.
+        ///
+        /// Returns the message header and the length of the message in `buffer`.
+        async fn receive(&mut self, buffer: &mut [u8]) -> Result<(u16, usize), SessionError> {
+            loop {
+                let len = match self.pd_phy.receive(buffer).await {
+                    Ok(len) if len >= 2 => len,
+                    Ok(_) | Err(ucpd::RxError::Crc) | Err(ucpd::RxError::Overrun) => continue,
+                    Err(ucpd::RxError::HardReset) => return Err(SessionError::HardReset),
+                };
+                let header = u16::from_le_bytes([buffer[0], buffer[1]]);
+                if is_control(header, CONTROL_GOOD_CRC) {
+                    continue;
+                }
+                let good_crc = SOURCE_HEADER_FLAGS | (header & (0x7 << 9)) | CONTROL_GOOD_CRC;
+                if let Err(ucpd::TxError::HardReset) =
+                    self.pd_phy.transmit(&good_crc.to_le_bytes()).await
+                {
+                    return Err(SessionError::HardReset);
+                }
+                return Ok((header, len));
+            }
+        }
+
+        /// Forget the MessageID, after a soft or hard reset.
+        fn reset_message_ids(&mut self) {
+            self.next_message_id = 0;
+        }
+    }
+
+    /// Whether a header is the one of the given control message.
+    fn is_control(header: u16, message_type: u16) -> bool {
+        header & (1 << 15) == 0 && (header >> 12) & 0x7 == 0 && header & 0x1F == message_type
+    }
+
+    /// Whether a header is the one of a data message of the given type and object count.
+    fn is_data(header: u16, message_type: u16, object_count: u16) -> bool {
+        header & (1 << 15) == 0
+            && (header >> 12) & 0x7 == object_count
+            && header & 0x1F == message_type
+    }
+
+    /// Advertise the capabilities and serve requests until the session ends.
+    async fn run_source(
+        driver: &mut UcpdSourceDriver<'_>,
+        policy: &mut SourcePolicyManager,
+    ) -> SessionError {
+        let capabilities = policy.source_capabilities();
+
+        // Repeat the capabilities until the sink acknowledges them
+        let mut acknowledged = false;
+        for _ in 0..CAPS_COUNT {
+            match driver.send(DATA_SOURCE_CAPABILITIES, &capabilities).await {
+                Ok(true) => {
+                    acknowledged = true;
+                    break;
+                }
+                Ok(false) => Timer::after(SEND_SOURCE_CAP_INTERVAL).await,
+                Err(err) => return err,
+            }
+        }
+        if !acknowledged {
+            return SessionError::Unresponsive;
+        }
+
+        let mut buffer = [0u8; MAX_MESSAGE_LEN];
+        let mut response_timeout = Some(SENDER_RESPONSE_TIMEOUT);
+        loop {
+            let received = match response_timeout.take() {
+                Some(timeout) => match with_timeout(timeout, driver.receive(&mut buffer)).await {
+                    Ok(received) => received,
+                    Err(_) => {
+                        warn!("No request from the sink");
+                        return SessionError::ProtocolError;
+                    }
+                },
+                None => driver.receive(&mut buffer).await,
+            };
+            let header = match received {
+                Ok((header, _)) => header,
+                Err(err) => return err,
+            };
+
+            let result = if is_data(header, DATA_REQUEST, 1) {
+                let rdo = u32::from_le_bytes([buffer[2], buffer[3], buffer[4], buffer[5]]);
+                serve_request(driver, policy, rdo).await
+            } else if is_control(header, CONTROL_GET_SOURCE_CAP) {
+                response_timeout = Some(SENDER_RESPONSE_TIMEOUT);
+                driver.send(DATA_SOURCE_CAPABILITIES, &capabilities).await
+            } else if is_control(header, CONTROL_SOFT_RESET) {
+                driver.reset_message_ids();
+                return match driver.send(CONTROL_ACCEPT, &[]).await {
+                    Ok(true) => SessionError::SoftReset,
+                    Ok(false) => SessionError::ProtocolError,
+                    Err(err) => err,
+                };
+            } else {
+                debug!("Unsupported message {:04x} from the sink", header);
+                driver.send(CONTROL_NOT_SUPPORTED, &[]).await
+            };
+            match result {
+                Ok(true) => {}
+                Ok(false) => return SessionError::ProtocolError,
+                Err(err) => return err,
+            }
+        }
+    }
+
+    /// Answer a request with Accept and PS_RDY, or Reject.
+    async fn serve_request(
+        driver: &mut UcpdSourceDriver<'_>,
+        policy: &mut SourcePolicyManager,
+        rdo: u32,
+    ) -> Result<bool, SessionError> {
+        let Some(offer) = policy.evaluate_request(rdo) else {
+            warn!("Rejecting request {:08x}", rdo);
+            return driver.send(CONTROL_REJECT, &[]).await;
+        };
+        if !driver.send(CONTROL_ACCEPT, &[]).await? {
+            return Ok(false);
+        }
+        Timer::after(SOURCE_TRANSITION).await;
+        policy.transition_power(offer).await;
+        let ps_rdy = driver.send(CONTROL_PS_RDY, &[]).await?;
+        if ps_rdy {
+            set_pd_state(PdState::Contract);
+        }
+        Ok(ps_rdy)
+    }
+
+    /// Wait for a sink to pull a CC line down with Rd, debounced for tCCDebounce.
+    async fn wait_sink_attached<T: ucpd::Instance>(cc_phy: &mut CcPhy<'_, T>) -> CcSel {
+        loop {
+            // With Rp applied, an open line reads highest and Rd reads low
+            let (cc1, cc2) = cc_phy.vstate();
+            let cc_sel = match (cc1, cc2) {
+                (CcVState::LOW, _) => CcSel::CC1,
+                (_, CcVState::LOW) => CcSel::CC2,
+                _ => {
+                    cc_phy.wait_for_vstate_change().await;
+                    continue;
+                }
+            };
+            if with_timeout(CC_DEBOUNCE, cc_phy.wait_for_vstate_change())
+                .await
+                .is_err()
+            {
+                return cc_sel;
+            }
+        }
+    }
+
+    /// Wait for the Rd of the sink to disappear from the selected CC line.
+    async fn wait_sink_detached<T: ucpd::Instance>(cc_phy: &mut CcPhy<'_, T>, cc_sel: CcSel) {
+        loop {
+            let (cc1, cc2) = cc_phy.vstate();
+            let state = match cc_sel {
+                CcSel::CC1 => cc1,
+                _ => cc2,
+            };
+            if state != CcVState::LOW {
+                return;
+            }
+            cc_phy.wait_for_vstate_change().await;
+        }
+    }
+
+    /// Act as a PD source with the demo capabilities.
+    #[embassy_executor::task]
+    pub async fn ucpd_source_task(
+        mut ucpd_resources: UcpdResources,
+        mut vbus_enable: Output<'static>,
+    ) {
+        let mut policy = SourcePolicyManager::new(&DEMO_OFFERS);
+
+        loop {
+            vbus_enable.set_low();
+            policy.reset();
+            set_pd_state(PdState::WaitingForAttach);
+
+            let mut ucpd = Ucpd::new(
+                ucpd_resources.ucpd.reborrow(),
+                Irqs {},
+                ucpd_resources.pin_cc1.reborrow(),
+                ucpd_resources.pin_cc2.reborrow(),
+                Default::default(),
+            );
+            // Rp advertising 3A, matching the offered current
+            ucpd.cc_phy().set_pull(CcPull::Source3_0A);
+
+            info!("Waiting for a sink");
+            let cc_sel = wait_sink_attached(ucpd.cc_phy()).await;
+            info!("Sink attached, providing vSafe5V");
+            set_pd_state(PdState::Negotiating);
+            vbus_enable.set_high();
+
+            let (mut cc_phy, pd_phy) = ucpd.split_pd_phy(
+                ucpd_resources.rx_dma.reborrow(),
+                ucpd_resources.tx_dma.reborrow(),
+                cc_sel,
+            );
+            let mut driver = UcpdSourceDriver::new(pd_phy);
+
+            loop {
+                let session = select(
+                    run_source(&mut driver, &mut policy),
+                    wait_sink_detached(&mut cc_phy, cc_sel),
+                )
+                .await;
+                match session {
+                    Either::First(SessionError::SoftReset) => {
+                        info!("Soft reset, re-sending capabilities");
+                        policy.reset();
+                    }
+                    Either::First(
+                        error @ (SessionError::HardReset | SessionError::ProtocolError),
+                    ) => {
+                        if let SessionError::ProtocolError = error {
+                            warn!("Protocol error, sending hard reset");
+                            let _ = driver.pd_phy.transmit_hardreset().await;
+                        }
+                        warn!("Hard reset, cycling VBUS");
+                        set_pd_state(PdState::Fault);
+                        driver.reset_message_ids();
+                        policy.reset();
+                        vbus_enable.set_low();
+                        Timer::after(SOURCE_RECOVER).await;
+                        vbus_enable.set_high();
+                    }
+                    Either::First(SessionError::Unresponsive) => {
+                        // Type-C only sink, keep vSafe5V until it is unplugged
+                        info!("Sink doesn't respond to PD, providing vSafe5V only");
+                        wait_sink_detached(&mut cc_phy, cc_sel).await;
+                        break;
+                    }
+                    Either::Second(()) => break,
+                }
+            }
+            info!("Sink detached");
+        }
+    }
+}
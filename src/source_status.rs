@@ -0,0 +1,157 @@
+//! Status of the source: its temperature, input power and protection events.
+//!
+//! Queried with Get_Status, which the policy engine has no support for; the UCPD driver
+//! sends it on request and reports the parsed Status message as a `PdEvent`. Sources that
+//! answer with Not_Supported, or not at all, are not asked again on the same attachment.
+use core::cell::Cell;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+
+#[cfg(target_os = "none")]
+pub use poll::status_poll_task;
+
+use crate::power::{DRIVER_REQUEST, DriverRequest};
+
+/// Temperature of the source as it reports it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum TemperatureStatus {
+    Normal,
+    Warning,
+    OverTemperature,
+}
+
+/// Status data block (USB PD 3.2 Table 6.67).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct SourceStatus {
+    /// Internal temperature in °C, `None` if not reported. 1 means below 2°C.
+    pub internal_temp_c: Option<u8>,
+    /// Powered from an external supply
+    pub external_power: bool,
+    /// The external supply is AC rather than DC
+    pub external_power_ac: bool,
+    /// Powered from an internal battery
+    pub internal_battery: bool,
+    /// Powered from an internal source other than a battery
+    pub internal_other: bool,
+    /// Over-current protection event
+    pub ocp: bool,
+    /// Over-temperature protection event
+    pub otp: bool,
+    /// Over-voltage protection event
+    pub ovp: bool,
+    /// Temperature state, `None` if not reported
+    pub temperature: Option<TemperatureStatus>,
+}
+
+impl SourceStatus {
+    /// Length of the data block up to the Temperature Status, the part parsed here
+    const LEN: usize = 5;
+
+    /// Parse the data block of a Status message.
+    ///
+    /// Returns `None` if it is too short.
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        if data.len() < Self::LEN {
+            return None;
+        }
+
+        let present_input = data[1];
+        let event_flags = data[3];
+        Some(Self {
+            internal_temp_c: Some(data[0]).filter(|&temp| temp != 0),
+            external_power: present_input & (1 << 1) != 0,
+            external_power_ac: present_input & (1 << 2) != 0,
+            internal_battery: present_input & (1 << 3) != 0,
+            internal_other: present_input & (1 << 4) != 0,
+            ocp: event_flags & (1 << 1) != 0,
+            otp: event_flags & (1 << 2) != 0,
+            ovp: event_flags & (1 << 3) != 0,
+            temperature: match (data[4] >> 1) & 0x3 {
+                0b01 => Some(TemperatureStatus::Normal),
+                0b10 => Some(TemperatureStatus::Warning),
+                0b11 => Some(TemperatureStatus::OverTemperature),
+                _ => None,
+            },
+        })
+    }
+
+    /// Whether the source reports a protection event or overheating.
+    pub fn is_fault(&self) -> bool {
+        self.ocp
+            || self.otp
+            || self.ovp
+            || self.temperature == Some(TemperatureStatus::OverTemperature)
+    }
+}
+
+/// Latest status of the source on this attachment
+static LATEST: Mutex<CriticalSectionRawMutex, Cell<Option<SourceStatus>>> =
+    Mutex::new(Cell::new(None));
+
+/// The source answered Get_Status with Not_Supported on this attachment
+static UNSUPPORTED: AtomicBool = AtomicBool::new(false);
+
+/// Latest status reported by the source, `None` until one arrived on this attachment.
+pub fn latest() -> Option<SourceStatus> {
+    LATEST.lock(|latest| latest.get())
+}
+
+/// Whether the source supports Get_Status, as far as known on this attachment.
+pub fn is_supported() -> bool {
+    !UNSUPPORTED.load(Ordering::Relaxed)
+}
+
+/// Query the status of the source, reported as a `PdEvent`.
+///
+/// Sent by the driver once the policy engine is idle in PE_SNK_Ready, not in the middle of
+/// an AMS, queries made while detached are dropped on attach. Skipped for sources that don't support the query.
+pub fn request() {
+    if is_supported() {
+        let _ = DRIVER_REQUEST.try_send(DriverRequest::Status);
+    }
+}
+
+/// Record the answer to Get_Status, `None` if the source doesn't support it.
+#[cfg(target_os = "none")]
+pub(crate) fn set(status: Option<SourceStatus>) {
+    match status {
+        Some(status) => LATEST.lock(|latest| latest.set(Some(status))),
+        None => UNSUPPORTED.store(true, Ordering::Relaxed),
+    }
+}
+
+/// Forget the source, on attach.
+#[cfg(target_os = "none")]
+pub(crate) fn reset() {
+    LATEST.lock(|latest| latest.set(None));
+    UNSUPPORTED.store(false, Ordering::Relaxed);
+}
+
+/// Periodic status query, only available on the target.
+#[cfg(target_os = "none")]
+mod poll {
+    use embassy_time::{Duration, Timer};
+
+    use crate::power;
+
+    /// Time between status queries while a contract is in place
+    const POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+    /// Query the source's status every 10s while a contract is in place.
+    ///
+    /// Polls that find the policy engine busy, e.g. renegotiating, are skipped rather than
+    /// queued behind the AMS.
+    #[embassy_executor::task]
+    pub async fn status_poll_task() {
+        loop {
+            Timer::after(POLL_INTERVAL).await;
+            if power::current_contract().is_active() && power::pe_ready() {
+                super::request();
+            }
+        }
+    }
+}
@@ -0,0 +1,238 @@
+//! Negotiation statistics across attach cycles, e.g. for soak tests with flaky chargers.
+use core::cell::Cell;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_time::Instant;
+
+#[cfg(target_os = "none")]
+pub use heartbeat::heartbeat_task;
+
+#[cfg(target_os = "none")]
+use crate::fmt::info;
+use crate::power::Contract;
+
+/// Counters since boot or the last `Stats::reset`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Stats {
+    /// Attachments of a source
+    pub attaches: u32,
+    /// Hard resets sent or received
+    pub hard_resets: u32,
+    /// Messages lost to RX overruns, a sign of DMA or interrupt latency pressure
+    pub overruns: u32,
+    /// Messages lost to CRC errors, a sign of poor signal integrity of the cable
+    pub crc_errors: u32,
+    /// Contracts confirmed with PS_RDY, renegotiations included
+    pub contracts: u32,
+    /// Attachments that reached a contract
+    pub attaches_with_contract: u32,
+    /// Time from attach to the first Source_Capabilities of the latest attachment in ms
+    pub last_time_to_caps_ms: Option<u32>,
+    /// Longest time from attach to the first Source_Capabilities in ms
+    pub max_time_to_caps_ms: u32,
+    /// Time from attach to the first contract of the latest attachment in ms
+    pub last_time_to_contract_ms: Option<u32>,
+    /// Longest time from attach to the first contract in ms
+    pub max_time_to_contract_ms: u32,
+    /// Sum of the times from attach to the first contract in ms, for the average
+    pub total_time_to_contract_ms: u64,
+    /// Highest power contract established, what the best charger seen can do
+    pub best_contract: Option<Contract>,
+}
+
+impl Stats {
+    /// Snapshot of the current counters.
+    pub fn current() -> Self {
+        STATS.lock(|stats| stats.get().stats)
+    }
+
+    /// Clear all counters, e.g. at the start of a soak test.
+    pub fn reset() {
+        STATS.lock(|stats| {
+            let mut state = stats.get();
+            state.stats = Stats::default();
+            stats.set(state);
+        });
+    }
+
+    /// Forget the best contract, e.g. before trying another charger.
+    pub fn reset_best_contract() {
+        update(|state| state.stats.best_contract = None);
+    }
+
+    /// Average time from attach to the first contract in ms.
+    pub fn mean_time_to_contract_ms(&self) -> Option<u32> {
+        if self.attaches_with_contract == 0 {
+            return None;
+        }
+        Some((self.total_time_to_contract_ms / self.attaches_with_contract as u64) as u32)
+    }
+}
+
+#[derive(Clone, Copy)]
+struct State {
+    stats: Stats,
+    /// Time of the current attach, until its first contract
+    attached_at: Option<Instant>,
+    /// Time of the current attach, until its first Source_Capabilities
+    caps_pending_since: Option<Instant>,
+}
+
+static STATS: Mutex<CriticalSectionRawMutex, Cell<State>> = Mutex::new(Cell::new(State {
+    stats: Stats {
+        attaches: 0,
+        hard_resets: 0,
+        overruns: 0,
+        crc_errors: 0,
+        contracts: 0,
+        attaches_with_contract: 0,
+        last_time_to_caps_ms: None,
+        max_time_to_caps_ms: 0,
+        last_time_to_contract_ms: None,
+        max_time_to_contract_ms: 0,
+        total_time_to_contract_ms: 0,
+        best_contract: None,
+    },
+    attached_at: None,
+    caps_pending_since: None,
+}));
+
+fn update(f: impl FnOnce(&mut State)) {
+    STATS.lock(|stats| {
+        let mut state = stats.get();
+        f(&mut state);
+        stats.set(state);
+    });
+}
+
+/// Count an attach and start timing its first contract.
+#[cfg(target_os = "none")]
+pub(crate) fn record_attach() {
+    update(|state| {
+        state.stats.attaches += 1;
+        state.attached_at = Some(Instant::now());
+        state.caps_pending_since = state.attached_at;
+    });
+}
+
+/// Count a hard reset sent or received.
+#[cfg(target_os = "none")]
+pub(crate) fn record_hard_reset() {
+    update(|state| state.stats.hard_resets += 1);
+}
+
+/// Count a message lost to an RX overrun.
+#[cfg(target_os = "none")]
+pub(crate) fn record_overrun() {
+    update(|state| state.stats.overruns += 1);
+}
+
+/// Count a message lost to a CRC error.
+#[cfg(target_os = "none")]
+pub(crate) fn record_crc_error() {
+    update(|state| state.stats.crc_errors += 1);
+}
+
+/// Whether the current attachment hasn't seen Source_Capabilities yet.
+#[cfg(target_os = "none")]
+pub(crate) fn awaiting_caps() -> bool {
+    STATS.lock(|stats| stats.get().caps_pending_since.is_some())
+}
+
+/// Time the first Source_Capabilities of the attachment, returning the delay in ms.
+///
+/// Returns `None` for later capabilities of the same attachment.
+pub(crate) fn record_caps() -> Option<u32> {
+    let mut elapsed_ms = None;
+    update(|state| {
+        if let Some(attached_at) = state.caps_pending_since.take() {
+            let ms = attached_at.elapsed().as_millis() as u32;
+            state.stats.last_time_to_caps_ms = Some(ms);
+            state.stats.max_time_to_caps_ms = state.stats.max_time_to_caps_ms.max(ms);
+            elapsed_ms = Some(ms);
+        }
+    });
+    elapsed_ms
+}
+
+/// Count a confirmed contract, timing it if it is the first of the attachment.
+///
+/// Also kept as the best contract if it has more power than any before.
+pub(crate) fn record_contract(contract: &Contract) {
+    update(|state| {
+        state.stats.contracts += 1;
+        let best_mw = state.stats.best_contract.map_or(0, |best| best.power_mw());
+        if contract.power_mw() > best_mw {
+            state.stats.best_contract = Some(*contract);
+        }
+        if let Some(attached_at) = state.attached_at.take() {
+            let elapsed_ms = attached_at.elapsed().as_millis() as u32;
+            let stats = &mut state.stats;
+            stats.attaches_with_contract += 1;
+            stats.last_time_to_contract_ms = Some(elapsed_ms);
+            stats.max_time_to_contract_ms = stats.max_time_to_contract_ms.max(elapsed_ms);
+            stats.total_time_to_contract_ms += elapsed_ms as u64;
+        }
+    });
+}
+
+/// Print the counters, on each detach.
+#[cfg(target_os = "none")]
+pub(crate) fn log_summary() {
+    let stats = Stats::current();
+    info!(
+        "Stats: {} attaches, {} with contract, {} contracts, {} hard resets, {} overruns, {} CRC errors, time to caps last {}ms max {}ms, time to contract last {}ms mean {}ms max {}ms",
+        stats.attaches,
+        stats.attaches_with_contract,
+        stats.contracts,
+        stats.hard_resets,
+        stats.overruns,
+        stats.crc_errors,
+        stats.last_time_to_caps_ms.unwrap_or(0),
+        stats.max_time_to_caps_ms,
+        stats.last_time_to_contract_ms.unwrap_or(0),
+        stats.mean_time_to_contract_ms().unwrap_or(0),
+        stats.max_time_to_contract_ms
+    );
+}
+
+/// Periodic liveness log, only available on the target.
+#[cfg(target_os = "none")]
+mod heartbeat {
+    use embassy_time::{Duration, Instant, Timer};
+
+    use super::Stats;
+    use crate::fmt::info;
+    use crate::power::{self, Contract};
+    use crate::status;
+
+    /// Time between heartbeats
+    const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
+
+    /// Log uptime, PD state, contract and counters every 30s, attached or not, to show
+    /// the firmware is alive during soak tests.
+    #[embassy_executor::task]
+    pub async fn heartbeat_task() {
+        loop {
+            Timer::after(HEARTBEAT_INTERVAL).await;
+            let stats = Stats::current();
+            let contract = power::current_contract();
+            let best = stats.best_contract.unwrap_or(Contract::NONE);
+            info!(
+                "Heartbeat: up {}s, {}, contract {}mV {}mA, best {}mV {}mA, {} attaches, {} contracts, {} hard resets, {} overruns, {} CRC errors",
+                Instant::now().as_secs(),
+                status::pd_state(),
+                contract.voltage_mv,
+                contract.current_ma,
+                best.voltage_mv,
+                best.current_ma,
+                stats.attaches,
+                stats.contracts,
+                stats.hard_resets,
+                stats.overruns,
+                stats.crc_errors
+            );
+        }
+    }
+}
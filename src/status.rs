@@ -0,0 +1,113 @@
+//! Status shown on the board LED.
+use core::sync::atomic::{AtomicU8, Ordering};
+
+/// PD phase shown on the status LED.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum PdState {
+    /// Slow blink while no cable is attached
+    WaitingForAttach,
+    /// Fast blink from attach until a contract is in place
+    Negotiating,
+    /// Solid while a contract is in place
+    Contract,
+    /// Double blink after a hard reset or a failed negotiation
+    Fault,
+    /// Short flash while a debug accessory is attached
+    DebugAccessory,
+}
+
+impl PdState {
+    const ALL: [PdState; 5] = [
+        PdState::WaitingForAttach,
+        PdState::Negotiating,
+        PdState::Contract,
+        PdState::Fault,
+        PdState::DebugAccessory,
+    ];
+
+    /// One period of the blink pattern, as on and off times in ms.
+    pub fn led_pattern(self) -> &'static [(u64, u64)] {
+        match self {
+            PdState::WaitingForAttach => &[(500, 500)],
+            PdState::Negotiating => &[(100, 100)],
+            PdState::Contract => &[(500, 0)],
+            PdState::Fault => &[(100, 100), (100, 700)],
+            PdState::DebugAccessory => &[(50, 950)],
+        }
+    }
+
+    /// Brightness pattern on a PWM driven LED, the counterpart of `led_pattern`.
+    pub fn led_brightness(self) -> BrightnessPattern {
+        match self {
+            PdState::WaitingForAttach => BrightnessPattern::Breathe { period_ms: 3000 },
+            PdState::Negotiating => BrightnessPattern::Breathe { period_ms: 500 },
+            PdState::Contract => BrightnessPattern::Steady(40),
+            PdState::Fault => BrightnessPattern::Pulses {
+                count: 2,
+                period_ms: 1000,
+            },
+            PdState::DebugAccessory => BrightnessPattern::Pulses {
+                count: 1,
+                period_ms: 1000,
+            },
+        }
+    }
+}
+
+/// Brightness over time of a PWM driven status LED.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum BrightnessPattern {
+    /// Constant brightness in percent
+    Steady(u8),
+    /// Fade in and out once per period
+    Breathe { period_ms: u32 },
+    /// Short full brightness pulses at the start of each period
+    Pulses { count: u8, period_ms: u32 },
+}
+
+impl BrightnessPattern {
+    /// Length of a pulse and of the gap after it in ms
+    const PULSE_MS: u32 = 100;
+
+    /// Brightness in percent `elapsed_ms` into the pattern.
+    pub fn brightness_at(self, elapsed_ms: u32) -> u8 {
+        match self {
+            BrightnessPattern::Steady(percent) => percent.min(100),
+            BrightnessPattern::Breathe { period_ms } => {
+                let period_ms = period_ms.max(2);
+                let half_ms = period_ms / 2;
+                let phase_ms = elapsed_ms % period_ms;
+                let level = if phase_ms < half_ms {
+                    phase_ms * 100 / half_ms
+                } else {
+                    (period_ms - phase_ms) * 100 / (period_ms - half_ms)
+                };
+                // Squared, as perceived brightness isn't linear in the duty cycle
+                (level.min(100) * level.min(100) / 100) as u8
+            }
+            BrightnessPattern::Pulses { count, period_ms } => {
+                let phase_ms = elapsed_ms % period_ms.max(1);
+                let pulse = phase_ms / (2 * Self::PULSE_MS);
+                if pulse < count as u32 && phase_ms % (2 * Self::PULSE_MS) < Self::PULSE_MS {
+                    100
+                } else {
+                    0
+                }
+            }
+        }
+    }
+}
+
+static PD_STATE: AtomicU8 = AtomicU8::new(PdState::WaitingForAttach as u8);
+
+/// Update the PD phase shown on the status LED.
+pub fn set_pd_state(state: PdState) {
+    PD_STATE.store(state as u8, Ordering::Relaxed);
+}
+
+/// PD phase currently shown on the status LED.
+pub fn pd_state() -> PdState {
+    PdState::ALL[PD_STATE.load(Ordering::Relaxed) as usize]
+}
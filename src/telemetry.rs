@@ -0,0 +1,178 @@
+//! Measured VBUS power from an INA226 current/power monitor on I2C.
+//!
+//! The readings are compared against the contract, as a source may not enforce its limits
+//! and the load may draw more than negotiated. The latest reading is published through
+//! `TELEMETRY` for a consuming task, e.g. a display, and `latest` for the CLI.
+#[cfg(target_os = "none")]
+pub use ina226::{Ina226Resources, ina226_task};
+
+use core::cell::Cell;
+
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
+
+use crate::power::Contract;
+
+/// Draw above the contract tolerated before warning, in percent, for the measurement error
+pub const OVERDRAW_MARGIN_PERCENT: u32 = 5;
+
+/// One reading of the monitor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Telemetry {
+    /// VBUS in mV
+    pub vbus_mv: u32,
+    /// Current into the sink in mA, negative if it flows back to the source
+    pub current_ma: i32,
+    /// Power in mW
+    pub power_mw: u32,
+}
+
+impl Telemetry {
+    /// Convert the raw Bus Voltage, Current and Power registers.
+    ///
+    /// The bus voltage is in 1.25mV units, the current in units of `current_lsb_ua` and the
+    /// power in units of 25 times that (INA226 datasheet 7.6).
+    pub fn from_registers(bus: u16, current: i16, power: u16, current_lsb_ua: u32) -> Self {
+        Self {
+            vbus_mv: bus as u32 * 125 / 100,
+            current_ma: (current as i64 * current_lsb_ua as i64 / 1000) as i32,
+            power_mw: (power as u64 * 25 * current_lsb_ua as u64 / 1000) as u32,
+        }
+    }
+
+    /// Whether the measured current or power exceeds `contract`, beyond the margin.
+    ///
+    /// Always `false` without a contract, as nothing was negotiated to exceed.
+    pub fn exceeds(&self, contract: &Contract) -> bool {
+        if !contract.is_active() {
+            return false;
+        }
+        let with_margin = |limit: u32| limit as u64 * (100 + OVERDRAW_MARGIN_PERCENT) as u64 / 100;
+        self.current_ma.max(0) as u64 > with_margin(contract.current_ma)
+            || self.power_mw as u64 > with_margin(contract.power_mw())
+    }
+}
+
+/// Raised with each reading of the monitor.
+pub static TELEMETRY: Signal<CriticalSectionRawMutex, Telemetry> = Signal::new();
+
+/// Latest reading, for consumers that poll rather than wait on `TELEMETRY`
+static LATEST: Mutex<CriticalSectionRawMutex, Cell<Option<Telemetry>>> =
+    Mutex::new(Cell::new(None));
+
+/// Latest reading of the monitor, `None` before the first one.
+pub fn latest() -> Option<Telemetry> {
+    LATEST.lock(|latest| latest.get())
+}
+
+/// Publish a reading to `TELEMETRY` and `latest`.
+#[cfg(target_os = "none")]
+fn publish(telemetry: Telemetry) {
+    LATEST.lock(|latest| latest.set(Some(telemetry)));
+    TELEMETRY.signal(telemetry);
+}
+
+/// INA226 handling, only available on the target.
+#[cfg(target_os = "none")]
+mod ina226 {
+    use embassy_stm32::i2c::{self, I2c};
+    use embassy_stm32::mode::Blocking;
+    use embassy_stm32::time::Hertz;
+    use embassy_stm32::{Peri, peripherals};
+    use embassy_time::{Duration, Timer};
+
+    use super::{Telemetry, publish};
+    use crate::fmt::{info, warn};
+    use crate::power::current_contract;
+
+    /// I2C address with A0 and A1 grounded
+    const ADDRESS: u8 = 0x40;
+    const REG_CONFIG: u8 = 0x00;
+    const REG_BUS_VOLTAGE: u8 = 0x02;
+    const REG_POWER: u8 = 0x03;
+    const REG_CURRENT: u8 = 0x04;
+    const REG_CALIBRATION: u8 = 0x05;
+    /// 16 samples averaged, 1.1ms conversions, shunt and bus continuous
+    const CONFIG: u16 = 0x4000 | (0b010 << 9) | (0b100 << 6) | (0b100 << 3) | 0b111;
+    /// Current resolution in µA, for up to 32A
+    const CURRENT_LSB_UA: u32 = 1_000;
+    /// Time between readings
+    const READ_INTERVAL: Duration = Duration::from_millis(500);
+
+    pub struct Ina226Resources {
+        pub i2c: Peri<'static, peripherals::I2C3>,
+        pub pin_scl: Peri<'static, peripherals::PA8>,
+        pub pin_sda: Peri<'static, peripherals::PB5>,
+    }
+
+    /// Read VBUS voltage, current and power every 500ms from an INA226 with a
+    /// `shunt_mohm` shunt, warning when the draw exceeds the contract.
+    #[embassy_executor::task]
+    pub async fn ina226_task(resources: Ina226Resources, shunt_mohm: u32) {
+        let mut i2c = I2c::new_blocking(
+            resources.i2c,
+            resources.pin_scl,
+            resources.pin_sda,
+            Hertz::khz(400),
+            i2c::Config::default(),
+        );
+        // CAL = 0.00512 / (current LSB × shunt), datasheet 7.5
+        let calibration =
+            (5_120_000_000 / (CURRENT_LSB_UA as u64 * shunt_mohm.max(1) as u64)).min(0x7FFF) as u16;
+        if write_register(&mut i2c, REG_CONFIG, CONFIG).is_err()
+            || write_register(&mut i2c, REG_CALIBRATION, calibration).is_err()
+        {
+            warn!("INA226 not responding, telemetry disabled");
+            return;
+        }
+        info!("INA226 ready, {}mOhm shunt", shunt_mohm);
+
+        let mut exceeded = false;
+        loop {
+            Timer::after(READ_INTERVAL).await;
+            let registers = (
+                read_register(&mut i2c, REG_BUS_VOLTAGE),
+                read_register(&mut i2c, REG_CURRENT),
+                read_register(&mut i2c, REG_POWER),
+            );
+            let (Ok(bus), Ok(current), Ok(power)) = registers else {
+                warn!("INA226 read failed");
+                continue;
+            };
+            let telemetry = Telemetry::from_registers(bus, current as i16, power, CURRENT_LSB_UA);
+            publish(telemetry);
+
+            // Only warn when the draw goes over, not on every reading while it stays there
+            let contract = current_contract();
+            let over = telemetry.exceeds(&contract);
+            if over && !exceeded {
+                warn!(
+                    "Drawing {}mA {}mW at {}mV, above the {}mV {}mA contract",
+                    telemetry.current_ma,
+                    telemetry.power_mw,
+                    telemetry.vbus_mv,
+                    contract.voltage_mv,
+                    contract.current_ma
+                );
+            }
+            exceeded = over;
+        }
+    }
+
+    fn write_register(
+        i2c: &mut I2c<'static, Blocking>,
+        register: u8,
+        value: u16,
+    ) -> Result<(), i2c::Error> {
+        let [high, low] = value.to_be_bytes();
+        i2c.blocking_write(ADDRESS, &[register, high, low])
+    }
+
+    fn read_register(i2c: &mut I2c<'static, Blocking>, register: u8) -> Result<u16, i2c::Error> {
+        let mut value = [0u8; 2];
+        i2c.blocking_write_read(ADDRESS, &[register], &mut value)?;
+        Ok(u16::from_be_bytes(value))
+    }
+}
@@ -0,0 +1,157 @@
+//! Board temperature from an NTC thermistor, and derating of the requested current.
+use core::sync::atomic::{AtomicU8, Ordering};
+
+#[cfg(target_os = "none")]
+pub use adc::{TempSensor, thermal_task};
+#[cfg(not(target_os = "none"))]
+pub use simulated::TempSensor;
+
+/// Derating steps: above `threshold_c`, only `percent` of the current is requested
+const DERATING_STEPS: [(i32, u8); 3] = [(60, 75), (70, 50), (80, 25)];
+/// A step is left only once the temperature is this far below its threshold
+const HYSTERESIS_C: i32 = 5;
+
+/// Active derating step, 0 when no derating is applied
+static DERATING_LEVEL: AtomicU8 = AtomicU8::new(0);
+
+/// Share of the selected current to request in percent, 100 below the first threshold.
+pub fn derating_percent() -> u32 {
+    match DERATING_LEVEL.load(Ordering::Relaxed) as usize {
+        0 => 100,
+        level => DERATING_STEPS[level - 1].1 as u32,
+    }
+}
+
+/// Update the derating for a new temperature reading, returning whether it changed.
+///
+/// Steps are entered when the temperature reaches their threshold and left once it drops
+/// `HYSTERESIS_C` below, so a reading around a threshold doesn't toggle between levels.
+pub fn update_derating(temperature_c: i32) -> bool {
+    let mut level = DERATING_LEVEL.load(Ordering::Relaxed) as usize;
+    let previous = level;
+    while level < DERATING_STEPS.len() && temperature_c >= DERATING_STEPS[level].0 {
+        level += 1;
+    }
+    while level > 0 && temperature_c < DERATING_STEPS[level - 1].0 - HYSTERESIS_C {
+        level -= 1;
+    }
+    DERATING_LEVEL.store(level as u8, Ordering::Relaxed);
+    level != previous
+}
+
+/// Resistance of a 10k B3950 NTC from 0 to 100°C in 10°C steps, in ohms
+const NTC_TABLE: [(i32, u32); 11] = [
+    (0, 32_650),
+    (10, 19_900),
+    (20, 12_490),
+    (30, 8_057),
+    (40, 5_327),
+    (50, 3_603),
+    (60, 2_488),
+    (70, 1_752),
+    (80, 1_258),
+    (90, 918),
+    (100, 680),
+];
+
+/// Temperature in °C for an NTC resistance, interpolated linearly and clamped to the table.
+pub fn ntc_temperature_c(resistance_ohms: u32) -> i32 {
+    let (first_c, first_ohms) = NTC_TABLE[0];
+    if resistance_ohms >= first_ohms {
+        return first_c;
+    }
+    for pair in NTC_TABLE.windows(2) {
+        let ((low_c, low_ohms), (high_c, high_ohms)) = (pair[0], pair[1]);
+        if resistance_ohms >= high_ohms {
+            let span = (low_ohms - high_ohms) as i32;
+            let offset = (low_ohms - resistance_ohms) as i32;
+            return low_c + (high_c - low_c) * offset / span;
+        }
+    }
+    NTC_TABLE[NTC_TABLE.len() - 1].0
+}
+
+/// ADC based measurement, only available on the target.
+#[cfg(target_os = "none")]
+mod adc {
+    use embassy_stm32::Peri;
+    use embassy_stm32::adc::{Adc, AdcChannel, AnyAdcChannel};
+    use embassy_stm32::peripherals::ADC2;
+    use embassy_time::{Duration, Timer};
+
+    use super::{derating_percent, ntc_temperature_c, update_derating};
+    use crate::fmt::{debug, info};
+    use crate::power::RENEGOTIATE;
+
+    /// Full scale value of a 12-bit conversion
+    const ADC_FULL_SCALE: u32 = 4095;
+    /// Interval between temperature readings
+    const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+    /// Measures an NTC thermistor to ground, pulled up to VREF by a series resistor.
+    pub struct TempSensor {
+        adc: Adc<'static, ADC2>,
+        channel: AnyAdcChannel<ADC2>,
+        /// Pull-up resistor in ohms
+        series_ohms: u32,
+    }
+
+    impl TempSensor {
+        pub fn new(
+            adc: Peri<'static, ADC2>,
+            channel: impl AdcChannel<ADC2>,
+            series_ohms: u32,
+        ) -> Self {
+            Self {
+                adc: Adc::new(adc),
+                channel: channel.degrade_adc(),
+                series_ohms,
+            }
+        }
+
+        /// Read the board temperature in °C.
+        pub fn read_c(&mut self) -> i32 {
+            let raw = (self.adc.blocking_read(&mut self.channel) as u32).min(ADC_FULL_SCALE - 1);
+            let resistance_ohms = self.series_ohms * raw / (ADC_FULL_SCALE - raw);
+            ntc_temperature_c(resistance_ohms)
+        }
+    }
+
+    /// Track the board temperature and renegotiate when the derating changes.
+    #[embassy_executor::task]
+    pub async fn thermal_task(mut sensor: TempSensor) {
+        loop {
+            let temperature_c = sensor.read_c();
+            debug!("Board temperature {}°C", temperature_c);
+            if update_derating(temperature_c) {
+                info!(
+                    "Board at {}°C, requesting {}% of the current",
+                    temperature_c,
+                    derating_percent()
+                );
+                RENEGOTIATE.signal(());
+            }
+            Timer::after(SAMPLE_INTERVAL).await;
+        }
+    }
+}
+
+/// Stand-in for host builds, reports a fixed temperature.
+#[cfg(not(target_os = "none"))]
+mod simulated {
+    pub struct TempSensor {
+        /// Temperature reported by `read_c`
+        pub temperature_c: i32,
+    }
+
+    impl TempSensor {
+        pub fn new(temperature_c: i32) -> Self {
+            Self { temperature_c }
+        }
+
+        /// Read the simulated temperature in °C.
+        pub fn read_c(&mut self) -> i32 {
+            self.temperature_c
+        }
+    }
+}
@@ -0,0 +1,167 @@
+//! Line based control over USB CDC-ACM, a serial port on the host without extra wires.
+//!
+//! Commands are parsed into `control::Command` and answered through the control channel,
+//! so the policy sees the same requests as from any other transport. Requires the 48MHz
+//! USB clock, see `clock::pd_usb_clock_config`.
+#[cfg(target_os = "none")]
+pub use usb::{UsbResources, usb_cdc_task};
+
+use crate::cli::{ParseError, parse_target};
+use crate::control::Command;
+
+/// Usage shown for `help`.
+pub const HELP: &str = "commands:\r\n  set <mV>  request a new target voltage\r\n  contract  show the current contract\r\n  caps      show the source capabilities\r\n  reset     hard reset the link with the source\r\n  help      show this message\r\n";
+
+/// Parse a line into a control command, ignoring surrounding whitespace.
+///
+/// `help` is answered by the transport and not a command.
+pub fn parse(line: &str) -> Result<Command, ParseError> {
+    let mut words = line.split_whitespace();
+    let command = match words.next().ok_or(ParseError::Empty)? {
+        "set" => Command::SetTarget {
+            voltage_mv: parse_target(words.next())?,
+        },
+        "contract" => Command::GetContract,
+        "caps" => Command::GetCaps,
+        "reset" => Command::ForceReset,
+        _ => return Err(ParseError::UnknownCommand),
+    };
+
+    match words.next() {
+        Some(_) => Err(ParseError::TrailingInput),
+        None => Ok(command),
+    }
+}
+
+/// USB handling, only available on the target.
+#[cfg(target_os = "none")]
+mod usb {
+    use core::fmt::Write;
+
+    use embassy_futures::join::join;
+    use embassy_stm32::usb::{self, Driver};
+    use embassy_stm32::{Peri, bind_interrupts, peripherals};
+    use embassy_usb::class::cdc_acm::{CdcAcmClass, State};
+    use embassy_usb::driver::EndpointError;
+    use embassy_usb::{Builder, Config};
+    use heapless::String;
+
+    use super::{HELP, parse};
+    use crate::cli::{DUMP_LEN, write_reply};
+    use crate::control;
+    use crate::fmt::info;
+
+    bind_interrupts!(struct Irqs {
+        USB_LP => usb::InterruptHandler<peripherals::USB>;
+    });
+
+    /// Longest accepted command line
+    const MAX_LINE: usize = 32;
+    /// Full speed bulk endpoint size
+    const MAX_PACKET: usize = 64;
+
+    pub struct UsbResources {
+        pub usb: Peri<'static, peripherals::USB>,
+        pub pin_dp: Peri<'static, peripherals::PA12>,
+        pub pin_dm: Peri<'static, peripherals::PA11>,
+    }
+
+    /// Run the USB device and answer command lines from the host's serial port.
+    #[embassy_executor::task]
+    pub async fn usb_cdc_task(resources: UsbResources) {
+        let driver = Driver::new(resources.usb, Irqs, resources.pin_dp, resources.pin_dm);
+
+        // pid.codes test VID/PID
+        let mut config = Config::new(0x1209, 0x0001);
+        config.manufacturer = Some("okhsunrog");
+        config.product = Some("STM32G431 PD sink");
+        config.max_power = 100;
+        config.max_packet_size_0 = MAX_PACKET as u8;
+
+        let mut config_descriptor = [0; 256];
+        let mut bos_descriptor = [0; 256];
+        let mut control_buf = [0; 64];
+        let mut state = State::new();
+        let mut builder = Builder::new(
+            driver,
+            config,
+            &mut config_descriptor,
+            &mut bos_descriptor,
+            &mut [],
+            &mut control_buf,
+        );
+        let mut class = CdcAcmClass::new(&mut builder, &mut state, MAX_PACKET as u16);
+        let mut device = builder.build();
+
+        let serve = async {
+            loop {
+                class.wait_connection().await;
+                info!("USB CLI connected");
+                // Disconnects end the session, the line in progress is lost
+                let _ = serve_lines(&mut class).await;
+                info!("USB CLI disconnected");
+            }
+        };
+        join(device.run(), serve).await;
+    }
+
+    async fn serve_lines(
+        class: &mut CdcAcmClass<'_, Driver<'static, peripherals::USB>>,
+    ) -> Result<(), EndpointError> {
+        let mut line: String<MAX_LINE> = String::new();
+        let mut overflow = false;
+        let mut packet = [0u8; MAX_PACKET];
+        loop {
+            let len = class.read_packet(&mut packet).await?;
+            for &byte in &packet[..len] {
+                match byte {
+                    b'\r' | b'\n' => {
+                        if overflow {
+                            respond(class, "line too long\r\n").await?;
+                        } else if !line.is_empty() {
+                            execute(class, &line).await?;
+                        }
+                        line.clear();
+                        overflow = false;
+                    }
+                    c if c.is_ascii() => overflow |= line.push(c as char).is_err(),
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    async fn execute(
+        class: &mut CdcAcmClass<'_, Driver<'static, peripherals::USB>>,
+        line: &str,
+    ) -> Result<(), EndpointError> {
+        if line.trim() == "help" {
+            return respond(class, HELP).await;
+        }
+
+        let mut reply: String<DUMP_LEN> = String::new();
+        let written = match parse(line) {
+            Ok(command) => write_reply(&mut reply, &control::call(command).await),
+            Err(err) => write!(reply, "error: {}\r\n", err.message()),
+        };
+        if written.is_err() {
+            reply.clear();
+            let _ = reply.push_str("error: reply too long\r\n");
+        }
+        respond(class, &reply).await
+    }
+
+    /// Write `reply` in full packets, ended by a short or empty one so the host returns it.
+    async fn respond(
+        class: &mut CdcAcmClass<'_, Driver<'static, peripherals::USB>>,
+        reply: &str,
+    ) -> Result<(), EndpointError> {
+        for chunk in reply.as_bytes().chunks(MAX_PACKET) {
+            class.write_packet(chunk).await?;
+        }
+        if reply.len() % MAX_PACKET == 0 {
+            class.write_packet(&[]).await?;
+        }
+        Ok(())
+    }
+}
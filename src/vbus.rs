@@ -0,0 +1,81 @@
+//! VBUS voltage measurement through a resistive divider.
+#[cfg(target_os = "none")]
+pub use adc::VbusMonitor;
+#[cfg(not(target_os = "none"))]
+pub use simulated::VbusMonitor;
+
+/// ADC based measurement, only available on the target.
+#[cfg(target_os = "none")]
+mod adc {
+    use embassy_stm32::Peri;
+    use embassy_stm32::adc::{Adc, AdcChannel, AnyAdcChannel};
+    use embassy_stm32::peripherals::ADC1;
+
+    /// ADC reference voltage in mV
+    const VREF_MV: u32 = 3300;
+    /// Full scale value of a 12-bit conversion
+    const ADC_FULL_SCALE: u32 = 4095;
+
+    /// Measures VBUS on an ADC1 channel behind a resistive divider.
+    pub struct VbusMonitor {
+        adc: Adc<'static, ADC1>,
+        channel: AnyAdcChannel<ADC1>,
+        /// Ratio of VBUS to the voltage at the ADC pin, e.g. 11 for a 100k/10k divider
+        divider_ratio: u32,
+        /// Allowed deviation of VBUS from the negotiated voltage in mV
+        pub tolerance_mv: u32,
+        /// Issue a hard reset when VBUS is out of tolerance after a transition
+        pub hard_reset_on_mismatch: bool,
+    }
+
+    impl VbusMonitor {
+        pub fn new(
+            adc: Peri<'static, ADC1>,
+            channel: impl AdcChannel<ADC1>,
+            divider_ratio: u32,
+            tolerance_mv: u32,
+        ) -> Self {
+            Self {
+                adc: Adc::new(adc),
+                channel: channel.degrade_adc(),
+                divider_ratio,
+                tolerance_mv,
+                hard_reset_on_mismatch: false,
+            }
+        }
+
+        /// Read the current VBUS voltage in mV.
+        pub fn read_mv(&mut self) -> u32 {
+            let raw = self.adc.blocking_read(&mut self.channel) as u32;
+            raw * VREF_MV * self.divider_ratio / ADC_FULL_SCALE
+        }
+    }
+}
+
+/// Stand-in for host builds, reports a fixed voltage.
+#[cfg(not(target_os = "none"))]
+mod simulated {
+    pub struct VbusMonitor {
+        /// Voltage reported by `read_mv`
+        pub voltage_mv: u32,
+        /// Allowed deviation of VBUS from the negotiated voltage in mV
+        pub tolerance_mv: u32,
+        /// Issue a hard reset when VBUS is out of tolerance after a transition
+        pub hard_reset_on_mismatch: bool,
+    }
+
+    impl VbusMonitor {
+        pub fn new(voltage_mv: u32, tolerance_mv: u32) -> Self {
+            Self {
+                voltage_mv,
+                tolerance_mv,
+                hard_reset_on_mismatch: false,
+            }
+        }
+
+        /// Read the simulated VBUS voltage in mV.
+        pub fn read_mv(&mut self) -> u32 {
+            self.voltage_mv
+        }
+    }
+}
@@ -0,0 +1,82 @@
+//! VCONN supply for e-marked cables, switched onto the CC line not used for PD.
+//!
+//! The UCPD can't source VCONN itself, so an external switch per CC line connects the
+//! board's 5V to it. It is only enabled around the SOP' exchange with the cable plug.
+//!
+//! Without a VCONN_Swap the sink is not the VCONN source, and a source powering the
+//! e-marker itself puts its VCONN on the same line. The driver checks that the line is
+//! unpowered before enabling, see `UcpdConfig::vconn_source`.
+use core::sync::atomic::{AtomicBool, Ordering};
+
+#[cfg(target_os = "none")]
+use crate::power::CableOrientation;
+#[cfg(target_os = "none")]
+pub use pin::install;
+
+static VCONN_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Whether VCONN is currently sourced to the cable.
+pub fn is_enabled() -> bool {
+    VCONN_ENABLED.load(Ordering::Relaxed)
+}
+
+/// Source VCONN on the CC line opposite to the one PD runs on with `orientation`.
+#[cfg(target_os = "none")]
+pub(crate) fn enable(orientation: CableOrientation) {
+    let on_cc1 = match orientation {
+        CableOrientation::Normal => false,
+        CableOrientation::Flipped => true,
+        // No e-marker to power in debug accessory mode
+        CableOrientation::DebugAccessoryMode => return,
+    };
+    pin::set(Some(on_cc1));
+    VCONN_ENABLED.store(true, Ordering::Relaxed);
+    crate::fmt::info!("VCONN on {}", if on_cc1 { "CC1" } else { "CC2" });
+}
+
+/// Stop sourcing VCONN, after the SOP' exchange and on detach.
+#[cfg(target_os = "none")]
+pub(crate) fn disable() {
+    pin::set(None);
+    if VCONN_ENABLED.swap(false, Ordering::Relaxed) {
+        crate::fmt::info!("VCONN off");
+    }
+}
+
+/// Enable pins of the VCONN switches, only available on the target.
+#[cfg(target_os = "none")]
+mod pin {
+    use core::cell::RefCell;
+
+    use embassy_stm32::gpio::Output;
+    use embassy_sync::blocking_mutex::Mutex;
+    use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+
+    /// Switches onto CC1 and CC2
+    static VCONN_PINS: Mutex<CriticalSectionRawMutex, RefCell<Option<[Output<'static>; 2]>>> =
+        Mutex::new(RefCell::new(None));
+
+    /// Take over the active-high enable pins of the VCONN switches onto CC1 and CC2,
+    /// starting disabled.
+    pub fn install(mut cc1: Output<'static>, mut cc2: Output<'static>) {
+        cc1.set_low();
+        cc2.set_low();
+        VCONN_PINS.lock(|pins| *pins.borrow_mut() = Some([cc1, cc2]));
+    }
+
+    /// Switch VCONN onto CC1 (`Some(true)`), CC2 (`Some(false)`) or off.
+    pub(super) fn set(on_cc1: Option<bool>) {
+        VCONN_PINS.lock(|pins| {
+            if let Some([cc1, cc2]) = pins.borrow_mut().as_mut() {
+                // Break before make, VCONN must never reach the PD line
+                cc1.set_low();
+                cc2.set_low();
+                match on_cc1 {
+                    Some(true) => cc1.set_high(),
+                    Some(false) => cc2.set_high(),
+                    None => {}
+                }
+            }
+        });
+    }
+}